@@ -0,0 +1,298 @@
+use std::cmp;
+
+use util::{FixedDimension};
+use terrain::{Terrain};
+use height_source::{value2};
+
+/// How the effect of a brush fades from its centre to its radius
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Falloff {
+    Linear,
+    /// Smoothstep, flat in the middle and soft at the edge
+    Smooth,
+    /// Bell curve, shifted and scaled to reach zero at the radius
+    Gaussian,
+}
+
+impl Falloff {
+    /// Weight at `t`, the distance from the centre relative to the radius
+    pub fn weight(self, t: f32) -> f32 {
+        if t >= 1.0 {
+            return 0.0;
+        }
+        match self {
+            Falloff::Linear => 1.0 - t,
+            Falloff::Smooth => 1.0 - t * t * (3.0 - 2.0 * t),
+            Falloff::Gaussian => {
+                let edge = (-4.0f32).exp();
+                ((-4.0 * t * t).exp() - edge) / (1.0 - edge)
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BrushOp {
+    /// Adds `strength` world units at the centre
+    Raise,
+    /// Removes `strength` world units at the centre
+    Lower,
+    /// Blends towards the average of the neighbours
+    Smooth,
+    /// Blends towards the given height
+    Flatten(f32),
+    /// Adds value noise of the given wavelength in world units, `strength` is its amplitude
+    Noise { seed: u32, wavelength: f32 },
+    /// Sets everything inside the radius to the given height, ignoring falloff and strength
+    Set(f32),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Brush {
+    pub op: BrushOp,
+    /// World x and z of the centre
+    pub center: [f32; 2],
+    pub radius: f32,
+    /// World units for raise, lower and noise, blend factor in `[0, 1]` for smooth and flatten
+    pub strength: f32,
+    pub falloff: Falloff,
+}
+
+/// Inclusive range of grid coordinates that were changed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub min: [usize; 2],
+    pub max: [usize; 2],
+}
+
+impl DirtyRect {
+    /// Grows the rect by `n` samples, staying inside `dims`.
+    /// Normals depend on the neighbours, so meshes have to be rebuilt one sample further.
+    pub fn expand(self, n: usize, dims: [usize; 2]) -> DirtyRect {
+        DirtyRect {
+            min: [self.min[0].saturating_sub(n), self.min[1].saturating_sub(n)],
+            max: [cmp::min(self.max[0] + n, dims[0] - 1), cmp::min(self.max[1] + n, dims[1] - 1)],
+        }
+    }
+
+    pub fn union(self, other: DirtyRect) -> DirtyRect {
+        DirtyRect {
+            min: [cmp::min(self.min[0], other.min[0]), cmp::min(self.min[1], other.min[1])],
+            max: [cmp::max(self.max[0], other.max[0]), cmp::max(self.max[1], other.max[1])],
+        }
+    }
+}
+
+pub trait Sculpt {
+    /// Applies the brush, `sample_size` is the world size of one cell like in `mesh::chunk_mesh`.
+    /// Returns the changed samples, or `None` if the brush doesn't cover any sample of the terrain.
+    fn sculpt(&mut self, brush: &Brush, sample_size: [f32; 2]) -> Option<DirtyRect>;
+}
+
+impl Sculpt for Terrain {
+    fn sculpt(&mut self, brush: &Brush, sample_size: [f32; 2]) -> Option<DirtyRect> {
+        let dims = self.dims();
+        if brush.radius <= 0.0 {
+            return None;
+        }
+
+        let grid_min = [
+            ((brush.center[0] - brush.radius) / sample_size[0]).ceil(),
+            ((brush.center[1] - brush.radius) / sample_size[1]).ceil(),
+        ];
+        let grid_max = [
+            ((brush.center[0] + brush.radius) / sample_size[0]).floor(),
+            ((brush.center[1] + brush.radius) / sample_size[1]).floor(),
+        ];
+        if grid_max[0] < 0.0 || grid_max[1] < 0.0 || grid_min[0] > (dims[0] - 1) as f32 || grid_min[1] > (dims[1] - 1) as f32 {
+            return None;
+        }
+        // a brush smaller than a cell can lie between the samples
+        if grid_min[0] > grid_max[0] || grid_min[1] > grid_max[1] {
+            return None;
+        }
+
+        let rect = DirtyRect {
+            min: [grid_min[0].max(0.0) as usize, grid_min[1].max(0.0) as usize],
+            max: [
+                cmp::min(grid_max[0] as usize, dims[0] - 1),
+                cmp::min(grid_max[1] as usize, dims[1] - 1),
+            ],
+        };
+
+        // smoothing reads the neighbours, which must not see the already smoothed values
+        let original = match brush.op {
+            BrushOp::Smooth => Some(self.vec.clone()),
+            _ => None,
+        };
+
+        for x in rect.min[0]..rect.max[0] + 1 {
+            for z in rect.min[1]..rect.max[1] + 1 {
+                let pos = [x as f32 * sample_size[0], z as f32 * sample_size[1]];
+                let dist = ((pos[0] - brush.center[0]).powi(2) + (pos[1] - brush.center[1]).powi(2)).sqrt();
+                let t = dist / brush.radius;
+                if t > 1.0 {
+                    continue;
+                }
+                let weight = brush.falloff.weight(t) * brush.strength;
+                let blend = weight.max(0.0).min(1.0);
+
+                let index = self.fixed_dim.to_index([x, z]).unwrap();
+                let h = self.vec[index];
+
+                let new_h = match brush.op {
+                    BrushOp::Raise => h + weight,
+                    BrushOp::Lower => h - weight,
+                    BrushOp::Smooth => {
+                        let original = original.as_ref().unwrap();
+                        let avg = neighbour_average(original, self, [x, z]);
+                        h + (avg - h) * blend
+                    },
+                    BrushOp::Flatten(target) => h + (target - h) * blend,
+                    BrushOp::Noise { seed, wavelength } => {
+                        h + value2(seed, [pos[0] / wavelength, pos[1] / wavelength]) * weight
+                    },
+                    BrushOp::Set(target) => target,
+                };
+                self.vec[index] = new_h;
+            }
+        }
+
+        Some(rect)
+    }
+}
+
+/// Average of the sample and its 8 neighbours inside the terrain, read from `heights`
+fn neighbour_average(heights: &[f32], terrain: &Terrain, coords: [usize; 2]) -> f32 {
+    let dims = terrain.dims();
+    let mut sum = 0.0;
+    let mut count = 0;
+
+    for x in coords[0].saturating_sub(1)..cmp::min(coords[0] + 2, dims[0]) {
+        for z in coords[1].saturating_sub(1)..cmp::min(coords[1] + 2, dims[1]) {
+            sum+= heights[terrain.fixed_dim.to_index([x, z]).unwrap()];
+            count+= 1;
+        }
+    }
+
+    sum / count as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use util::{Mat, FixedHeight};
+    use super::*;
+
+    /// 11 by 11 samples of `f(x, z)`
+    fn terrain<F>(f: F) -> Terrain where F: Fn(usize, usize) -> f32 {
+        Mat {
+            vec: (0..121).map(|i| f(i / 11, i % 11)).collect(),
+            fixed_dim: FixedHeight::from_height(11).unwrap(),
+        }
+    }
+
+    fn brush(op: BrushOp, center: [f32; 2], radius: f32, strength: f32) -> Brush {
+        Brush {
+            op: op,
+            center: center,
+            radius: radius,
+            strength: strength,
+            falloff: Falloff::Linear,
+        }
+    }
+
+    fn height(terrain: &Terrain, x: usize, z: usize) -> f32 {
+        *terrain.get([x, z]).unwrap()
+    }
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn falloff_ends() {
+        for &falloff in [Falloff::Linear, Falloff::Smooth, Falloff::Gaussian].iter() {
+            assert_eq!(falloff.weight(0.0), 1.0);
+            assert!(falloff.weight(0.999).abs() < 1e-2, "{:?}", falloff);
+            assert_eq!(falloff.weight(1.0), 0.0);
+            let mut last = 1.0;
+            for i in 1..100 {
+                let w = falloff.weight(i as f32 / 100.0);
+                assert!(w <= last && w >= 0.0, "{:?}", falloff);
+                last = w;
+            }
+        }
+    }
+
+    #[test]
+    fn raise_and_lower() {
+        let mut t = terrain(|_, _| 1.0);
+        let rect = t.sculpt(&brush(BrushOp::Raise, [5.0, 5.0], 3.0, 2.0), [1.0, 1.0]);
+        assert_eq!(rect, Some(DirtyRect { min: [2, 2], max: [8, 8] }));
+        assert_near(height(&t, 5, 5), 3.0);
+        assert_near(height(&t, 6, 5), 1.0 + 2.0 * 2.0 / 3.0);
+        assert_near(height(&t, 5, 8), 1.0);
+        assert_near(height(&t, 2, 2), 1.0);
+        assert_near(height(&t, 9, 5), 1.0);
+
+        t.sculpt(&brush(BrushOp::Lower, [5.0, 5.0], 3.0, 2.0), [1.0, 1.0]);
+        assert!(t.vec.iter().all(|&h| (h - 1.0).abs() < 1e-5));
+    }
+
+    #[test]
+    fn flatten_blends_towards_the_target() {
+        let mut t = terrain(|x, _| x as f32);
+        t.sculpt(&brush(BrushOp::Flatten(5.0), [4.0, 5.0], 3.0, 1.0), [1.0, 1.0]);
+        assert_near(height(&t, 4, 5), 5.0);
+        assert_near(height(&t, 6, 5), 6.0 + (5.0 - 6.0) / 3.0);
+        assert_near(height(&t, 2, 5), 2.0 + (5.0 - 2.0) / 3.0);
+        assert_near(height(&t, 8, 5), 8.0);
+    }
+
+    #[test]
+    fn smooth_reads_the_unsmoothed_heights() {
+        let mut t = terrain(|x, z| if x == 5 && z == 5 { 9.0 } else { 0.0 });
+        // strong enough to replace every sample within 2 by its average
+        t.sculpt(&brush(BrushOp::Smooth, [5.0, 5.0], 2.5, 100.0), [1.0, 1.0]);
+        for x in 4..7 {
+            for z in 4..7 {
+                assert_near(height(&t, x, z), 1.0);
+            }
+        }
+        assert_near(height(&t, 7, 5), 0.0);
+    }
+
+    #[test]
+    fn smooth_averages_a_plane_to_itself() {
+        let mut t = terrain(|x, z| x as f32 * 0.5 + z as f32);
+        let plane = t.vec.clone();
+        t.sculpt(&brush(BrushOp::Smooth, [5.0, 5.0], 3.0, 1.0), [1.0, 1.0]);
+        assert!(t.vec.iter().zip(plane.iter()).all(|(a, b)| (a - b).abs() < 1e-5));
+    }
+
+    #[test]
+    fn dirty_rect_at_the_border() {
+        let mut t = terrain(|_, _| 0.0);
+        assert_eq!(t.sculpt(&brush(BrushOp::Raise, [0.0, 0.0], 2.5, 1.0), [1.0, 1.0]),
+            Some(DirtyRect { min: [0, 0], max: [2, 2] }));
+        assert_eq!(t.sculpt(&brush(BrushOp::Raise, [10.5, 4.0], 1.0, 1.0), [1.0, 1.0]),
+            Some(DirtyRect { min: [10, 3], max: [10, 5] }));
+        // sample size 2, the terrain ends at 20
+        assert_eq!(t.sculpt(&brush(BrushOp::Raise, [19.0, 21.0], 3.0, 1.0), [2.0, 2.0]),
+            Some(DirtyRect { min: [8, 9], max: [10, 10] }));
+        assert_eq!(t.sculpt(&brush(BrushOp::Raise, [-5.0, 3.0], 2.0, 1.0), [1.0, 1.0]), None);
+        assert_eq!(t.sculpt(&brush(BrushOp::Raise, [3.0, 12.5], 1.0, 1.0), [1.0, 1.0]), None);
+    }
+
+    #[test]
+    fn brush_smaller_than_a_sample() {
+        let mut t = terrain(|_, _| 0.0);
+        assert_eq!(t.sculpt(&brush(BrushOp::Set(1.0), [4.5, 4.5], 0.3, 1.0), [1.0, 1.0]), None);
+        assert!(t.vec.iter().all(|&h| h == 0.0));
+
+        assert_eq!(t.sculpt(&brush(BrushOp::Set(1.0), [4.1, 4.0], 0.3, 1.0), [1.0, 1.0]),
+            Some(DirtyRect { min: [4, 4], max: [4, 4] }));
+        assert_eq!(t.vec.iter().filter(|&&h| h == 1.0).count(), 1);
+        assert_eq!(height(&t, 4, 4), 1.0);
+    }
+}
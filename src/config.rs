@@ -0,0 +1,784 @@
+extern crate toml;
+
+use std::io::{self, Read};
+use std::fs::File;
+use std::path::Path;
+use std::fmt;
+use std::cell::{RefCell};
+use std::collections::{HashSet};
+
+use self::toml::{Parser, Value};
+
+use terrain::{Area};
+use lod::{SeamMode};
+use mesh::{NormalMethod, IndexLayout};
+use splat::{SplatRule, MAX_LAYERS};
+use shadow::{MAX_CASCADES};
+
+/// Everything that describes the scene, loaded from a TOML file.
+/// Missing keys keep their default values, unknown keys are an error.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub terrain: TerrainConfig,
+    pub sky: SkyConfig,
+    pub camera: CameraConfig,
+    pub lod: LodConfig,
+    pub streaming: StreamingConfig,
+    pub splat: SplatConfig,
+    pub triplanar: TriplanarConfig,
+    pub shadows: ShadowConfig,
+    pub water: WaterConfig,
+}
+
+#[derive(Clone, Debug)]
+pub struct TerrainConfig {
+    /// Part of the noise plane the terrain is sampled from
+    pub area: Area,
+    pub samples: [u32; 2],
+    pub seed: u32,
+    pub max_height: f32,
+    /// World size of the terrain mesh
+    pub size: [f32; 2],
+    /// Number of cells one repetition of the texture spans
+    pub samples_per_tex: usize,
+    pub texture: String,
+    pub normals: NormalMethod,
+    /// Index buffers of the terrain meshes
+    pub index_layout: IndexLayout,
+}
+
+/// Day/night cycle moving the sun, see `sky::SkyParams`
+#[derive(Clone, Debug)]
+pub struct SkyConfig {
+    /// Seconds of a full day, 0 stops the time
+    pub day_length: f32,
+    pub start_hour: f32,
+    /// In degrees
+    pub latitude: f32,
+    /// Of the sun in degrees, the season
+    pub declination: f32,
+    /// Towards the light, stops the cycle. Replaces the former `light.direction`.
+    pub fixed_direction: Option<[f32; 3]>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CameraConfig {
+    pub position: [f32; 3],
+    pub direction: [f32; 3],
+    /// Vertical field of view in degrees
+    pub fov: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct LodConfig {
+    /// Cells per edge of each terrain chunk mesh
+    pub chunk_cells: usize,
+    /// Chunks are refined while their error covers more pixels than this
+    pub max_pixel_error: f32,
+    /// Of every chunk, single chunks can be changed with `LodTree::set_seams`
+    pub seams: SeamMode,
+    /// Depth of the walls with `SeamMode::Skirts`
+    pub skirt_depth: f32,
+}
+
+/// Terrain generated in tiles around the camera instead of the fixed `terrain.size`,
+/// using the seed, height and scale of the terrain section
+#[derive(Clone, Debug)]
+pub struct StreamingConfig {
+    pub enabled: bool,
+    /// World edge length of a tile
+    pub tile_size: f32,
+    /// Cells per tile edge
+    pub cells: u32,
+    /// In tiles around the camera's tile
+    pub load_radius: u32,
+    pub unload_radius: u32,
+    pub max_tiles: usize,
+    pub max_in_flight: usize,
+    pub uploads_per_frame: usize,
+    pub workers: usize,
+}
+
+/// Material layers blended by height and slope. Without layers `terrain.texture` covers everything.
+#[derive(Clone, Debug)]
+pub struct SplatConfig {
+    pub layers: Vec<SplatLayerConfig>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SplatLayerConfig {
+    pub texture: String,
+    pub rule: SplatRule,
+}
+
+/// Texturing by world position projected along x, y and z instead of the mesh's texture coordinates
+#[derive(Clone, Debug)]
+pub struct TriplanarConfig {
+    pub enabled: bool,
+    /// World size of one repetition of the textures
+    pub scale: f32,
+    /// Higher values narrow the blending between the projections
+    pub sharpness: f32,
+}
+
+/// Cascaded shadow maps of the light
+#[derive(Clone, Debug)]
+pub struct ShadowConfig {
+    pub enabled: bool,
+    /// Number of shadow maps the view is split into
+    pub cascades: usize,
+    /// Blends the splits between uniform (0) and logarithmic (1)
+    pub split_lambda: f32,
+    /// View depth up to which shadows are drawn, at most `camera.far`
+    pub distance: f32,
+    /// Edge length of each shadow map in texels
+    pub map_size: u32,
+    /// World distance the shadow lookup is moved towards the light
+    pub bias: f32,
+    /// World distance the shadow lookup is moved along the surface normal
+    pub normal_bias: f32,
+    /// Percentage closer filtering averages `(2 * radius + 1)^2` texels
+    pub pcf_radius: u32,
+}
+
+/// Water plane with reflection and refraction, see `water::WaterParams`
+#[derive(Clone, Debug)]
+pub struct WaterConfig {
+    pub enabled: bool,
+    /// Height of the surface as a fraction of `terrain.max_height`
+    pub level: f32,
+    pub deep_color: [f32; 3],
+    pub absorption: [f32; 3],
+    pub foam_depth: f32,
+    pub wave_scale: f32,
+    pub wave_speed: f32,
+    pub distortion: f32,
+    pub clip_margin: f32,
+    pub resolution: f32,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(io::Error),
+    /// The file is no valid TOML
+    Parse(String),
+    /// A value has the wrong type or is out of range
+    Invalid { key: String, reason: String },
+    /// Keys that don't belong to any setting, misspelled or removed ones
+    Unknown(Vec<String>),
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            terrain: TerrainConfig {
+                area: Area { x: 0.0, y: 0.0, w: 1000.0, h: 1000.0 },
+                samples: [100, 100],
+                seed: 12,
+                max_height: 30.0,
+                size: [100.0, 100.0],
+                samples_per_tex: 30,
+                texture: "res/terrain.png".to_string(),
+                normals: NormalMethod::CentralDifference,
+                index_layout: IndexLayout::Triangles,
+            },
+            sky: SkyConfig {
+                day_length: 240.0,
+                start_hour: 10.0,
+                latitude: 45.0,
+                declination: 10.0,
+                fixed_direction: None,
+            },
+            camera: CameraConfig {
+                position: [60.0, 30.0, 60.0],
+                direction: [0.0, -0.3, 0.0],
+                fov: 90.0,
+                near: 0.1,
+                far: 100.0,
+            },
+            lod: LodConfig {
+                chunk_cells: 16,
+                max_pixel_error: 2.0,
+                seams: SeamMode::Skirts,
+                skirt_depth: 1.0,
+            },
+            streaming: StreamingConfig {
+                enabled: false,
+                tile_size: 50.0,
+                cells: 60,
+                load_radius: 3,
+                unload_radius: 5,
+                max_tiles: 128,
+                max_in_flight: 8,
+                uploads_per_frame: 2,
+                workers: 2,
+            },
+            splat: SplatConfig {
+                layers: Vec::new(),
+            },
+            triplanar: TriplanarConfig {
+                enabled: false,
+                scale: 30.0,
+                sharpness: 4.0,
+            },
+            shadows: ShadowConfig {
+                enabled: true,
+                cascades: 3,
+                split_lambda: 0.75,
+                distance: 100.0,
+                map_size: 2048,
+                bias: 0.05,
+                normal_bias: 0.1,
+                pcf_radius: 1,
+            },
+            water: WaterConfig {
+                enabled: true,
+                level: 0.3,
+                deep_color: [0.02, 0.12, 0.15],
+                absorption: [0.45, 0.12, 0.08],
+                foam_depth: 0.4,
+                wave_scale: 4.0,
+                wave_speed: 0.5,
+                distortion: 0.02,
+                clip_margin: 0.5,
+                resolution: 0.5,
+            },
+        }
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let mut s = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut s)));
+        Config::parse(&s)
+    }
+
+    pub fn parse(s: &str) -> Result<Config, ConfigError> {
+        let mut parser = Parser::new(s);
+        let root = match parser.parse() {
+            Some(table) => Value::Table(table),
+            None => {
+                let errors = parser.errors.iter()
+                    .map(|err| {
+                        let (line, col) = parser.to_linecol(err.lo);
+                        format!("{}:{}: {}", line + 1, col + 1, err.desc)
+                    })
+                    .collect::<Vec<_>>();
+                return Err(ConfigError::Parse(errors.join("\n")));
+            },
+        };
+
+        let read = RefCell::new(HashSet::new());
+        let table = Tracked {
+            value: &root,
+            prefix: String::new(),
+            read: &read,
+        };
+        let mut config = Config::default();
+
+        {
+            let t = &mut config.terrain;
+            if let Some(area) = try!(array::<[f32; 4]>(&table, "terrain.area")) {
+                t.area = Area { x: area[0], y: area[1], w: area[2], h: area[3] };
+            }
+            try!(set(&mut t.samples, array::<[u32; 2]>(&table, "terrain.samples")));
+            try!(set(&mut t.seed, integer(&table, "terrain.seed")));
+            try!(set(&mut t.max_height, float(&table, "terrain.max_height")));
+            try!(set(&mut t.size, array::<[f32; 2]>(&table, "terrain.size")));
+            try!(set(&mut t.samples_per_tex, integer(&table, "terrain.samples_per_tex")));
+            try!(set(&mut t.texture, string(&table, "terrain.texture")));
+            if let Some(normals) = try!(string(&table, "terrain.normals")) {
+                t.normals = match &normals[..] {
+                    "central" => NormalMethod::CentralDifference,
+                    "sobel" => NormalMethod::Sobel,
+                    "area" => NormalMethod::AreaWeighted,
+                    _ => return Err(invalid("terrain.normals", "expected \"central\", \"sobel\" or \"area\"")),
+                };
+            }
+            if let Some(layout) = try!(string(&table, "terrain.index_layout")) {
+                t.index_layout = match &layout[..] {
+                    "triangles" => IndexLayout::Triangles,
+                    "strips" => IndexLayout::Strips,
+                    _ => return Err(invalid("terrain.index_layout", "expected \"triangles\" or \"strips\"")),
+                };
+            }
+        }
+        try!(set(&mut config.sky.day_length, float(&table, "sky.day_length")));
+        try!(set(&mut config.sky.start_hour, float(&table, "sky.start_hour")));
+        try!(set(&mut config.sky.latitude, float(&table, "sky.latitude")));
+        try!(set(&mut config.sky.declination, float(&table, "sky.declination")));
+        if let Some(dir) = try!(array::<[f32; 3]>(&table, "sky.fixed_direction")) {
+            config.sky.fixed_direction = Some(dir);
+        }
+        if table.lookup("light.direction").is_some() {
+            return Err(invalid("light.direction", "was replaced by sky.fixed_direction"));
+        }
+        {
+            let c = &mut config.camera;
+            try!(set(&mut c.position, array::<[f32; 3]>(&table, "camera.position")));
+            try!(set(&mut c.direction, array::<[f32; 3]>(&table, "camera.direction")));
+            try!(set(&mut c.fov, float(&table, "camera.fov")));
+            try!(set(&mut c.near, float(&table, "camera.near")));
+            try!(set(&mut c.far, float(&table, "camera.far")));
+        }
+        try!(set(&mut config.lod.chunk_cells, integer(&table, "lod.chunk_cells")));
+        try!(set(&mut config.lod.max_pixel_error, float(&table, "lod.max_pixel_error")));
+        if let Some(seams) = try!(string(&table, "lod.seams")) {
+            config.lod.seams = match &seams[..] {
+                "none" => SeamMode::None,
+                "skirts" => SeamMode::Skirts,
+                "stitch" => SeamMode::Stitch,
+                _ => return Err(invalid("lod.seams", "expected \"none\", \"skirts\" or \"stitch\"")),
+            };
+        }
+        try!(set(&mut config.lod.skirt_depth, float(&table, "lod.skirt_depth")));
+        {
+            let s = &mut config.streaming;
+            try!(set(&mut s.enabled, boolean(&table, "streaming.enabled")));
+            try!(set(&mut s.tile_size, float(&table, "streaming.tile_size")));
+            try!(set(&mut s.cells, integer(&table, "streaming.cells")));
+            try!(set(&mut s.load_radius, integer(&table, "streaming.load_radius")));
+            try!(set(&mut s.unload_radius, integer(&table, "streaming.unload_radius")));
+            try!(set(&mut s.max_tiles, integer(&table, "streaming.max_tiles")));
+            try!(set(&mut s.max_in_flight, integer(&table, "streaming.max_in_flight")));
+            try!(set(&mut s.uploads_per_frame, integer(&table, "streaming.uploads_per_frame")));
+            try!(set(&mut s.workers, integer(&table, "streaming.workers")));
+        }
+
+        try!(set(&mut config.triplanar.enabled, boolean(&table, "triplanar.enabled")));
+        try!(set(&mut config.triplanar.scale, float(&table, "triplanar.scale")));
+        try!(set(&mut config.triplanar.sharpness, float(&table, "triplanar.sharpness")));
+        {
+            let s = &mut config.shadows;
+            try!(set(&mut s.enabled, boolean(&table, "shadows.enabled")));
+            try!(set(&mut s.cascades, integer(&table, "shadows.cascades")));
+            try!(set(&mut s.split_lambda, float(&table, "shadows.split_lambda")));
+            try!(set(&mut s.distance, float(&table, "shadows.distance")));
+            try!(set(&mut s.map_size, integer(&table, "shadows.map_size")));
+            try!(set(&mut s.bias, float(&table, "shadows.bias")));
+            try!(set(&mut s.normal_bias, float(&table, "shadows.normal_bias")));
+            try!(set(&mut s.pcf_radius, integer(&table, "shadows.pcf_radius")));
+        }
+        {
+            let w = &mut config.water;
+            try!(set(&mut w.enabled, boolean(&table, "water.enabled")));
+            try!(set(&mut w.level, float(&table, "water.level")));
+            try!(set(&mut w.deep_color, array::<[f32; 3]>(&table, "water.deep_color")));
+            try!(set(&mut w.absorption, array::<[f32; 3]>(&table, "water.absorption")));
+            try!(set(&mut w.foam_depth, float(&table, "water.foam_depth")));
+            try!(set(&mut w.wave_scale, float(&table, "water.wave_scale")));
+            try!(set(&mut w.wave_speed, float(&table, "water.wave_speed")));
+            try!(set(&mut w.distortion, float(&table, "water.distortion")));
+            try!(set(&mut w.clip_margin, float(&table, "water.clip_margin")));
+            try!(set(&mut w.resolution, float(&table, "water.resolution")));
+        }
+        if let Some(layers) = table.lookup("splat.layers") {
+            let layers = try!(layers.as_slice().ok_or_else(|| invalid("splat.layers", "expected an array of tables")));
+            config.splat.layers = try!(layers.iter().enumerate()
+                .map(|(i, layer)| splat_layer(&table.child(layer, &format!("splat.layers[{}]", i)), i))
+                .collect());
+        }
+
+        let mut unknown = Vec::new();
+        unread_keys(&root, "", &read.borrow(), &mut unknown);
+        if !unknown.is_empty() {
+            return Err(ConfigError::Unknown(unknown));
+        }
+
+        try!(config.validate());
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let t = &self.terrain;
+        let c = &self.camera;
+
+        try!(check(t.area.w > 0.0 && t.area.h > 0.0, "terrain.area", "width and height must be positive"));
+        try!(check(t.samples[0] >= 2 && t.samples[1] >= 2, "terrain.samples", "need at least 2 samples per axis"));
+        try!(check(t.max_height > 0.0, "terrain.max_height", "must be positive"));
+        try!(check(t.size[0] > 0.0 && t.size[1] > 0.0, "terrain.size", "must be positive"));
+        try!(check(t.samples_per_tex > 0, "terrain.samples_per_tex", "must not be zero"));
+        try!(check(self.sky.day_length >= 0.0, "sky.day_length", "must not be negative"));
+        try!(check(self.sky.latitude.abs() <= 90.0, "sky.latitude", "must be between -90 and 90 degrees"));
+        try!(check(self.sky.declination.abs() <= 23.5, "sky.declination", "must be between -23.5 and 23.5 degrees"));
+        if let Some(dir) = self.sky.fixed_direction {
+            try!(check(!is_zero(&dir), "sky.fixed_direction", "must not be zero"));
+        }
+        try!(check(!is_zero(&c.direction), "camera.direction", "must not be zero"));
+        try!(check(c.fov > 0.0 && c.fov < 180.0, "camera.fov", "must be between 0 and 180 degrees"));
+        try!(check(c.near > 0.0, "camera.near", "must be positive"));
+        try!(check(c.far > c.near, "camera.far", "must be greater than camera.near"));
+        try!(check(self.lod.chunk_cells > 0, "lod.chunk_cells", "must not be zero"));
+        try!(check(self.lod.max_pixel_error > 0.0, "lod.max_pixel_error", "must be positive"));
+        try!(check(self.lod.skirt_depth >= 0.0, "lod.skirt_depth", "must not be negative"));
+
+        let s = &self.streaming;
+        let loaded = (2 * s.load_radius as usize + 1) * (2 * s.load_radius as usize + 1);
+        try!(check(s.tile_size > 0.0, "streaming.tile_size", "must be positive"));
+        try!(check(s.cells > 0, "streaming.cells", "must not be zero"));
+        try!(check(s.unload_radius > s.load_radius, "streaming.unload_radius", "must be greater than streaming.load_radius"));
+        try!(check(s.max_tiles >= loaded, "streaming.max_tiles", "must fit all tiles within streaming.load_radius"));
+        try!(check(s.max_in_flight > 0, "streaming.max_in_flight", "must not be zero"));
+        try!(check(s.uploads_per_frame > 0, "streaming.uploads_per_frame", "must not be zero"));
+        try!(check(s.workers > 0, "streaming.workers", "must not be zero"));
+
+        try!(check(self.triplanar.scale > 0.0, "triplanar.scale", "must be positive"));
+        try!(check(self.triplanar.sharpness >= 1.0, "triplanar.sharpness", "must be at least 1"));
+
+        let sh = &self.shadows;
+        try!(check(sh.cascades > 0 && sh.cascades <= MAX_CASCADES, "shadows.cascades", "must be between 1 and 4"));
+        try!(check(sh.split_lambda >= 0.0 && sh.split_lambda <= 1.0, "shadows.split_lambda", "must be between 0 and 1"));
+        try!(check(sh.distance > c.near && sh.distance <= c.far, "shadows.distance",
+            "must be greater than camera.near and at most camera.far"));
+        try!(check(sh.map_size >= 16, "shadows.map_size", "must be at least 16"));
+        try!(check(sh.bias >= 0.0 && sh.normal_bias >= 0.0, "shadows", "biases must not be negative"));
+        try!(check(sh.pcf_radius <= 4, "shadows.pcf_radius", "must be at most 4"));
+
+        let w = &self.water;
+        try!(check(w.absorption.iter().all(|&a| a >= 0.0), "water.absorption", "must not be negative"));
+        try!(check(w.foam_depth > 0.0, "water.foam_depth", "must be positive"));
+        try!(check(w.wave_scale > 0.0, "water.wave_scale", "must be positive"));
+        try!(check(w.clip_margin >= 0.0, "water.clip_margin", "must not be negative"));
+        try!(check(w.resolution > 0.0 && w.resolution <= 1.0, "water.resolution", "must be between 0 and 1"));
+
+        try!(check(self.splat.layers.len() <= MAX_LAYERS, "splat.layers", "at most 4 layers are supported"));
+        for layer in self.splat.layers.iter() {
+            let r = &layer.rule;
+            try!(check(r.height[0] <= r.height[1], "splat.layers.height", "must be ascending"));
+            try!(check(r.slope[0] <= r.slope[1], "splat.layers.slope", "must be ascending"));
+            try!(check(r.height_blend >= 0.0 && r.slope_blend >= 0.0, "splat.layers", "blend widths must not be negative"));
+        }
+
+        Ok(())
+    }
+}
+
+/// One `[[splat.layers]]` table, errors name the layer
+fn splat_layer(layer: &Tracked, index: usize) -> Result<SplatLayerConfig, ConfigError> {
+    parse_splat_layer(layer).map_err(|err| {
+        match err {
+            ConfigError::Invalid { key, reason } => ConfigError::Invalid {
+                key: format!("splat.layers[{}].{}", index, key),
+                reason: reason,
+            },
+            err => err,
+        }
+    })
+}
+
+/// Angles are in degrees
+fn parse_splat_layer(layer: &Tracked) -> Result<SplatLayerConfig, ConfigError> {
+    let texture = match try!(string(layer, "texture")) {
+        Some(texture) => texture,
+        None => return Err(invalid("texture", "is missing")),
+    };
+
+    let mut rule = SplatRule::everywhere();
+    try!(set(&mut rule.height, array::<[f32; 2]>(layer, "height")));
+    try!(set(&mut rule.height_blend, float(layer, "height_blend")));
+    if let Some(slope) = try!(array::<[f32; 2]>(layer, "slope")) {
+        rule.slope = [slope[0].to_radians(), slope[1].to_radians()];
+    }
+    if let Some(blend) = try!(float(layer, "slope_blend")) {
+        rule.slope_blend = blend.to_radians();
+    }
+
+    Ok(SplatLayerConfig {
+        texture: texture,
+        rule: rule,
+    })
+}
+
+fn is_zero(v: &[f32]) -> bool {
+    v.iter().all(|&x| x == 0.0)
+}
+
+fn check(ok: bool, key: &str, reason: &str) -> Result<(), ConfigError> {
+    if ok {
+        Ok(())
+    } else {
+        Err(invalid(key, reason))
+    }
+}
+
+fn invalid(key: &str, reason: &str) -> ConfigError {
+    ConfigError::Invalid {
+        key: key.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+/// A table of the document, remembers the keys that were read from it
+struct Tracked<'a> {
+    value: &'a Value,
+    /// Path of the table in the document, keys are recorded with it
+    prefix: String,
+    read: &'a RefCell<HashSet<String>>,
+}
+
+impl<'a> Tracked<'a> {
+    fn lookup(&self, key: &str) -> Option<&'a Value> {
+        self.read.borrow_mut().insert(format!("{}{}", self.prefix, key));
+        self.value.lookup(key)
+    }
+
+    /// A table nested in this one at `path`
+    fn child(&self, value: &'a Value, path: &str) -> Tracked<'a> {
+        Tracked {
+            value: value,
+            prefix: format!("{}{}.", self.prefix, path),
+            read: self.read,
+        }
+    }
+}
+
+/// Collects the paths of all values below `value` that weren't read.
+/// Tables in arrays are named by their index, like `splat.layers[0].texture`.
+fn unread_keys(value: &Value, path: &str, read: &HashSet<String>, unread: &mut Vec<String>) {
+    match *value {
+        Value::Table(ref table) => {
+            for (key, value) in table.iter() {
+                let key = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                unread_keys(value, &key, read, unread);
+            }
+        },
+        Value::Array(ref items) if !items.is_empty() && items.iter().all(|item| item.as_table().is_some()) => {
+            for (i, item) in items.iter().enumerate() {
+                unread_keys(item, &format!("{}[{}]", path, i), read, unread);
+            }
+        },
+        _ => {
+            if !read.contains(path) {
+                unread.push(path.to_string());
+            }
+        },
+    }
+}
+
+/// Overwrites `target` if the key was present
+fn set<T>(target: &mut T, value: Result<Option<T>, ConfigError>) -> Result<(), ConfigError> {
+    value.map(|value| {
+        if let Some(value) = value {
+            *target = value;
+        }
+    })
+}
+
+/// Conversion from a TOML number, integers are accepted where floats are expected
+trait FromToml: Sized {
+    fn from_toml(value: &Value) -> Option<Self>;
+}
+
+impl FromToml for f32 {
+    fn from_toml(value: &Value) -> Option<f32> {
+        match *value {
+            Value::Float(f) => Some(f as f32),
+            Value::Integer(i) => Some(i as f32),
+            _ => None,
+        }
+    }
+}
+
+impl FromToml for u32 {
+    fn from_toml(value: &Value) -> Option<u32> {
+        value.as_integer()
+            .and_then(|i| if i >= 0 && i <= u32::max_value() as i64 { Some(i as u32) } else { None })
+    }
+}
+
+impl FromToml for usize {
+    fn from_toml(value: &Value) -> Option<usize> {
+        value.as_integer()
+            .and_then(|i| if i >= 0 { Some(i as usize) } else { None })
+    }
+}
+
+/// Fixed size arrays of numbers
+trait FromTomlArray: Sized {
+    type Item: FromToml + Copy;
+    fn from_slice(items: &[Self::Item]) -> Self;
+    fn len() -> usize;
+}
+
+macro_rules! impl_from_toml_array {
+    ($t:ty, $n:expr) => {
+        impl FromTomlArray for [$t; $n] {
+            type Item = $t;
+            fn from_slice(items: &[$t]) -> [$t; $n] {
+                let mut arr = [items[0]; $n];
+                arr.copy_from_slice(items);
+                arr
+            }
+            fn len() -> usize {
+                $n
+            }
+        }
+    }
+}
+
+impl_from_toml_array!(f32, 2);
+impl_from_toml_array!(f32, 3);
+impl_from_toml_array!(f32, 4);
+impl_from_toml_array!(u32, 2);
+
+fn float(table: &Tracked, key: &str) -> Result<Option<f32>, ConfigError> {
+    number(table, key, "a number")
+}
+
+fn integer<T: FromToml>(table: &Tracked, key: &str) -> Result<Option<T>, ConfigError> {
+    number(table, key, "a non-negative integer")
+}
+
+fn number<T: FromToml>(table: &Tracked, key: &str, expected: &str) -> Result<Option<T>, ConfigError> {
+    match table.lookup(key) {
+        None => Ok(None),
+        Some(value) => {
+            T::from_toml(value)
+                .map(Some)
+                .ok_or_else(|| invalid(key, &format!("expected {}", expected)))
+        },
+    }
+}
+
+fn boolean(table: &Tracked, key: &str) -> Result<Option<bool>, ConfigError> {
+    match table.lookup(key) {
+        None => Ok(None),
+        Some(value) => {
+            value.as_bool()
+                .map(Some)
+                .ok_or_else(|| invalid(key, "expected true or false"))
+        },
+    }
+}
+
+fn array<A: FromTomlArray>(table: &Tracked, key: &str) -> Result<Option<A>, ConfigError> {
+    let expected = || invalid(key, &format!("expected an array of {} numbers", A::len()));
+
+    match table.lookup(key) {
+        None => Ok(None),
+        Some(value) => {
+            let items = try!(value.as_slice().ok_or_else(&expected));
+            if items.len() != A::len() {
+                return Err(expected());
+            }
+
+            let mut parsed = Vec::with_capacity(items.len());
+            for item in items {
+                parsed.push(try!(A::Item::from_toml(item).ok_or_else(&expected)));
+            }
+            Ok(Some(A::from_slice(&parsed)))
+        },
+    }
+}
+
+fn string(table: &Tracked, key: &str) -> Result<Option<String>, ConfigError> {
+    match table.lookup(key) {
+        None => Ok(None),
+        Some(value) => {
+            value.as_str()
+                .map(|s| Some(s.to_string()))
+                .ok_or_else(|| invalid(key, "expected a string"))
+        },
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> ConfigError {
+        ConfigError::IoError(err)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::IoError(ref err) => write!(f, "{}", err),
+            ConfigError::Parse(ref errors) => write!(f, "{}", errors),
+            ConfigError::Invalid { ref key, ref reason } => write!(f, "{}: {}", key, reason),
+            ConfigError::Unknown(ref keys) => {
+                let lines = keys.iter().map(|key| format!("{}: unknown key", key)).collect::<Vec<_>>();
+                write!(f, "{}", lines.join("\n"))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scene_has_no_unknown_keys() {
+        Config::parse(include_str!("../scene.toml")).unwrap();
+    }
+
+    #[test]
+    fn fixed_light_direction() {
+        let config = Config::parse("[sky]\nfixed_direction = [0.3, 0.4, 0.1]").unwrap();
+        assert_eq!(config.sky.fixed_direction, Some([0.3, 0.4, 0.1]));
+        assert_eq!(Config::default().sky.fixed_direction, None);
+
+        match Config::parse("[light]\ndirection = [0.3, 0.4, 0.1]") {
+            Err(ConfigError::Invalid { key, reason }) => {
+                assert_eq!(key, "light.direction");
+                assert!(reason.contains("sky.fixed_direction"));
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let toml = r#"
+            colour = "red"
+
+            [terrain]
+            seed = 3
+            sead = 4
+
+            [camera]
+            fov = 70
+            position = [1, 2, 3]
+            zoom = 2
+
+            [[splat.layers]]
+            texture = "a.png"
+
+            [[splat.layers]]
+            texture = "b.png"
+            heigth = [0, 1]
+        "#;
+        match Config::parse(toml) {
+            Err(ConfigError::Unknown(keys)) => assert_eq!(keys, vec![
+                "camera.zoom".to_string(),
+                "colour".to_string(),
+                "splat.layers[1].heigth".to_string(),
+                "terrain.sead".to_string(),
+            ]),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn value_instead_of_section() {
+        match Config::parse("terrain = 5") {
+            Err(ConfigError::Unknown(keys)) => assert_eq!(keys, vec!["terrain".to_string()]),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn shadow_distance_within_view() {
+        let config = Config::parse("[camera]\nfar = 500.0\n[shadows]\ndistance = 500.0").unwrap();
+        assert_eq!(config.shadows.distance, 500.0);
+
+        for toml in ["[camera]\nfar = 50.0", "[shadows]\ndistance = 0.05"].iter() {
+            match Config::parse(toml) {
+                Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "shadows.distance"),
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use util::{XorShift, CardinalDirection, FixedDimension};
+use terrain::{Terrain};
+
+#[derive(Copy, Clone, Debug)]
+pub struct HydraulicParams {
+    /// Number of droplets
+    pub iterations: usize,
+    pub seed: u32,
+    /// Maximum number of steps a droplet takes
+    pub max_lifetime: usize,
+    /// How much a droplet keeps its direction instead of following the slope, in `[0, 1]`
+    pub inertia: f32,
+    /// Multiplier for how much sediment a droplet can carry
+    pub sediment_capacity: f32,
+    /// Capacity of droplets on flat terrain, keeps them eroding there
+    pub min_sediment_capacity: f32,
+    /// Fraction of the surplus sediment that is dropped each step
+    pub deposition_rate: f32,
+    /// Fraction of the free capacity that is eroded each step
+    pub erosion_rate: f32,
+    /// Fraction of water that evaporates each step
+    pub evaporation_rate: f32,
+    pub gravity: f32,
+    pub initial_water: f32,
+    pub initial_speed: f32,
+}
+
+impl Default for HydraulicParams {
+    fn default() -> HydraulicParams {
+        HydraulicParams {
+            iterations: 20000,
+            seed: 0,
+            max_lifetime: 30,
+            inertia: 0.05,
+            sediment_capacity: 4.0,
+            min_sediment_capacity: 0.01,
+            deposition_rate: 0.3,
+            erosion_rate: 0.3,
+            evaporation_rate: 0.01,
+            gravity: 4.0,
+            initial_water: 1.0,
+            initial_speed: 1.0,
+        }
+    }
+}
+
+/// Simulates rain droplets running downhill, picking up and depositing sediment.
+/// The droplets are placed pseudo-randomly, the same seed always gives the same result.
+/// Only droplets leaving the map take material with them.
+pub fn hydraulic_erosion(terrain: &mut Terrain, params: &HydraulicParams) {
+    let dims = terrain.dims();
+    if dims[0] < 2 || dims[1] < 2 {
+        return;
+    }
+    let max_pos = [(dims[0] - 1) as f32, (dims[1] - 1) as f32];
+
+    let mut rng = XorShift::new(params.seed);
+
+    for _ in 0..params.iterations {
+        let mut pos = [rng.next_f32() * max_pos[0], rng.next_f32() * max_pos[1]];
+        let mut dir = [0.0, 0.0];
+        let mut speed = params.initial_speed;
+        let mut water = params.initial_water;
+        let mut sediment = 0.0;
+        let mut left_map = false;
+
+        for _ in 0..params.max_lifetime {
+            let cell = [pos[0] as usize, pos[1] as usize];
+            let offset = [pos[0] - cell[0] as f32, pos[1] - cell[1] as f32];
+            let (height, gradient) = height_and_gradient(terrain, cell, offset);
+
+            dir = [
+                dir[0] * params.inertia - gradient[0] * (1.0 - params.inertia),
+                dir[1] * params.inertia - gradient[1] * (1.0 - params.inertia),
+            ];
+            let len = (dir[0] * dir[0] + dir[1] * dir[1]).sqrt();
+            if len == 0.0 {
+                // stuck in a perfectly flat spot
+                break;
+            }
+            dir = [dir[0] / len, dir[1] / len];
+            pos = [pos[0] + dir[0], pos[1] + dir[1]];
+
+            if pos[0] < 0.0 || pos[1] < 0.0 || pos[0] >= max_pos[0] || pos[1] >= max_pos[1] {
+                // left the map, its sediment is lost
+                left_map = true;
+                break;
+            }
+
+            let new_cell = [pos[0] as usize, pos[1] as usize];
+            let new_offset = [pos[0] - new_cell[0] as f32, pos[1] - new_cell[1] as f32];
+            let delta_height = height_and_gradient(terrain, new_cell, new_offset).0 - height;
+
+            let capacity = (-delta_height * speed * water * params.sediment_capacity)
+                .max(params.min_sediment_capacity);
+
+            if sediment > capacity || delta_height > 0.0 {
+                // moving uphill fills the pit behind the droplet, otherwise drop the surplus
+                let amount = if delta_height > 0.0 {
+                    delta_height.min(sediment)
+                } else {
+                    (sediment - capacity) * params.deposition_rate
+                };
+                sediment-= amount;
+                add_bilinear(terrain, cell, offset, amount);
+            } else {
+                // never dig deeper than the height difference, or the droplet would carve pits
+                let amount = ((capacity - sediment) * params.erosion_rate).min(-delta_height);
+                sediment+= amount;
+                add_bilinear(terrain, cell, offset, -amount);
+            }
+
+            speed = (speed * speed - delta_height * params.gravity).max(0.0).sqrt();
+            water*= 1.0 - params.evaporation_rate;
+        }
+
+        if !left_map {
+            // the droplet stopped or dried up, what it carried stays where it is
+            let cell = [pos[0] as usize, pos[1] as usize];
+            let offset = [pos[0] - cell[0] as f32, pos[1] - cell[1] as f32];
+            add_bilinear(terrain, cell, offset, sediment);
+        }
+    }
+}
+
+/// Bilinearly interpolated height and gradient inside the cell whose lower corner is `cell`
+fn height_and_gradient(terrain: &Terrain, cell: [usize; 2], offset: [f32; 2]) -> (f32, [f32; 2]) {
+    let h = |dx, dz| *terrain.get([cell[0] + dx, cell[1] + dz]).unwrap();
+    let (h00, h10, h01, h11) = (h(0, 0), h(1, 0), h(0, 1), h(1, 1));
+    let (u, v) = (offset[0], offset[1]);
+
+    let gradient = [
+        (h10 - h00) * (1.0 - v) + (h11 - h01) * v,
+        (h01 - h00) * (1.0 - u) + (h11 - h10) * u,
+    ];
+    let height =
+        h00 * (1.0 - u) * (1.0 - v) +
+        h10 * u * (1.0 - v) +
+        h01 * (1.0 - u) * v +
+        h11 * u * v;
+
+    (height, gradient)
+}
+
+/// Distributes `amount` over the four corners of `cell`, weighted by their closeness to `offset`
+fn add_bilinear(terrain: &mut Terrain, cell: [usize; 2], offset: [f32; 2], amount: f32) {
+    let (u, v) = (offset[0], offset[1]);
+    let corners = [
+        ([0, 0], (1.0 - u) * (1.0 - v)),
+        ([1, 0], u * (1.0 - v)),
+        ([0, 1], (1.0 - u) * v),
+        ([1, 1], u * v),
+    ];
+
+    for &(d, weight) in corners.iter() {
+        if let Some(h) = terrain.get_mut([cell[0] + d[0], cell[1] + d[1]]) {
+            *h+= amount * weight;
+        }
+    }
+}
+
+/// Which neighbours of a cell thermal erosion moves material to
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Neighbourhood {
+    /// The four cardinal directions
+    VonNeumann,
+    /// Cardinal and diagonal directions
+    Moore,
+}
+
+impl Neighbourhood {
+    fn offsets(self) -> Vec<[i32; 2]> {
+        let cardinal = (0..4)
+            .map(|i| CardinalDirection::from_index(i).unwrap().offset());
+
+        match self {
+            Neighbourhood::VonNeumann => cardinal.collect(),
+            Neighbourhood::Moore => {
+                cardinal
+                    .chain(vec![[1, 1], [1, -1], [-1, 1], [-1, -1]].into_iter())
+                    .collect()
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ThermalParams {
+    pub iterations: usize,
+    /// Steepest stable slope in radians, anything steeper crumbles
+    pub talus_angle: f32,
+    /// Fraction of the excess material moved each iteration, in `(0, 1]`
+    pub rate: f32,
+    /// World size of one cell, needed to turn height differences into angles
+    pub cell_size: [f32; 2],
+    pub neighbourhood: Neighbourhood,
+}
+
+impl Default for ThermalParams {
+    fn default() -> ThermalParams {
+        ThermalParams {
+            iterations: 50,
+            talus_angle: 35.0f32.to_radians(),
+            rate: 0.5,
+            cell_size: [1.0, 1.0],
+            neighbourhood: Neighbourhood::Moore,
+        }
+    }
+}
+
+/// Moves material downhill wherever the slope to a neighbour is steeper than the talus angle.
+/// Each iteration first collects all moves and then applies them, so the result doesn't depend on
+/// the order in which cells are visited.
+pub fn thermal_erosion(terrain: &mut Terrain, params: &ThermalParams) {
+    let dims = terrain.dims();
+    let dims = [dims[0] as i32, dims[1] as i32];
+
+    let talus = params.talus_angle.tan();
+    let neighbours = params.neighbourhood.offsets().into_iter()
+        .map(|offset| {
+            let dist = ((offset[0] as f32 * params.cell_size[0]).powi(2)
+                + (offset[1] as f32 * params.cell_size[1]).powi(2)).sqrt();
+            (offset, talus * dist)
+        })
+        .collect::<Vec<_>>();
+
+    let mut deltas = vec![0.0; terrain.vec.len()];
+
+    for _ in 0..params.iterations {
+        for d in deltas.iter_mut() {
+            *d = 0.0;
+        }
+
+        for (i, coords) in terrain.fixed_dim.coords_iter().take(terrain.vec.len()).enumerate() {
+            let height = terrain.vec[i];
+            let coords = [coords[0] as i32, coords[1] as i32];
+
+            let mut excesses = [(0, 0.0); 8];
+            let mut excess_count = 0;
+            let mut excess_sum = 0.0;
+            let mut excess_max = 0.0f32;
+
+            for &(offset, max_diff) in neighbours.iter() {
+                let n = [coords[0] + offset[0], coords[1] + offset[1]];
+                if n[0] < 0 || n[1] < 0 || n[0] >= dims[0] || n[1] >= dims[1] {
+                    continue;
+                }
+                let n_index = terrain.fixed_dim.to_index([n[0] as usize, n[1] as usize]).unwrap();
+                let excess = height - terrain.vec[n_index] - max_diff;
+                if excess > 0.0 {
+                    excesses[excess_count] = (n_index, excess);
+                    excess_count+= 1;
+                    excess_sum+= excess;
+                    excess_max = excess_max.max(excess);
+                }
+            }
+
+            if excess_count == 0 {
+                continue;
+            }
+
+            // moving half the largest excess would level the steepest pair exactly
+            let amount = params.rate * excess_max / 2.0;
+            deltas[i]-= amount;
+            for &(n_index, excess) in excesses[..excess_count].iter() {
+                deltas[n_index]+= amount * excess / excess_sum;
+            }
+        }
+
+        for (h, d) in terrain.vec.iter_mut().zip(deltas.iter()) {
+            *h+= *d;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terrain::{Terrain};
+    use util::{Mat, FixedHeight};
+
+    /// Rough bowl, everything runs towards the middle and no droplet leaves the map
+    fn bowl(size: usize) -> Terrain {
+        let c = (size - 1) as f32 / 2.0;
+        Mat {
+            vec: (0..size * size)
+                .map(|i| {
+                    let (x, z) = ((i / size) as f32 - c, (i % size) as f32 - c);
+                    (x * x + z * z) * 0.1 + (x * 0.9).sin() * (z * 1.3).cos() * 0.5
+                })
+                .collect(),
+            fixed_dim: FixedHeight::from_height(size).unwrap(),
+        }
+    }
+
+    fn mass(terrain: &Terrain) -> f64 {
+        terrain.vec.iter().fold(0.0, |acc, &h| acc + h as f64)
+    }
+
+    #[test]
+    fn hydraulic_is_deterministic() {
+        let params = HydraulicParams { iterations: 2000, seed: 7, .. Default::default() };
+        let mut a = bowl(48);
+        let mut b = bowl(48);
+        hydraulic_erosion(&mut a, &params);
+        hydraulic_erosion(&mut b, &params);
+        assert!(a.vec != bowl(48).vec);
+        assert_eq!(a.vec, b.vec);
+
+        let mut c = bowl(48);
+        hydraulic_erosion(&mut c, &HydraulicParams { seed: 8, .. params });
+        assert!(a.vec != c.vec);
+    }
+
+    #[test]
+    fn hydraulic_conserves_mass() {
+        let mut terrain = bowl(48);
+        let before = mass(&terrain);
+        hydraulic_erosion(&mut terrain, &HydraulicParams { iterations: 1000, .. Default::default() });
+        let after = mass(&terrain);
+        assert!((after - before).abs() < before.abs() * 1e-4, "{} != {}", after, before);
+    }
+
+    #[test]
+    fn thermal_conserves_mass() {
+        let mut terrain = bowl(32);
+        for h in terrain.vec.iter_mut() {
+            *h*= 4.0;
+        }
+        let before = mass(&terrain);
+        thermal_erosion(&mut terrain, &ThermalParams::default());
+        let after = mass(&terrain);
+        assert!((after - before).abs() < before.abs() * 1e-5, "{} != {}", after, before);
+    }
+
+    fn field<F>(size: usize, f: F) -> Terrain where F: Fn(usize, usize) -> f32 {
+        Mat {
+            vec: (0..size * size).map(|i| f(i / size, i % size)).collect(),
+            fixed_dim: FixedHeight::from_height(size).unwrap(),
+        }
+    }
+
+    fn at(terrain: &Terrain, x: usize, z: usize) -> f32 {
+        terrain.vec[terrain.fixed_dim.to_index([x, z]).unwrap()]
+    }
+
+    #[test]
+    fn thermal_keeps_stable_slopes() {
+        let params = ThermalParams::default();
+        // steepest along the diagonal, just below the talus angle
+        let grad = params.talus_angle.tan() * 0.99 / 2.0f32.sqrt();
+        let plane = field(16, |x, z| (x + z) as f32 * grad);
+        let mut terrain = field(16, |x, z| (x + z) as f32 * grad);
+        thermal_erosion(&mut terrain, &params);
+        assert_eq!(terrain.vec, plane.vec);
+    }
+
+    #[test]
+    fn thermal_moves_to_steep_neighbours_only() {
+        // the middle is 0.4 above its neighbours, only `[3, 2]` and `[2, 3]` are steeper than talus
+        let height = |x, z| match (x, z) {
+            (2, 2) => 2.0,
+            (3, 2) => 1.0,
+            (2, 3) => 0.95,
+            _ => 1.6,
+        };
+        let mut terrain = field(5, &height);
+        let params = ThermalParams { iterations: 1, rate: 1.0, .. Default::default() };
+        thermal_erosion(&mut terrain, &params);
+
+        let talus = params.talus_angle.tan();
+        let excess = [1.0 - talus, 1.05 - talus];
+        let amount = excess[1] / 2.0;
+        for x in 0..5 {
+            for z in 0..5 {
+                let expected = height(x, z) + match (x, z) {
+                    (2, 2) => -amount,
+                    (3, 2) => amount * excess[0] / (excess[0] + excess[1]),
+                    (2, 3) => amount * excess[1] / (excess[0] + excess[1]),
+                    _ => 0.0,
+                };
+                assert!((at(&terrain, x, z) - expected).abs() < 1e-6, "{:?}: {} != {}", (x, z), at(&terrain, x, z), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn thermal_relaxes_a_spike_to_talus() {
+        let mut terrain = field(21, |x, z| if (x, z) == (10, 10) { 10.0 } else { 0.0 });
+        let params = ThermalParams { iterations: 2000, .. Default::default() };
+        thermal_erosion(&mut terrain, &params);
+
+        let talus = params.talus_angle.tan();
+        assert!(at(&terrain, 10, 10) < 3.0);
+        let mut steepest = 0.0f32;
+        for x in 0..20 {
+            for z in 0..20 {
+                steepest = steepest
+                    .max((at(&terrain, x, z) - at(&terrain, x + 1, z)).abs())
+                    .max((at(&terrain, x, z) - at(&terrain, x, z + 1)).abs());
+            }
+        }
+        assert!(steepest <= talus + 1e-3, "{} > {}", steepest, talus);
+        // the pile is not flattened below the talus angle either
+        assert!(steepest > talus * 0.9, "{}", steepest);
+    }
+}
@@ -0,0 +1,449 @@
+use std::io::{self, Write, BufWriter};
+use std::fs::File;
+use std::path::Path;
+use std::mem;
+
+use glium::index::{PrimitiveType};
+
+use mesh::{Mesh, FaceVertex, PRIMITIVE_RESTART, tri_normal};
+
+#[derive(Debug)]
+pub enum ExportError {
+    IoError(io::Error),
+    /// Only triangle lists and strips can be exported
+    UnsupportedPrimitive(PrimitiveType),
+    /// An index points past the vertices
+    IndexOutOfRange(u32),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StlFormat {
+    Ascii,
+    Binary,
+}
+
+/// Vertex indices of the mesh's triangles, for unindexed meshes the vertices are taken in order
+pub fn triangles(mesh: &Mesh<FaceVertex>) -> Result<Vec<[u32; 3]>, ExportError> {
+    let inds = match mesh.inds {
+        Some(ref inds) => inds.clone(),
+        None => (0..mesh.verts.len() as u32).collect(),
+    };
+
+    let tris = match mesh.primitive_type {
+        PrimitiveType::TrianglesList => {
+            inds.chunks(3)
+                .filter(|tri| tri.len() == 3)
+                .map(|tri| [tri[0], tri[1], tri[2]])
+                .collect::<Vec<_>>()
+        },
+        PrimitiveType::TriangleStrip => {
+            let mut tris = Vec::new();
+            for strip in inds.split(|&i| i == PRIMITIVE_RESTART) {
+                for (k, w) in strip.windows(3).enumerate() {
+                    // every second triangle of a strip is flipped to keep the winding
+                    if k % 2 == 0 {
+                        tris.push([w[0], w[1], w[2]]);
+                    } else {
+                        tris.push([w[1], w[0], w[2]]);
+                    }
+                }
+            }
+            tris
+        },
+        other => return Err(ExportError::UnsupportedPrimitive(other)),
+    };
+
+    for tri in tris.iter() {
+        for &i in tri.iter() {
+            if i as usize >= mesh.verts.len() {
+                return Err(ExportError::IndexOutOfRange(i));
+            }
+        }
+    }
+    Ok(tris)
+}
+
+/// `triangles` counter-clockwise seen from above, as the file formats expect. The terrain meshes
+/// wind them clockwise.
+fn exported_triangles(mesh: &Mesh<FaceVertex>) -> Result<Vec<[u32; 3]>, ExportError> {
+    triangles(mesh).map(|tris| tris.into_iter().map(|tri| [tri[0], tri[2], tri[1]]).collect())
+}
+
+/// Wavefront OBJ with positions, texture coordinates and normals
+pub fn save_obj<P: AsRef<Path>>(mesh: &Mesh<FaceVertex>, path: P) -> Result<(), ExportError> {
+    let tris = try!(exported_triangles(mesh));
+    let mut w = BufWriter::new(try!(File::create(path)));
+
+    for v in mesh.verts.iter() {
+        try!(writeln!(w, "v {} {} {}", v.v_pos[0], v.v_pos[1], v.v_pos[2]));
+    }
+    for v in mesh.verts.iter() {
+        try!(writeln!(w, "vt {} {}", v.v_tex_pos[0], v.v_tex_pos[1]));
+    }
+    for v in mesh.verts.iter() {
+        try!(writeln!(w, "vn {} {} {}", v.v_normal[0], v.v_normal[1], v.v_normal[2]));
+    }
+    for tri in tris.iter() {
+        // one-based, the same index for position, texture coordinate and normal
+        let (a, b, c) = (tri[0] + 1, tri[1] + 1, tri[2] + 1);
+        try!(writeln!(w, "f {}/{}/{} {}/{}/{} {}/{}/{}", a, a, a, b, b, b, c, c, c));
+    }
+    Ok(())
+}
+
+/// Binary little endian PLY, the vertices have `x y z nx ny nz s t`
+pub fn save_ply<P: AsRef<Path>>(mesh: &Mesh<FaceVertex>, path: P) -> Result<(), ExportError> {
+    let tris = try!(exported_triangles(mesh));
+    let mut w = BufWriter::new(try!(File::create(path)));
+
+    try!(write!(w, "ply\n\
+        format binary_little_endian 1.0\n\
+        element vertex {}\n\
+        property float x\n\
+        property float y\n\
+        property float z\n\
+        property float nx\n\
+        property float ny\n\
+        property float nz\n\
+        property float s\n\
+        property float t\n\
+        element face {}\n\
+        property list uchar uint vertex_indices\n\
+        end_header\n", mesh.verts.len(), tris.len()));
+
+    let mut data = Vec::with_capacity(mesh.verts.len() * 32 + tris.len() * 13);
+    for v in mesh.verts.iter() {
+        for &x in v.v_pos.iter().chain(v.v_normal.iter()).chain(v.v_tex_pos.iter()) {
+            put_f32(&mut data, x);
+        }
+    }
+    for tri in tris.iter() {
+        data.push(3);
+        for &i in tri.iter() {
+            put_u32(&mut data, i);
+        }
+    }
+    try!(w.write_all(&data));
+    Ok(())
+}
+
+/// STL only stores positions, each facet gets the normal of its plane
+pub fn save_stl<P: AsRef<Path>>(mesh: &Mesh<FaceVertex>, path: P, format: StlFormat) -> Result<(), ExportError> {
+    let tris = try!(exported_triangles(mesh));
+    let mut w = BufWriter::new(try!(File::create(path)));
+
+    let facets = tris.iter().map(|tri| {
+        let verts = [
+            mesh.verts[tri[0] as usize].v_pos,
+            mesh.verts[tri[1] as usize].v_pos,
+            mesh.verts[tri[2] as usize].v_pos,
+        ];
+        (facet_normal(verts), verts)
+    });
+
+    match format {
+        StlFormat::Ascii => {
+            try!(writeln!(w, "solid terrain"));
+            for (n, verts) in facets {
+                try!(writeln!(w, "facet normal {} {} {}", n[0], n[1], n[2]));
+                try!(writeln!(w, "outer loop"));
+                for v in verts.iter() {
+                    try!(writeln!(w, "vertex {} {} {}", v[0], v[1], v[2]));
+                }
+                try!(writeln!(w, "endloop"));
+                try!(writeln!(w, "endfacet"));
+            }
+            try!(writeln!(w, "endsolid terrain"));
+        },
+        StlFormat::Binary => {
+            let mut data = Vec::with_capacity(84 + tris.len() * 50);
+            data.extend_from_slice(&[0; 80]);
+            put_u32(&mut data, tris.len() as u32);
+            for (n, verts) in facets {
+                for &x in n.iter().chain(verts.iter().flat_map(|v| v.iter())) {
+                    put_f32(&mut data, x);
+                }
+                // attribute byte count
+                data.extend_from_slice(&[0, 0]);
+            }
+            try!(w.write_all(&data));
+        },
+    }
+    Ok(())
+}
+
+/// glTF 2.0 with the buffer embedded as data URI, so the mesh is a single file
+pub fn save_gltf<P: AsRef<Path>>(mesh: &Mesh<FaceVertex>, path: P) -> Result<(), ExportError> {
+    let tris = try!(exported_triangles(mesh));
+    let count = mesh.verts.len();
+
+    let mut min = [::std::f32::MAX; 3];
+    let mut max = [::std::f32::MIN; 3];
+    let mut data = Vec::with_capacity(count * 32 + tris.len() * 12);
+    for v in mesh.verts.iter() {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(v.v_pos[axis]);
+            max[axis] = max[axis].max(v.v_pos[axis]);
+            put_f32(&mut data, v.v_pos[axis]);
+        }
+    }
+    for v in mesh.verts.iter() {
+        for &x in v.v_normal.iter() {
+            put_f32(&mut data, x);
+        }
+    }
+    for v in mesh.verts.iter() {
+        // glTF has the origin of texture coordinates at the top left
+        put_f32(&mut data, v.v_tex_pos[0]);
+        put_f32(&mut data, 1.0 - v.v_tex_pos[1]);
+    }
+    for tri in tris.iter() {
+        for &i in tri.iter() {
+            put_u32(&mut data, i);
+        }
+    }
+
+    let (pos_offset, normal_offset, tex_offset, index_offset) = (0, count * 12, count * 24, count * 32);
+    if count == 0 {
+        min = [0.0; 3];
+        max = [0.0; 3];
+    }
+
+    let json = format!(r#"{{
+  "asset": {{ "version": "2.0", "generator": "tetras" }},
+  "scene": 0,
+  "scenes": [{{ "nodes": [0] }}],
+  "nodes": [{{ "mesh": 0 }}],
+  "meshes": [{{
+    "primitives": [{{
+      "attributes": {{ "POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2 }},
+      "indices": 3,
+      "mode": 4
+    }}]
+  }}],
+  "buffers": [{{ "byteLength": {len}, "uri": "data:application/octet-stream;base64,{data}" }}],
+  "bufferViews": [
+    {{ "buffer": 0, "byteOffset": {pos_offset}, "byteLength": {vec3_len}, "target": 34962 }},
+    {{ "buffer": 0, "byteOffset": {normal_offset}, "byteLength": {vec3_len}, "target": 34962 }},
+    {{ "buffer": 0, "byteOffset": {tex_offset}, "byteLength": {vec2_len}, "target": 34962 }},
+    {{ "buffer": 0, "byteOffset": {index_offset}, "byteLength": {index_len}, "target": 34963 }}
+  ],
+  "accessors": [
+    {{ "bufferView": 0, "componentType": 5126, "count": {count}, "type": "VEC3",
+       "min": [{min0}, {min1}, {min2}], "max": [{max0}, {max1}, {max2}] }},
+    {{ "bufferView": 1, "componentType": 5126, "count": {count}, "type": "VEC3" }},
+    {{ "bufferView": 2, "componentType": 5126, "count": {count}, "type": "VEC2" }},
+    {{ "bufferView": 3, "componentType": 5125, "count": {index_count}, "type": "SCALAR" }}
+  ]
+}}
+"#,
+        len = data.len(),
+        data = base64(&data),
+        pos_offset = pos_offset,
+        normal_offset = normal_offset,
+        tex_offset = tex_offset,
+        index_offset = index_offset,
+        vec3_len = count * 12,
+        vec2_len = count * 8,
+        index_len = tris.len() * 12,
+        count = count,
+        index_count = tris.len() * 3,
+        min0 = json_float(min[0]), min1 = json_float(min[1]), min2 = json_float(min[2]),
+        max0 = json_float(max[0]), max1 = json_float(max[1]), max2 = json_float(max[2]));
+
+    let mut file = try!(File::create(path));
+    try!(file.write_all(json.as_bytes()));
+    Ok(())
+}
+
+fn facet_normal(verts: [[f32; 3]; 3]) -> [f32; 3] {
+    let n = tri_normal(verts);
+    // degenerate triangles have no plane
+    if n.iter().any(|x| x.is_nan()) {
+        [0.0, 0.0, 0.0]
+    } else {
+        n
+    }
+}
+
+/// `Display` of floats never uses exponents, so it is valid JSON as long as the number is finite
+fn json_float(x: f32) -> String {
+    if x.is_finite() { x.to_string() } else { "0".to_string() }
+}
+
+fn put_u32(buf: &mut Vec<u8>, x: u32) {
+    buf.extend_from_slice(&[x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8]);
+}
+
+fn put_f32(buf: &mut Vec<u8>, x: f32) {
+    put_u32(buf, unsafe { mem::transmute::<f32, u32>(x) });
+}
+
+fn base64(data: &[u8]) -> String {
+    const CHARS: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for k in 0..4 {
+            if k <= chunk.len() {
+                out.push(CHARS[(n >> (18 - 6 * k)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+impl From<io::Error> for ExportError {
+    fn from(err: io::Error) -> ExportError {
+        ExportError::IoError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::{Read};
+    use std::path::{PathBuf};
+
+    use terrain::{Terrain};
+    use util::{Mat, FixedHeight};
+    use mesh::{self, Mesh, FaceVertex, IndexLayout, NormalMethod};
+    use super::*;
+
+    /// 4 by 3 samples of gentle hills, 12 triangles
+    fn terrain_mesh(layout: IndexLayout) -> Mesh<FaceVertex> {
+        let terrain: Terrain = Mat {
+            vec: (0..12).map(|i| ((i / 3) as f32 * 0.7).sin() + (i % 3) as f32 * 0.2).collect(),
+            fixed_dim: FixedHeight::from_height(3).unwrap(),
+        };
+        mesh::indexed_terrain_mesh(&terrain, [1.0, 1.5], 4, layout, NormalMethod::CentralDifference)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("terrain-export-test-{}", name))
+    }
+
+    fn read(path: &PathBuf) -> Vec<u8> {
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    fn get_u32(data: &[u8], offset: usize) -> u32 {
+        (0..4).fold(0, |acc, k| acc | (data[offset + k] as u32) << (8 * k))
+    }
+
+    fn get_f32(data: &[u8], offset: usize) -> f32 {
+        unsafe { mem::transmute::<u32, f32>(get_u32(data, offset)) }
+    }
+
+    fn normal(a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> [f32; 3] {
+        let (u, v) = ([b[0] - a[0], b[1] - a[1], b[2] - a[2]], [c[0] - a[0], c[1] - a[1], c[2] - a[2]]);
+        [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]]
+    }
+
+    #[test]
+    fn ascii_stl() {
+        let path = temp_path("ascii.stl");
+        save_stl(&terrain_mesh(IndexLayout::Triangles), &path, StlFormat::Ascii).unwrap();
+        let text = String::from_utf8(read(&path)).unwrap();
+
+        let mut facets = 0;
+        let mut verts = Vec::new();
+        for line in text.lines() {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let floats = |from: usize| words[from..].iter().map(|w| w.parse::<f32>().unwrap()).collect::<Vec<_>>();
+            match words[0] {
+                "facet" => {
+                    assert!(floats(2)[1] > 0.0, "{}", line);
+                    facets+= 1;
+                },
+                "vertex" => {
+                    let v = floats(1);
+                    verts.push([v[0], v[1], v[2]]);
+                },
+                _ => {},
+            }
+        }
+        assert_eq!(facets, 12);
+        assert_eq!(verts.len(), 36);
+        for tri in verts.chunks(3) {
+            assert!(normal(tri[0], tri[1], tri[2])[1] > 0.0);
+        }
+    }
+
+    #[test]
+    fn binary_stl() {
+        let path = temp_path("binary.stl");
+        save_stl(&terrain_mesh(IndexLayout::Strips), &path, StlFormat::Binary).unwrap();
+        let data = read(&path);
+
+        let facets = get_u32(&data, 80) as usize;
+        assert_eq!(facets, 12);
+        assert_eq!(data.len(), 84 + facets * 50);
+        for i in 0..facets {
+            let offset = 84 + i * 50;
+            let v = |k: usize| [get_f32(&data, offset + k * 12), get_f32(&data, offset + k * 12 + 4), get_f32(&data, offset + k * 12 + 8)];
+            assert!(v(0)[1] > 0.0);
+            assert!(normal(v(1), v(2), v(3))[1] > 0.0);
+        }
+    }
+
+    /// The number after `"key": ` at the `n`th occurrence of the key
+    fn json_number(json: &str, key: &str, n: usize) -> usize {
+        let pattern = format!("\"{}\": ", key);
+        let start = json.match_indices(&pattern[..]).nth(n).unwrap().0 + pattern.len();
+        json[start..].chars().take_while(|c| c.is_digit(10)).collect::<String>().parse().unwrap()
+    }
+
+    fn unbase64(text: &str) -> Vec<u8> {
+        const CHARS: &'static str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let values = text.chars()
+            .take_while(|&c| c != '=')
+            .map(|c| CHARS.find(c).unwrap() as u32)
+            .collect::<Vec<_>>();
+        let mut data = Vec::new();
+        for chunk in values.chunks(4) {
+            let n = chunk.iter().enumerate().fold(0, |acc, (k, &v)| acc | v << (18 - 6 * k));
+            for k in 0..chunk.len() - 1 {
+                data.push((n >> (16 - 8 * k)) as u8);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn gltf() {
+        let mesh = terrain_mesh(IndexLayout::Triangles);
+        let path = temp_path("mesh.gltf");
+        save_gltf(&mesh, &path).unwrap();
+        let json = String::from_utf8(read(&path)).unwrap();
+
+        let prefix = "base64,";
+        let start = json.find(prefix).unwrap() + prefix.len();
+        let end = start + json[start..].find('"').unwrap();
+        let data = unbase64(&json[start..end]);
+        assert_eq!(data.len(), json_number(&json, "byteLength", 0));
+
+        let count = json_number(&json, "count", 0);
+        let index_count = json_number(&json, "count", 3);
+        assert_eq!(count, 12);
+        assert_eq!(index_count, 36);
+
+        let index_offset = json_number(&json, "byteOffset", 3);
+        let pos = |i: u32| {
+            let offset = i as usize * 12;
+            [get_f32(&data, offset), get_f32(&data, offset + 4), get_f32(&data, offset + 8)]
+        };
+        for tri in 0..index_count / 3 {
+            let i = |k: usize| get_u32(&data, index_offset + (tri * 3 + k) * 4);
+            assert!(normal(pos(i(0)), pos(i(1)), pos(i(2)))[1] > 0.0);
+        }
+        assert_eq!(pos(5), mesh.verts[5].v_pos);
+    }
+}
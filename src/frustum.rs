@@ -0,0 +1,150 @@
+use cg::{Matrix4};
+
+/// The six planes bounding what a camera sees, as `[a, b, c, d]` with the normal pointing
+/// inside: a point `p` is on the inner side if `a * p.x + b * p.y + c * p.z + d >= 0`
+#[derive(Copy, Clone, Debug)]
+pub struct Frustum {
+    pub planes: [[f32; 4]; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes from the matrix the scene is rendered with (Gribb/Hartmann),
+    /// in the space the matrix transforms from. Uses OpenGL clip space, `-w <= z <= w`.
+    pub fn from_projview(projview: &Matrix4<f32>) -> Frustum {
+        let m: &[[f32; 4]; 4] = projview.as_ref();
+        let row = |i: usize| [m[0][i], m[1][i], m[2][i], m[3][i]];
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+
+        let add = |a: [f32; 4], b: [f32; 4]| [a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3]];
+        let sub = |a: [f32; 4], b: [f32; 4]| [a[0] - b[0], a[1] - b[1], a[2] - b[2], a[3] - b[3]];
+
+        let mut planes = [
+            add(r3, r0), // left
+            sub(r3, r0), // right
+            add(r3, r1), // bottom
+            sub(r3, r1), // top
+            add(r3, r2), // near
+            sub(r3, r2), // far
+        ];
+
+        for plane in planes.iter_mut() {
+            let len = (plane[0] * plane[0] + plane[1] * plane[1] + plane[2] * plane[2]).sqrt();
+            if len > 0.0 {
+                for x in plane.iter_mut() {
+                    *x/= len;
+                }
+            }
+        }
+
+        Frustum {
+            planes: planes,
+        }
+    }
+
+    /// Signed distance of the point to each plane is non-negative
+    pub fn contains_point(&self, p: [f32; 3]) -> bool {
+        self.planes.iter().all(|plane| distance(plane, p) >= 0.0)
+    }
+
+    /// Conservative, boxes near the corners of the frustum may be reported as visible
+    /// although they are outside
+    pub fn intersects_aabb(&self, min: [f32; 3], max: [f32; 3]) -> bool {
+        self.planes.iter().all(|plane| {
+            // the corner furthest along the plane's normal
+            let mut p = [0.0; 3];
+            for axis in 0..3 {
+                p[axis] = if plane[axis] >= 0.0 { max[axis] } else { min[axis] };
+            }
+            distance(plane, p) >= 0.0
+        })
+    }
+}
+
+fn distance(plane: &[f32; 4], p: [f32; 3]) -> f32 {
+    plane[0] * p[0] + plane[1] * p[1] + plane[2] * p[2] + plane[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use cg::{self, Matrix4, Point3, Vector3};
+
+    use super::*;
+
+    /// 90 degrees wide and high, from `[10, 5, 10]` towards negative z, depth 1 to 100.
+    /// At view depth `d` the frustum spans `d` to each side.
+    fn frustum() -> Frustum {
+        let proj = cg::perspective(cg::deg(90.0), 1.0, 1.0, 100.0);
+        let view = Matrix4::look_at(Point3::new(10.0, 5.0, 10.0), Point3::new(10.0, 5.0, 0.0), Vector3::new(0.0, 1.0, 0.0));
+        Frustum::from_projview(&(proj * view))
+    }
+
+    /// Box of half size 1 around the point at view depth `d` and offsets `x`, `y` from the view axis
+    fn cube(x: f32, y: f32, d: f32) -> ([f32; 3], [f32; 3]) {
+        let c = [10.0 + x, 5.0 + y, 10.0 - d];
+        ([c[0] - 1.0, c[1] - 1.0, c[2] - 1.0], [c[0] + 1.0, c[1] + 1.0, c[2] + 1.0])
+    }
+
+    #[test]
+    fn planes_are_normalized() {
+        for plane in frustum().planes.iter() {
+            let len = (plane[0] * plane[0] + plane[1] * plane[1] + plane[2] * plane[2]).sqrt();
+            assert!((len - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn points() {
+        let f = frustum();
+        assert!(f.contains_point([10.0, 5.0, 0.0]));
+        assert!(f.contains_point([10.0 - 9.9, 5.0 + 9.9, 0.0]));
+        assert!(!f.contains_point([10.0, 5.0, 10.5]));
+        assert!(!f.contains_point([10.0 + 10.1, 5.0, 0.0]));
+        assert!(!f.contains_point([10.0, 5.0, -91.0]));
+    }
+
+    #[test]
+    fn boxes_inside() {
+        let f = frustum();
+        for &(x, y, d) in [(0.0, 0.0, 10.0), (7.0, -7.0, 10.0), (0.0, 0.0, 98.0), (-40.0, 40.0, 60.0)].iter() {
+            let (min, max) = cube(x, y, d);
+            assert!(f.intersects_aabb(min, max), "{:?}", (x, y, d));
+        }
+    }
+
+    #[test]
+    fn boxes_outside_each_plane() {
+        let f = frustum();
+        let outside = [
+            (-15.0, 0.0, 10.0), // left
+            (15.0, 0.0, 10.0), // right
+            (0.0, -15.0, 10.0), // bottom
+            (0.0, 15.0, 10.0), // top
+            (0.0, 0.0, -2.0), // behind the near plane
+            (0.0, 0.0, 102.0), // beyond the far plane
+        ];
+        for &(x, y, d) in outside.iter() {
+            let (min, max) = cube(x, y, d);
+            assert!(!f.intersects_aabb(min, max), "{:?}", (x, y, d));
+        }
+    }
+
+    #[test]
+    fn boxes_straddling() {
+        let f = frustum();
+        let straddling = [
+            (-10.5, 0.0, 10.0), // left
+            (10.5, 0.0, 10.0), // right
+            (0.0, -10.5, 10.0), // bottom
+            (0.0, 10.5, 10.0), // top
+            (0.0, 0.0, 0.5), // near
+            (0.0, 0.0, 100.5), // far
+        ];
+        for &(x, y, d) in straddling.iter() {
+            let (min, max) = cube(x, y, d);
+            assert!(f.intersects_aabb(min, max), "{:?}", (x, y, d));
+        }
+
+        // contains the whole frustum
+        assert!(f.intersects_aabb([-200.0, -200.0, -200.0], [200.0, 200.0, 200.0]));
+    }
+}
@@ -0,0 +1,236 @@
+extern crate noise;
+
+use self::noise::{Seed};
+
+/// Something that can be sampled for a terrain height at any point of the plane
+pub trait HeightSource {
+    /// Returns the height at `pos`, which lies in `[-amplitude(), amplitude()]`
+    fn height(&self, pos: [f32; 2]) -> f32;
+    fn amplitude(&self) -> f32;
+}
+
+/// Single-octave noise function each fractal octave is built from
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Basis {
+    Perlin,
+    OpenSimplex,
+    Value,
+    Worley,
+}
+
+/// How the octaves of a `Fractal` are combined
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FractalKind {
+    /// Plain fractional brownian motion
+    Fbm,
+    /// fBm of the absolute basis values, gives puffy rounded hills
+    Billow,
+    /// Musgrave's ridged multifractal, gives sharp mountain ridges
+    Ridged,
+    /// Musgrave's hybrid multifractal, smooth valleys and rough peaks
+    HybridMultifractal,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct FractalParams {
+    pub octaves: usize,
+    /// Wavelength of the first octave in world units
+    pub wavelength: f32,
+    /// Frequency multiplier between two octaves
+    pub lacunarity: f32,
+    /// Amplitude multiplier between two octaves
+    pub persistence: f32,
+}
+
+impl Default for FractalParams {
+    fn default() -> FractalParams {
+        FractalParams {
+            octaves: 8,
+            wavelength: 240.0,
+            lacunarity: 2.0,
+            persistence: 0.5,
+        }
+    }
+}
+
+pub struct Fractal {
+    pub kind: FractalKind,
+    pub basis: Basis,
+    pub params: FractalParams,
+    seed: Seed,
+    raw_seed: u32,
+}
+
+/// Offset added to the basis values by the multifractal kinds
+const RIDGE_OFFSET: f32 = 1.0;
+const RIDGE_GAIN: f32 = 2.0;
+const HYBRID_OFFSET: f32 = 0.7;
+
+impl Fractal {
+    pub fn new(kind: FractalKind, basis: Basis, seed: u32) -> Fractal {
+        Fractal {
+            kind: kind,
+            basis: basis,
+            params: FractalParams::default(),
+            seed: Seed::new(seed),
+            raw_seed: seed,
+        }
+    }
+
+    pub fn fbm(basis: Basis, seed: u32) -> Fractal {
+        Fractal::new(FractalKind::Fbm, basis, seed)
+    }
+
+    pub fn billow(basis: Basis, seed: u32) -> Fractal {
+        Fractal::new(FractalKind::Billow, basis, seed)
+    }
+
+    pub fn ridged(basis: Basis, seed: u32) -> Fractal {
+        Fractal::new(FractalKind::Ridged, basis, seed)
+    }
+
+    pub fn hybrid_multifractal(basis: Basis, seed: u32) -> Fractal {
+        Fractal::new(FractalKind::HybridMultifractal, basis, seed)
+    }
+
+    pub fn params(self, params: FractalParams) -> Fractal {
+        Fractal { params: params, .. self }
+    }
+
+    pub fn octaves(mut self, octaves: usize) -> Fractal {
+        self.params.octaves = octaves;
+        self
+    }
+
+    pub fn wavelength(mut self, wavelength: f32) -> Fractal {
+        self.params.wavelength = wavelength;
+        self
+    }
+
+    pub fn lacunarity(mut self, lacunarity: f32) -> Fractal {
+        self.params.lacunarity = lacunarity;
+        self
+    }
+
+    pub fn persistence(mut self, persistence: f32) -> Fractal {
+        self.params.persistence = persistence;
+        self
+    }
+
+    /// Basis value of one octave, in `[-1, 1]`
+    fn basis(&self, octave: usize, pos: [f32; 2]) -> f32 {
+        match self.basis {
+            Basis::Perlin => noise::perlin2(&self.seed, &pos),
+            Basis::OpenSimplex => noise::open_simplex2(&self.seed, &pos),
+            // the lattice noises are decorrelated per octave, the others get that from the frequency
+            Basis::Value => value2(self.raw_seed.wrapping_add(octave as u32), pos),
+            Basis::Worley => worley2(self.raw_seed.wrapping_add(octave as u32), pos),
+        }
+    }
+
+    /// Amplitude of each octave, summed
+    fn octave_sum(&self) -> f32 {
+        (0..self.params.octaves).fold(0.0, |acc, i| acc + self.params.persistence.powi(i as i32))
+    }
+}
+
+impl HeightSource for Fractal {
+    fn height(&self, pos: [f32; 2]) -> f32 {
+        let mut frequency = 1.0 / self.params.wavelength;
+        let mut amplitude = 1.0;
+        let mut result = 0.0;
+        let mut weight = 1.0;
+
+        for octave in 0..self.params.octaves {
+            let p = [pos[0] * frequency, pos[1] * frequency];
+            let n = self.basis(octave, p);
+
+            result+= match self.kind {
+                FractalKind::Fbm => n * amplitude,
+                FractalKind::Billow => (n.abs() * 2.0 - 1.0) * amplitude,
+                FractalKind::Ridged => {
+                    let signal = (RIDGE_OFFSET - n.abs()).powi(2) * weight;
+                    weight = (signal * RIDGE_GAIN).max(0.0).min(1.0);
+                    signal * amplitude
+                },
+                FractalKind::HybridMultifractal => {
+                    let signal = (n + HYBRID_OFFSET) * amplitude;
+                    let contribution = if octave == 0 { signal } else { signal * weight };
+                    weight = (if octave == 0 { signal } else { weight * signal }).max(0.0).min(1.0);
+                    contribution
+                },
+            };
+
+            frequency*= self.params.lacunarity;
+            amplitude*= self.params.persistence;
+        }
+
+        // the multifractals are positive, center them around zero
+        match self.kind {
+            FractalKind::Fbm | FractalKind::Billow => result,
+            FractalKind::Ridged => result * 2.0 - self.octave_sum(),
+            FractalKind::HybridMultifractal => result - self.amplitude(),
+        }
+    }
+
+    fn amplitude(&self) -> f32 {
+        match self.kind {
+            FractalKind::Fbm | FractalKind::Billow | FractalKind::Ridged => self.octave_sum(),
+            FractalKind::HybridMultifractal => self.octave_sum() * (1.0 + HYBRID_OFFSET) / 2.0,
+        }
+    }
+}
+
+/// Integer hash of a lattice point
+fn hash2(seed: u32, x: i32, y: i32) -> u32 {
+    let mut h = seed
+        ^ (x as u32).wrapping_mul(0x27d4eb2d)
+        ^ (y as u32).wrapping_mul(0x165667b1);
+    h^= h >> 15;
+    h = h.wrapping_mul(0x2c1b3c6d);
+    h^= h >> 12;
+    h = h.wrapping_mul(0x297a2d39);
+    h^= h >> 15;
+    h
+}
+
+/// Maps a hash to `[0, 1]`
+fn unit(h: u32) -> f32 {
+    (h & 0xffffff) as f32 / 0xffffff as f32
+}
+
+/// Smoothly interpolated random values on the integer lattice, in `[-1, 1]`
+pub fn value2(seed: u32, pos: [f32; 2]) -> f32 {
+    let x0 = pos[0].floor();
+    let y0 = pos[1].floor();
+    let (ix, iy) = (x0 as i32, y0 as i32);
+
+    let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+    let tx = smooth(pos[0] - x0);
+    let ty = smooth(pos[1] - y0);
+
+    let v = |dx, dy| unit(hash2(seed, ix + dx, iy + dy)) * 2.0 - 1.0;
+    let bottom = v(0, 0) + (v(1, 0) - v(0, 0)) * tx;
+    let top = v(0, 1) + (v(1, 1) - v(0, 1)) * tx;
+    bottom + (top - bottom) * ty
+}
+
+/// Cellular noise: distance to the closest of one random feature point per lattice cell, in `[-1, 1]`
+pub fn worley2(seed: u32, pos: [f32; 2]) -> f32 {
+    let cx = pos[0].floor() as i32;
+    let cy = pos[1].floor() as i32;
+
+    let mut min_dist_sq = ::std::f32::MAX;
+    for dy in -1..2 {
+        for dx in -1..2 {
+            let (x, y) = (cx + dx, cy + dy);
+            let h = hash2(seed, x, y);
+            let fx = x as f32 + unit(h);
+            let fy = y as f32 + unit(hash2(h, y, x));
+            let dist_sq = (fx - pos[0]).powi(2) + (fy - pos[1]).powi(2);
+            min_dist_sq = min_dist_sq.min(dist_sq);
+        }
+    }
+
+    min_dist_sq.sqrt().min(1.0) * 2.0 - 1.0
+}
@@ -0,0 +1,508 @@
+extern crate image;
+
+use std::io::{self, Read, BufRead, BufReader, Write, BufWriter};
+use std::fs::File;
+use std::path::Path;
+
+use self::image::{ImageDecoder, ImageError, ColorType, DecodingResult};
+use self::image::png::{PNGDecoder, PNGEncoder};
+
+use util::{Mat, FixedHeight, MapRange};
+use terrain::{Terrain, Area};
+
+#[derive(Debug)]
+pub enum HeightmapError {
+    IoError(io::Error),
+    ImageError(ImageError),
+    UnsupportedColorType(ColorType),
+    /// The data doesn't contain exactly `width * height` samples
+    SizeMismatch { expected: usize, actual: usize },
+    /// 16 bit data ends in the middle of a sample
+    PartialSample,
+    /// Width or height is zero
+    Empty,
+    Parse(String),
+}
+
+/// Header of an ESRI ASCII grid
+#[derive(Copy, Clone, Debug)]
+pub struct AsciiGridHeader {
+    pub ncols: usize,
+    pub nrows: usize,
+    /// Lower left corner of the grid, in world units
+    pub corner: [f32; 2],
+    pub cell_size: f32,
+    pub nodata: Option<f32>,
+}
+
+/// Sidecar of an exported heightmap, needed to turn the normalized samples back into heights
+#[derive(Copy, Clone)]
+pub struct HeightmapMeta {
+    pub area: Area,
+    /// Height of the lowest sample
+    pub min_height: f32,
+    /// Height of the highest sample
+    pub max_height: f32,
+    /// Seed the terrain was generated with, if it was generated
+    pub seed: Option<u32>,
+}
+
+impl HeightmapMeta {
+    pub fn new(terrain: &Terrain, area: Area, seed: Option<u32>) -> HeightmapMeta {
+        let range = height_range(terrain);
+        HeightmapMeta {
+            area: area,
+            min_height: range[0],
+            max_height: range[1],
+            seed: seed,
+        }
+    }
+
+    /// Range to pass to the loaders to get the original heights back
+    pub fn range(&self) -> [f32; 2] {
+        [self.min_height, self.max_height]
+    }
+}
+
+/// Builds a terrain from row-major samples. All formats store the northernmost row first, like a
+/// map, so the first row becomes the highest y of the terrain.
+pub fn from_rows<F>(dims: [usize; 2], f: F) -> Result<Terrain, HeightmapError> where F: Fn(usize) -> f32 {
+    let fixed_dim = try!(FixedHeight::from_height(dims[1]).ok_or(HeightmapError::Empty));
+    if dims[0] == 0 {
+        return Err(HeightmapError::Empty);
+    }
+
+    let vec = (0..dims[0] * dims[1])
+        .map(|i| {
+            // the terrain is y-major, the input x-major
+            let (x, y) = (i / dims[1], i % dims[1]);
+            f((dims[1] - 1 - y) * dims[0] + x)
+        })
+        .collect();
+
+    Ok(Mat {
+        vec: vec,
+        fixed_dim: fixed_dim,
+    })
+}
+
+/// Loads an 8- or 16-bit grayscale PNG, mapping black to `range[0]` and white to `range[1]`. The
+/// top row becomes the highest y.
+pub fn load_png<P: AsRef<Path>>(path: P, range: [f32; 2]) -> Result<Terrain, HeightmapError> {
+    let file = try!(File::open(path));
+    read_png(BufReader::new(file), range)
+}
+
+pub fn read_png<R: Read>(reader: R, range: [f32; 2]) -> Result<Terrain, HeightmapError> {
+    let mut decoder = PNGDecoder::new(reader);
+    let (w, h) = try!(decoder.dimensions());
+    let color_type = try!(decoder.colortype());
+    let data = try!(decoder.read_image());
+    let dims = [w as usize, h as usize];
+
+    match (color_type, data) {
+        (ColorType::Gray(8), DecodingResult::U8(data)) => {
+            try!(check_len(dims, data.len()));
+            from_rows(dims, |i| (data[i] as f32).map_range([0.0, 255.0], range))
+        },
+        // 16 bit samples are stored big endian
+        (ColorType::Gray(16), DecodingResult::U8(data)) => {
+            try!(check_bytes(dims, data.len(), 2));
+            from_rows(dims, |i| {
+                let sample = (data[2 * i] as u16) << 8 | data[2 * i + 1] as u16;
+                (sample as f32).map_range([0.0, 65535.0], range)
+            })
+        },
+        (ColorType::Gray(16), DecodingResult::U16(data)) => {
+            try!(check_len(dims, data.len()));
+            from_rows(dims, |i| (data[i] as f32).map_range([0.0, 65535.0], range))
+        },
+        (color_type, _) => Err(HeightmapError::UnsupportedColorType(color_type)),
+    }
+}
+
+/// Loads headerless little endian 16 bit samples (`.r16`/`.raw`), mapping 0 to `range[0]` and
+/// 65535 to `range[1]`. The first row becomes the highest y.
+pub fn load_raw<P: AsRef<Path>>(path: P, dims: [usize; 2], range: [f32; 2]) -> Result<Terrain, HeightmapError> {
+    let file = try!(File::open(path));
+    read_raw(BufReader::new(file), dims, range)
+}
+
+pub fn read_raw<R: Read>(mut reader: R, dims: [usize; 2], range: [f32; 2]) -> Result<Terrain, HeightmapError> {
+    let mut data = Vec::new();
+    try!(reader.read_to_end(&mut data));
+    try!(check_bytes(dims, data.len(), 2));
+
+    from_rows(dims, |i| {
+        let sample = data[2 * i] as u16 | (data[2 * i + 1] as u16) << 8;
+        (sample as f32).map_range([0.0, 65535.0], range)
+    })
+}
+
+/// Loads an ESRI ASCII grid, mapping its lowest height to `range[0]` and its highest to
+/// `range[1]`. Cells without data get the lowest height. The first row of the grid is the
+/// northernmost one, it becomes the highest y of the terrain.
+pub fn load_ascii_grid<P: AsRef<Path>>(path: P, range: [f32; 2])
+        -> Result<(Terrain, AsciiGridHeader), HeightmapError> {
+    let file = try!(File::open(path));
+    read_ascii_grid(BufReader::new(file), range)
+}
+
+pub fn read_ascii_grid<R: BufRead>(reader: R, range: [f32; 2])
+        -> Result<(Terrain, AsciiGridHeader), HeightmapError> {
+    let mut ncols = None;
+    let mut nrows = None;
+    let mut corner = [0.0, 0.0];
+    let mut cell_size = 1.0;
+    let mut nodata = None;
+    let mut values = Vec::new();
+
+    for line in reader.lines() {
+        let line = try!(line);
+        let mut tokens = line.split_whitespace().peekable();
+
+        let is_header = tokens.peek()
+            .map(|t| t.chars().next().map(|c| c.is_alphabetic()).unwrap_or(false))
+            .unwrap_or(false);
+
+        if is_header {
+            let key = tokens.next().unwrap().to_lowercase();
+            let value = try!(tokens.next().ok_or_else(|| HeightmapError::Parse(format!("missing value for {}", key))));
+            match &key[..] {
+                "ncols" => ncols = Some(try!(parse::<usize>(value))),
+                "nrows" => nrows = Some(try!(parse::<usize>(value))),
+                // the center variants are half a cell off, the difference doesn't matter for heights
+                "xllcorner" | "xllcenter" => corner[0] = try!(parse::<f32>(value)),
+                "yllcorner" | "yllcenter" => corner[1] = try!(parse::<f32>(value)),
+                "cellsize" => cell_size = try!(parse::<f32>(value)),
+                "nodata_value" => nodata = Some(try!(parse::<f32>(value))),
+                _ => return Err(HeightmapError::Parse(format!("unknown header field {}", key))),
+            }
+        } else {
+            for token in tokens {
+                values.push(try!(parse::<f32>(token)));
+            }
+        }
+    }
+
+    let ncols = try!(ncols.ok_or(HeightmapError::Parse("missing ncols".to_string())));
+    let nrows = try!(nrows.ok_or(HeightmapError::Parse("missing nrows".to_string())));
+    let dims = [ncols, nrows];
+    try!(check_len(dims, values.len()));
+
+    let is_data = |v: f32| nodata.map(|nd| v != nd).unwrap_or(true);
+    let (min, max) = values.iter().cloned()
+        .filter(|&v| is_data(v))
+        .fold((::std::f32::MAX, ::std::f32::MIN), |(min, max), v| (min.min(v), max.max(v)));
+    // a flat or empty grid maps everything to the lower end of the range
+    let (min, max) = if min < max { (min, max) } else { (0.0, 1.0) };
+
+    let terrain = try!(from_rows(dims, |i| {
+        let v = values[i];
+        let v = if is_data(v) { v } else { min };
+        v.map_range([min, max], range)
+    }));
+
+    let header = AsciiGridHeader {
+        ncols: ncols,
+        nrows: nrows,
+        corner: corner,
+        cell_size: cell_size,
+        nodata: nodata,
+    };
+
+    Ok((terrain, header))
+}
+
+/// Returns the lowest and highest height of the terrain
+pub fn height_range(terrain: &Terrain) -> [f32; 2] {
+    terrain.vec.iter()
+        .fold([::std::f32::MAX, ::std::f32::MIN], |r, &h| [r[0].min(h), r[1].max(h)])
+}
+
+/// Maps the heights to 16 bit samples spanning the full range, in row-major order from the highest y
+fn to_samples(terrain: &Terrain) -> Vec<u16> {
+    let range = height_range(terrain);
+    let dims = terrain.dims();
+
+    (0..dims[0] * dims[1])
+        .map(|i| {
+            let (x, y) = (i % dims[0], dims[1] - 1 - i / dims[0]);
+            let h = *terrain.get([x, y]).unwrap();
+            if range[0] < range[1] {
+                h.map_range(range, [0.0, 65535.0]).round() as u16
+            } else {
+                0
+            }
+        })
+        .collect()
+}
+
+/// Saves the terrain as 16-bit grayscale PNG, the lowest height becomes black and the highest white
+pub fn save_png<P: AsRef<Path>>(terrain: &Terrain, path: P) -> Result<(), HeightmapError> {
+    let file = try!(File::create(path));
+    write_png(BufWriter::new(file), terrain)
+}
+
+pub fn write_png<W: Write>(w: W, terrain: &Terrain) -> Result<(), HeightmapError> {
+    let dims = terrain.dims();
+    let data = to_samples(terrain).iter()
+        .flat_map(|&s| vec![(s >> 8) as u8, s as u8])
+        .collect::<Vec<u8>>();
+
+    try!(PNGEncoder::new(w).encode(&data, dims[0] as u32, dims[1] as u32, ColorType::Gray(16)));
+    Ok(())
+}
+
+/// Saves the terrain as headerless little endian 16 bit samples, the counterpart of `load_raw`
+pub fn save_raw<P: AsRef<Path>>(terrain: &Terrain, path: P) -> Result<(), HeightmapError> {
+    let data = to_samples(terrain).iter()
+        .flat_map(|&s| vec![s as u8, (s >> 8) as u8])
+        .collect::<Vec<u8>>();
+
+    let mut file = try!(File::create(path));
+    try!(file.write_all(&data));
+    Ok(())
+}
+
+/// Saves the terrain as ESRI ASCII grid with the real heights, the counterpart of `load_ascii_grid`.
+/// The format only knows square cells, the cell size is the sample spacing along the width of the area.
+pub fn save_ascii_grid<P: AsRef<Path>>(terrain: &Terrain, path: P, area: Area) -> Result<(), HeightmapError> {
+    let mut w = BufWriter::new(try!(File::create(path)));
+    write_ascii_grid(&mut w, terrain, area)
+}
+
+pub fn write_ascii_grid<W: Write>(w: &mut W, terrain: &Terrain, area: Area) -> Result<(), HeightmapError> {
+    let dims = terrain.dims();
+
+    try!(writeln!(w, "ncols {}", dims[0]));
+    try!(writeln!(w, "nrows {}", dims[1]));
+    try!(writeln!(w, "xllcorner {}", area.x));
+    try!(writeln!(w, "yllcorner {}", area.y));
+    try!(writeln!(w, "cellsize {}", area.w / (dims[0] - 1).max(1) as f32));
+
+    // northernmost row first
+    for y in (0..dims[1]).rev() {
+        let row = (0..dims[0])
+            .map(|x| terrain.get([x, y]).unwrap().to_string())
+            .collect::<Vec<_>>();
+        try!(writeln!(w, "{}", row.join(" ")));
+    }
+
+    Ok(())
+}
+
+/// Writes the sidecar as `key value` lines, like the header of an ASCII grid
+pub fn save_meta<P: AsRef<Path>>(meta: &HeightmapMeta, path: P) -> Result<(), HeightmapError> {
+    let mut w = BufWriter::new(try!(File::create(path)));
+
+    try!(writeln!(w, "area_x {}", meta.area.x));
+    try!(writeln!(w, "area_y {}", meta.area.y));
+    try!(writeln!(w, "area_w {}", meta.area.w));
+    try!(writeln!(w, "area_h {}", meta.area.h));
+    try!(writeln!(w, "min_height {}", meta.min_height));
+    try!(writeln!(w, "max_height {}", meta.max_height));
+    if let Some(seed) = meta.seed {
+        try!(writeln!(w, "seed {}", seed));
+    }
+
+    Ok(())
+}
+
+pub fn load_meta<P: AsRef<Path>>(path: P) -> Result<HeightmapMeta, HeightmapError> {
+    let reader = BufReader::new(try!(File::open(path)));
+    read_meta(reader)
+}
+
+/// Fails if any field but the seed is missing
+pub fn read_meta<R: BufRead>(reader: R) -> Result<HeightmapMeta, HeightmapError> {
+    let mut area = [None; 4];
+    let mut min_height = None;
+    let mut max_height = None;
+    let mut seed = None;
+
+    for line in reader.lines() {
+        let line = try!(line);
+        let mut tokens = line.split_whitespace();
+        let (key, value) = match (tokens.next(), tokens.next()) {
+            (Some(key), Some(value)) => (key, value),
+            (None, _) => continue,
+            (Some(key), None) => return Err(HeightmapError::Parse(format!("missing value for {}", key))),
+        };
+
+        match key {
+            "area_x" => area[0] = Some(try!(parse(value))),
+            "area_y" => area[1] = Some(try!(parse(value))),
+            "area_w" => area[2] = Some(try!(parse(value))),
+            "area_h" => area[3] = Some(try!(parse(value))),
+            "min_height" => min_height = Some(try!(parse(value))),
+            "max_height" => max_height = Some(try!(parse(value))),
+            "seed" => seed = Some(try!(parse(value))),
+            _ => return Err(HeightmapError::Parse(format!("unknown meta field {}", key))),
+        }
+    }
+
+    let required = |value: Option<f32>, key: &str| value.ok_or_else(|| HeightmapError::Parse(format!("missing {}", key)));
+    Ok(HeightmapMeta {
+        area: Area {
+            x: try!(required(area[0], "area_x")),
+            y: try!(required(area[1], "area_y")),
+            w: try!(required(area[2], "area_w")),
+            h: try!(required(area[3], "area_h")),
+        },
+        min_height: try!(required(min_height, "min_height")),
+        max_height: try!(required(max_height, "max_height")),
+        seed: seed,
+    })
+}
+
+fn check_len(dims: [usize; 2], actual: usize) -> Result<(), HeightmapError> {
+    let expected = dims[0] * dims[1];
+    if expected == 0 {
+        Err(HeightmapError::Empty)
+    } else if actual != expected {
+        Err(HeightmapError::SizeMismatch {
+            expected: expected,
+            actual: actual,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// `check_len` for data of `sample_bytes` bytes per sample
+fn check_bytes(dims: [usize; 2], bytes: usize, sample_bytes: usize) -> Result<(), HeightmapError> {
+    if bytes % sample_bytes != 0 {
+        return Err(HeightmapError::PartialSample);
+    }
+    check_len(dims, bytes / sample_bytes)
+}
+
+fn parse<T: ::std::str::FromStr>(s: &str) -> Result<T, HeightmapError> {
+    s.parse().map_err(|_| HeightmapError::Parse(format!("invalid number {}", s)))
+}
+
+impl From<io::Error> for HeightmapError {
+    fn from(err: io::Error) -> HeightmapError {
+        HeightmapError::IoError(err)
+    }
+}
+
+impl From<ImageError> for HeightmapError {
+    fn from(err: ImageError) -> HeightmapError {
+        HeightmapError::ImageError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_size_must_match() {
+        let data = [0u8, 0, 255, 255, 0, 128];
+        let terrain = read_raw(&data[..], [3, 1], [0.0, 1.0]).unwrap();
+        assert_eq!(terrain.vec, vec![0.0, 1.0, 32768.0 / 65535.0]);
+
+        match read_raw(&data[..4], [3, 1], [0.0, 1.0]) {
+            Err(HeightmapError::SizeMismatch { expected: 3, actual: 2 }) => {},
+            other => panic!("{:?}", other.map(|t| t.vec)),
+        }
+        match read_raw(&[0u8; 8][..], [3, 1], [0.0, 1.0]) {
+            Err(HeightmapError::SizeMismatch { expected: 3, actual: 4 }) => {},
+            other => panic!("{:?}", other.map(|t| t.vec)),
+        }
+        for &len in [5, 7].iter() {
+            match read_raw(&[0u8; 7][..len], [3, 1], [0.0, 1.0]) {
+                Err(HeightmapError::PartialSample) => {},
+                other => panic!("{}: {:?}", len, other.map(|t| t.vec)),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_png_is_rejected() {
+        let terrain = from_rows([16, 16], |i| ((i * 37) % 101) as f32).unwrap();
+        let mut data = Vec::new();
+        write_png(&mut data, &terrain).unwrap();
+        let loaded = read_png(&data[..], [0.0, 100.0]).unwrap();
+        assert!(loaded.vec.iter().zip(terrain.vec.iter()).all(|(a, b)| (a - b).abs() < 1e-3));
+
+        // cuts into the image data
+        assert!(read_png(&data[..data.len() / 2], [0.0, 100.0]).is_err());
+    }
+
+    #[test]
+    fn ascii_grid_first_row_is_north() {
+        let grid = "ncols 2\nnrows 3\nxllcorner 10\nyllcorner 20\ncellsize 5\n4 5\n2 3\n0 1\n";
+        let (terrain, header) = read_ascii_grid(grid.as_bytes(), [0.0, 5.0]).unwrap();
+        assert_eq!(header.ncols, 2);
+        assert_eq!(header.nrows, 3);
+        assert_eq!(terrain.dims(), [2, 3]);
+        for y in 0..3 {
+            for x in 0..2 {
+                assert_eq!(*terrain.get([x, y]).unwrap(), (y * 2 + x) as f32);
+            }
+        }
+    }
+
+    #[test]
+    fn formats_agree_on_orientation() {
+        // 3 by 2 samples, north row first
+        let samples = [0u16, 1000, 2000, 30000, 50000, 65535];
+        let raw = samples.iter().flat_map(|&s| vec![s as u8, (s >> 8) as u8]).collect::<Vec<u8>>();
+        let grid = format!("ncols 3\nnrows 2\n{} {} {}\n{} {} {}\n",
+            samples[0], samples[1], samples[2], samples[3], samples[4], samples[5]);
+
+        let from_raw = read_raw(&raw[..], [3, 2], [0.0, 1.0]).unwrap();
+        let (from_grid, _) = read_ascii_grid(grid.as_bytes(), [0.0, 1.0]).unwrap();
+        assert_eq!(*from_raw.get([1, 1]).unwrap(), 1000.0 / 65535.0);
+        assert_eq!(*from_raw.get([1, 0]).unwrap(), 50000.0 / 65535.0);
+        assert!(from_raw.vec.iter().zip(from_grid.vec.iter()).all(|(a, b)| (a - b).abs() < 1e-6),
+            "{:?} {:?}", from_raw.vec, from_grid.vec);
+
+        let mut png = Vec::new();
+        write_png(&mut png, &from_raw).unwrap();
+        let from_png = read_png(&png[..], [0.0, 1.0]).unwrap();
+        assert!(from_raw.vec.iter().zip(from_png.vec.iter()).all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+
+    #[test]
+    fn ascii_grid_size_must_match() {
+        let grid = "ncols 2\nnrows 2\n0 1\n2 3\n4\n";
+        match read_ascii_grid(grid.as_bytes(), [0.0, 1.0]) {
+            Err(HeightmapError::SizeMismatch { expected: 4, actual: 5 }) => {},
+            other => panic!("{:?}", other.map(|t| t.0.vec)),
+        }
+    }
+
+    #[test]
+    fn ascii_grid_round_trip() {
+        let terrain = from_rows([3, 2], |i| i as f32 * 1.5).unwrap();
+        let area = Area { x: -4.0, y: 6.0, w: 10.0, h: 5.0 };
+        let mut data = Vec::new();
+        write_ascii_grid(&mut data, &terrain, area).unwrap();
+        assert_eq!(String::from_utf8(data.clone()).unwrap(),
+            "ncols 3\nnrows 2\nxllcorner -4\nyllcorner 6\ncellsize 5\n0 1.5 3\n4.5 6 7.5\n");
+
+        let (loaded, header) = read_ascii_grid(&data[..], [0.0, 7.5]).unwrap();
+        assert_eq!(loaded.vec, terrain.vec);
+        assert_eq!(header.corner, [-4.0, 6.0]);
+        assert_eq!(header.cell_size, 5.0);
+    }
+
+    #[test]
+    fn meta_requires_fields() {
+        let full = "area_x 1\narea_y 2\narea_w 30\narea_h 40\nmin_height -3\nmax_height 9\n";
+        let meta = read_meta(full.as_bytes()).unwrap();
+        assert_eq!([meta.area.x, meta.area.y, meta.area.w, meta.area.h], [1.0, 2.0, 30.0, 40.0]);
+        assert_eq!(meta.range(), [-3.0, 9.0]);
+        assert_eq!(meta.seed, None);
+        assert_eq!(read_meta(format!("{}seed 5\n", full).as_bytes()).unwrap().seed, Some(5));
+
+        for line in full.lines() {
+            let partial = full.replace(&format!("{}\n", line), "");
+            assert!(read_meta(partial.as_bytes()).is_err(), "accepted without {}", line);
+        }
+    }
+}
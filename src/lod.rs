@@ -0,0 +1,426 @@
+use std::cmp;
+use std::collections::{HashSet};
+
+use terrain::{Terrain};
+use util::{CardinalDirection};
+use mesh::{stride_samples, EdgeSamples};
+
+/// Square-ish part of the terrain that is drawn as one mesh
+#[derive(Clone, Debug)]
+pub struct LodNode {
+    /// Grid coordinates of the lower corner
+    pub origin: [usize; 2],
+    /// Number of cells covered along x and z
+    pub cells: [usize; 2],
+    /// Distance between two vertices of the node's mesh, in cells
+    pub stride: usize,
+    pub level: usize,
+    pub min_height: f32,
+    pub max_height: f32,
+    /// Largest vertical distance between the node's mesh and the full resolution terrain,
+    /// never smaller than that of its children
+    pub error: f32,
+    pub children: Option<[usize; 4]>,
+    /// How cracks towards the neighbours are hidden when the node is drawn
+    pub seams: SeamMode,
+}
+
+/// How the renderer hides the cracks between chunks, see `mesh::Seams`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SeamMode {
+    None,
+    Skirts,
+    Stitch,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct LodParams {
+    /// Nodes whose error would cover more pixels than this on screen are split
+    pub max_pixel_error: f32,
+    pub viewport_height: f32,
+    /// Vertical field of view in radians
+    pub fovy: f32,
+}
+
+/// Quadtree over the terrain, each level halving the stride of the level above.
+/// The meshes of all nodes have about `chunk_cells` cells per edge, the leaves have full resolution.
+/// Nodes of the same level form a grid, so a coarser neighbour spans a node's whole border.
+pub struct LodTree {
+    pub nodes: Vec<LodNode>,
+    pub sample_size: [f32; 2],
+}
+
+impl LodTree {
+    /// All nodes get the same `seams`, see `set_seams`
+    pub fn build(terrain: &Terrain, sample_size: [f32; 2], chunk_cells: usize, seams: SeamMode) -> LodTree {
+        let dims = terrain.dims();
+        let cells = [dims[0] - 1, dims[1] - 1];
+        let chunk_cells = cmp::max(chunk_cells, 1);
+
+        let mut tree = LodTree {
+            nodes: Vec::new(),
+            sample_size: sample_size,
+        };
+        tree.build_node(terrain, [0, 0], cells, chunk_cells, seams, 0);
+        tree
+    }
+
+    pub fn set_seams(&mut self, id: usize, seams: SeamMode) {
+        self.nodes[id].seams = seams;
+    }
+
+    /// Returns the index of the new node
+    fn build_node(&mut self, terrain: &Terrain, origin: [usize; 2], cells: [usize; 2], chunk_cells: usize, seams: SeamMode,
+            level: usize) -> usize {
+        let largest = cmp::max(cells[0], cells[1]);
+        let stride = ((largest + chunk_cells - 1) / chunk_cells).next_power_of_two();
+
+        let (min_height, max_height) = height_range(terrain, origin, cells);
+        let id = self.nodes.len();
+        self.nodes.push(LodNode {
+            origin: origin,
+            cells: cells,
+            stride: stride,
+            level: level,
+            min_height: min_height,
+            max_height: max_height,
+            error: stride_error(terrain, origin, cells, stride),
+            children: None,
+            seams: seams,
+        });
+
+        if stride > 1 {
+            let half = [cells[0] / 2, cells[1] / 2];
+            let quadrants = [
+                ([origin[0], origin[1]], [half[0], half[1]]),
+                ([origin[0] + half[0], origin[1]], [cells[0] - half[0], half[1]]),
+                ([origin[0], origin[1] + half[1]], [half[0], cells[1] - half[1]]),
+                ([origin[0] + half[0], origin[1] + half[1]], [cells[0] - half[0], cells[1] - half[1]]),
+            ];
+
+            let mut children = [0; 4];
+            for (i, &(child_origin, child_cells)) in quadrants.iter().enumerate() {
+                children[i] = self.build_node(terrain, child_origin, child_cells, chunk_cells, seams, level + 1);
+            }
+
+            let children_error = children.iter()
+                .map(|&c| self.nodes[c].error)
+                .fold(0.0, f32::max);
+            let node = &mut self.nodes[id];
+            node.error = node.error.max(children_error);
+            node.children = Some(children);
+        }
+
+        id
+    }
+
+    /// World space bounding box of the node, `(min, max)`
+    pub fn bounds(&self, id: usize) -> ([f32; 3], [f32; 3]) {
+        let node = &self.nodes[id];
+        let ss = self.sample_size;
+        (
+            [node.origin[0] as f32 * ss[0], node.min_height, node.origin[1] as f32 * ss[1]],
+            [
+                (node.origin[0] + node.cells[0]) as f32 * ss[0],
+                node.max_height,
+                (node.origin[1] + node.cells[1]) as f32 * ss[1],
+            ],
+        )
+    }
+
+    /// Size in pixels of the node's error seen from `eye`
+    pub fn screen_error(&self, id: usize, eye: [f32; 3], params: &LodParams) -> f32 {
+        let (min, max) = self.bounds(id);
+        let mut dist_sq = 0.0;
+        for axis in 0..3 {
+            let d = (min[axis] - eye[axis]).max(0.0).max(eye[axis] - max[axis]);
+            dist_sq+= d * d;
+        }
+        let dist = dist_sq.sqrt().max(1e-3);
+
+        let pixels_per_unit = params.viewport_height / (2.0 * (params.fovy / 2.0).tan());
+        self.nodes[id].error * pixels_per_unit / dist
+    }
+
+    /// Returns the nodes to draw, together they cover the terrain exactly once.
+    /// Nodes are split while their screen space error is too large, so moving the eye splits and
+    /// merges them without any state kept between calls.
+    pub fn select(&self, eye: [f32; 3], params: &LodParams) -> Vec<usize> {
+        let mut selected = Vec::new();
+        let mut stack = vec![0];
+
+        while let Some(id) = stack.pop() {
+            match self.nodes[id].children {
+                Some(children) if self.screen_error(id, eye, params) > params.max_pixel_error => {
+                    stack.extend_from_slice(&children);
+                },
+                _ => selected.push(id),
+            }
+        }
+
+        selected
+    }
+
+    /// The selected node covering the cell with the given lower corner, found by descending from
+    /// the root. `None` outside of the terrain.
+    pub fn selected_at(&self, selected: &HashSet<usize>, cell: [usize; 2]) -> Option<usize> {
+        let contains = |id: usize| {
+            let node = &self.nodes[id];
+            (0..2).all(|axis| node.origin[axis] <= cell[axis] && cell[axis] < node.origin[axis] + node.cells[axis])
+        };
+        if !contains(0) {
+            return None;
+        }
+
+        let mut id = 0;
+        while !selected.contains(&id) {
+            id = match self.nodes[id].children {
+                Some(children) => match children.iter().find(|&&c| contains(c)) {
+                    Some(&child) => child,
+                    None => return None,
+                },
+                None => return None,
+            };
+        }
+        Some(id)
+    }
+
+    /// Coarser neighbours of the node among the selected nodes, for `Seams::Stitch`
+    pub fn coarser_neighbours(&self, selected: &HashSet<usize>, id: usize) -> [Option<EdgeSamples>; 4] {
+        let node = &self.nodes[id];
+        let mut edges = [None; 4];
+
+        for i in 0..4 {
+            let dir = CardinalDirection::from_index(i).unwrap();
+            // axis across the border
+            let axis = if dir.offset()[0] != 0 { 0 } else { 1 };
+            let along = 1 - axis;
+
+            // the first cell on the other side of the border
+            let mut cell = node.origin;
+            if dir.offset()[axis] > 0 {
+                cell[axis]+= node.cells[axis];
+            } else if cell[axis] > 0 {
+                cell[axis]-= 1;
+            } else {
+                continue;
+            }
+
+            if let Some(other_id) = self.selected_at(selected, cell) {
+                let other = &self.nodes[other_id];
+                if other.stride > node.stride {
+                    edges[i] = Some(EdgeSamples {
+                        origin: other.origin[along],
+                        cells: other.cells[along],
+                        stride: other.stride,
+                    });
+                }
+            }
+        }
+
+        edges
+    }
+}
+
+fn height_range(terrain: &Terrain, origin: [usize; 2], cells: [usize; 2]) -> (f32, f32) {
+    let mut range = (::std::f32::MAX, ::std::f32::MIN);
+    for x in origin[0]..origin[0] + cells[0] + 1 {
+        for z in origin[1]..origin[1] + cells[1] + 1 {
+            let h = *terrain.get([x, z]).unwrap();
+            range = (range.0.min(h), range.1.max(h));
+        }
+    }
+    range
+}
+
+/// Largest vertical distance between the full resolution samples and the mesh with the given
+/// stride, which is triangulated like `mesh::grid_indices`
+fn stride_error(terrain: &Terrain, origin: [usize; 2], cells: [usize; 2], stride: usize) -> f32 {
+    if stride == 1 {
+        return 0.0;
+    }
+
+    let xs = stride_samples(origin[0], cells[0], stride);
+    let zs = stride_samples(origin[1], cells[1], stride);
+    let h = |x, z| *terrain.get([x, z]).unwrap();
+    let mut error = 0.0f32;
+
+    for xw in xs.windows(2) {
+        for zw in zs.windows(2) {
+            let (x0, x1, z0, z1) = (xw[0], xw[1], zw[0], zw[1]);
+            let (h00, h10, h01, h11) = (h(x0, z0), h(x1, z0), h(x0, z1), h(x1, z1));
+
+            for x in x0..x1 + 1 {
+                for z in z0..z1 + 1 {
+                    let u = (x - x0) as f32 / (x1 - x0) as f32;
+                    let v = (z - z0) as f32 / (z1 - z0) as f32;
+                    let approx = if u + v <= 1.0 {
+                        h00 + (h10 - h00) * u + (h01 - h00) * v
+                    } else {
+                        h11 - (h11 - h01) * (1.0 - u) - (h11 - h10) * (1.0 - v)
+                    };
+                    error = error.max((h(x, z) - approx).abs());
+                }
+            }
+        }
+    }
+
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use std::collections::{HashSet};
+
+    use terrain::{Terrain};
+    use util::{Mat, FixedHeight};
+    use mesh::{self, Seams, IndexLayout, NormalMethod};
+    use super::*;
+
+    /// Rolling hills, `size` samples per edge
+    fn hills(size: usize) -> Terrain {
+        Mat {
+            vec: (0..size * size)
+                .map(|i| ((i / size) as f32 * 0.7).sin() * 3.0 + ((i % size) as f32 * 0.4).cos() * 2.0)
+                .collect(),
+            fixed_dim: FixedHeight::from_height(size).unwrap(),
+        }
+    }
+
+    fn params(max_pixel_error: f32) -> LodParams {
+        LodParams {
+            max_pixel_error: max_pixel_error,
+            viewport_height: 600.0,
+            fovy: PI / 2.0,
+        }
+    }
+
+    /// Every cell is covered by exactly one of the nodes
+    fn assert_covers(tree: &LodTree, selected: &[usize], cells: usize) {
+        let mut covered = vec![0; cells * cells];
+        for &id in selected.iter() {
+            let node = &tree.nodes[id];
+            for x in node.origin[0]..node.origin[0] + node.cells[0] {
+                for z in node.origin[1]..node.origin[1] + node.cells[1] {
+                    covered[x * cells + z]+= 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn build() {
+        let tree = LodTree::build(&hills(33), [2.0, 2.0], 8, SeamMode::Skirts);
+        let root = &tree.nodes[0];
+        assert_eq!((root.origin, root.cells, root.stride, root.level), ([0, 0], [32, 32], 4, 0));
+        assert!(root.error > 0.0);
+
+        for node in tree.nodes.iter() {
+            match node.children {
+                Some(children) => {
+                    for &c in children.iter() {
+                        let child = &tree.nodes[c];
+                        assert_eq!(child.stride * 2, node.stride);
+                        assert_eq!(child.level, node.level + 1);
+                        assert!(child.error <= node.error);
+                    }
+                },
+                None => {
+                    assert_eq!(node.stride, 1);
+                    assert_eq!(node.error, 0.0);
+                },
+            }
+        }
+        assert_eq!(tree.bounds(0).0[0], 0.0);
+        assert_eq!(tree.bounds(0).1[2], 64.0);
+    }
+
+    #[test]
+    fn select_far_and_near() {
+        let tree = LodTree::build(&hills(129), [2.0, 2.0], 8, SeamMode::Skirts);
+
+        let far = tree.select([128.0, 1e6, 128.0], &params(2.0));
+        assert_eq!(far, vec![0]);
+
+        let eye = [10.0, 5.0, 10.0];
+        let near = tree.select(eye, &params(2.0));
+        assert_covers(&tree, &near, 128);
+        let under_eye = near.iter()
+            .find(|&&id| {
+                let (min, max) = tree.bounds(id);
+                min[0] <= eye[0] && eye[0] < max[0] && min[2] <= eye[2] && eye[2] < max[2]
+            })
+            .unwrap();
+        assert_eq!(tree.nodes[*under_eye].stride, 1);
+        assert!(near.iter().any(|&id| tree.nodes[id].stride > 1));
+    }
+
+    #[test]
+    fn select_at_threshold() {
+        let tree = LodTree::build(&hills(33), [2.0, 2.0], 8, SeamMode::Skirts);
+        let eye = [-40.0, 20.0, 30.0];
+        let root_error = tree.screen_error(0, eye, &params(1.0));
+
+        assert_eq!(tree.select(eye, &params(root_error)), vec![0]);
+        let split = tree.select(eye, &params(root_error * 0.999));
+        assert!(split.len() >= 4);
+        assert_covers(&tree, &split, 32);
+    }
+
+    #[test]
+    fn stitched_border_matches_coarse_edge() {
+        let terrain = hills(33);
+        let tree = LodTree::build(&terrain, [2.0, 2.0], 8, SeamMode::Stitch);
+        let root = tree.nodes[0].children.unwrap();
+        let split = tree.nodes[root[0]].children.unwrap();
+        // the lower left quadrant at full resolution, the other three at stride 2
+        let selected = split.iter().chain(root[1..].iter()).cloned().collect::<HashSet<_>>();
+
+        let fine = split[1];
+        let coarse = root[1];
+        assert_eq!(tree.selected_at(&selected, [16, 0]), Some(coarse));
+        assert_eq!(tree.selected_at(&selected, [9, 3]), Some(fine));
+        assert_eq!(tree.selected_at(&selected, [32, 0]), None);
+
+        let edges = tree.coarser_neighbours(&selected, fine);
+        assert_eq!(edges.iter().filter(|e| e.is_some()).count(), 1);
+        // nothing coarser next to the coarse chunks
+        assert_eq!(tree.coarser_neighbours(&selected, coarse), [None; 4]);
+
+        let mesh_of = |id: usize, seams| {
+            let node = &tree.nodes[id];
+            mesh::chunk_mesh(&terrain, tree.sample_size, 8, node.origin, node.cells, node.stride, seams,
+                IndexLayout::Triangles, NormalMethod::CentralDifference)
+        };
+        let fine_mesh = mesh_of(fine, Seams::Stitch(edges));
+        let coarse_mesh = mesh_of(coarse, Seams::None);
+
+        // the shared border at x = 16 samples, sorted along z
+        let border = |verts: &[mesh::FaceVertex]| {
+            let mut border = verts.iter()
+                .map(|v| v.v_pos)
+                .filter(|p| p[0] == 32.0)
+                .collect::<Vec<_>>();
+            border.sort_by(|a, b| a[2].partial_cmp(&b[2]).unwrap());
+            border
+        };
+        let fine_border = border(&fine_mesh.verts);
+        let coarse_border = border(&coarse_mesh.verts);
+        assert_eq!(fine_border.len(), 9);
+        assert_eq!(coarse_border.len(), 9);
+
+        for p in fine_border.iter() {
+            let w = coarse_border.windows(2).find(|w| w[0][2] <= p[2] && p[2] <= w[1][2]).unwrap();
+            let t = (p[2] - w[0][2]) / (w[1][2] - w[0][2]);
+            let h = w[0][1] + (w[1][1] - w[0][1]) * t;
+            assert!((p[1] - h).abs() < 1e-5, "{:?} is off the edge at {}", p, h);
+        }
+        // the coarse chunk is twice as long, the fine one covers its first half
+        for p in coarse_border[..5].iter() {
+            assert!(fine_border.contains(p));
+        }
+    }
+}
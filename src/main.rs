@@ -19,15 +19,36 @@ use glium::glutin::{GlProfile, Event, ElementState, VirtualKeyCode as KeyCode};
 
 use util::*;
 use renderer::{Renderer};
+use config::{Config};
 
 mod util;
 mod terrain;
+mod height_source;
+mod erosion;
+mod heightmap;
+mod brush;
+mod sampler;
+mod raycast;
 mod renderer;
 mod mesh;
+mod lod;
+mod frustum;
+mod streaming;
+mod decimate;
+mod export;
+mod splat;
+mod shadow;
+mod water;
+mod sky;
+#[cfg(test)]
+mod raster;
+mod config;
 
 fn main() {
     use glium::DisplayBuild;
 
+    let config = Config::load("scene.toml").unwrap_or_else(|err| panic!("Error loading scene.toml: {}", err));
+
     let monitor = glium::glutin::get_primary_monitor();
     let (w, h) = monitor.get_dimensions();
     let display = glium::glutin::WindowBuilder::new()
@@ -42,14 +63,18 @@ fn main() {
     let mut cursor_pos = (0, 0);
     let mut cursor_jump = true;
     let mut pressed_keys = HashSet::new();
-    let mut cam = FirstPersonCam::new(Point3f::new(60.0, 30.0, 60.0), Vector3f::new(0.0, -0.3, 0.0));
+    let cam_pos = config.camera.position;
+    let cam_dir = config.camera.direction;
+    let mut cam = FirstPersonCam::new(Point3f::new(cam_pos[0], cam_pos[1], cam_pos[2]), Vector3f::new(cam_dir[0], cam_dir[1], cam_dir[2]));
 
-    let fovy: Rad<f32> = cg::deg(90.0).into();
+    let fovy: Rad<f32> = cg::deg(config.camera.fov).into();
     let aspect = w as f32 / h as f32;
     let fovx = fovy * aspect;
-    let proj = cg::perspective(fovy, aspect, 0.1, 100.0);
+    let proj = cg::perspective(fovy, aspect, config.camera.near, config.camera.far);
 
-    let mut renderer = Renderer::new(&display).expect("Error creating Renderer.");
+    let mut renderer = Renderer::new(&display, &config).expect("Error creating Renderer.");
+    let mut last_stats = None;
+    let start = clock.time();
 
     'main: loop {
         let delta = clock.delta() as f32;
@@ -75,6 +100,13 @@ fn main() {
                 },
                 Event::KeyboardInput(state, _, Some(key_code)) => {
                     if state == ElementState::Pressed {
+                        if !pressed_keys.contains(&key_code) {
+                            match key_code {
+                                KeyCode::F => cam.toggle_mode(),
+                                KeyCode::T => renderer.toggle_triplanar(),
+                                _ => (),
+                            }
+                        }
                         pressed_keys.insert(key_code);
                     } else {
                         pressed_keys.remove(&key_code);
@@ -92,17 +124,26 @@ fn main() {
         cam.set_movement(movement_from_pressed_keys(&pressed_keys));
 
         // update cam pos
-        cam.update_pos(delta);
+        cam.update(delta, &renderer);
+        renderer.update(&display, [cam.pos.x, cam.pos.y, cam.pos.z]).expect("Error uploading terrain tile");
 
         let mut target = display.draw();
 
         {
             let view = Matrix4f::look_at(cam.pos, cam.pos + cam.dir, Vector3f::new(0.0, 1.0, 0.0));
-            renderer.render(&mut target, (proj * view).as_ref(), clock.time() as f32);
+            renderer.render(&display, &mut target, &(proj * view), [cam.pos.x, cam.pos.y, cam.pos.z], (clock.time() - start) as f32);
         }
 
         target.finish().expect("Error swapping");
 
+        let stats = renderer.stats();
+        if last_stats != Some(stats) {
+            let title = format!("tetras - {} chunks, {} culled, {} loading",
+                stats.chunks_selected, stats.chunks_culled, stats.tiles_in_flight);
+            display.get_window().unwrap().set_title(&title);
+            last_stats = Some(stats);
+        }
+
         thread::sleep(Duration::from_millis(1));
     }
 }
@@ -1,4 +1,6 @@
 
+use std::cmp;
+
 use cg::{EuclideanVector, Vector3};
 
 use glium::backend::{Facade};
@@ -108,166 +110,324 @@ pub fn show_normals(mesh: &Mesh<FaceVertex>, l: f32, colors: [[f32; 3]; 2]) -> M
     }
 }
 
-pub fn terrain_mesh(terrain: &Terrain, sample_size: [f32; 2], samples_per_tex: usize)
-        -> Mesh<FaceVertex> {
-
-    let width_z = terrain.fixed_dim.height();
-    let width_x = terrain.vec.len() / width_z;
-
-    let normals = terrain.vec.iter().zip(terrain.fixed_dim.coords_iter())
-        .map(|(&height, coords)| {
-            use util::CardinalDirection as CD;
-
-            let coords = [coords[0] as i32, coords[1] as i32];
-
-            let rel_coords = {
-                let mut rel_coords = [None; 4];
-                rel_coords[CD::Up.index()] = Some([coords[0], coords[1] + 1]);
-
-                rel_coords[CD::Down.index()] = Some([coords[0], coords[1] - 1]);
-                rel_coords[CD::Left.index()] = Some([coords[0] - 1, coords[1]]);
-                rel_coords[CD::Right.index()] = Some([coords[0] + 1, coords[1]]);
-                [
-                    rel_coords[0].unwrap(),
-                    rel_coords[1].unwrap(),
-                    rel_coords[2].unwrap(),
-                    rel_coords[3].unwrap(),
-                ]
-            };
-
-            let get_h = |dir: CD| {
-                let alt = || {
-                    let inv_index = dir.inv().index();
-                    let inv_dir_coords = rel_coords[inv_index];
-                    let inv_dir_coords = [inv_dir_coords[0] as usize, inv_dir_coords[1] as usize];
-                    let inv_height = *terrain.get(inv_dir_coords).unwrap_or(&height); // dunno rly
-                    2.0 * height - inv_height
-                };
-
-                let alt = || height;
-
-                let dir_coords = rel_coords[dir.index()];
-                if dir_coords[0] >= 0 && dir_coords[1] >= 0 && dir_coords[0] < width_x as i32 && dir_coords[1] < width_z as i32 {
-                    let dir_coords = [dir_coords[0] as usize, dir_coords[1] as usize];
-                    terrain.get(dir_coords)
-                        .map(|x| *x)
-                        .unwrap_or(alt())
-                } else {
-                    alt()
+/// Index that ends a triangle strip, with primitive restart the next index starts a new one
+pub const PRIMITIVE_RESTART: u32 = ::std::u32::MAX;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IndexLayout {
+    Triangles,
+    /// One strip per column of cells, separated by `PRIMITIVE_RESTART`
+    Strips,
+}
+
+/// Mesh of the whole terrain, each sample is a single vertex shared by the cells around it, with
+/// a per-vertex normal. The texture has to be sampled with repeat.
+pub fn indexed_terrain_mesh(terrain: &Terrain, sample_size: [f32; 2], samples_per_tex: usize,
+        layout: IndexLayout, normal_method: NormalMethod) -> Mesh<FaceVertex> {
+
+    let dims = terrain.dims();
+    chunk_mesh(terrain, sample_size, samples_per_tex, [0, 0], [dims[0] - 1, dims[1] - 1], 1, Seams::None, layout,
+        normal_method)
+}
+
+/// Indices of the cells of a grid of `xs` by `zs` vertices stored x-major. Each cell is split
+/// along the diagonal from its `[1, 0]` to its `[0, 1]` corner. All triangles wind clockwise seen
+/// from above, with both layouts.
+pub fn grid_indices(xs: usize, zs: usize, layout: IndexLayout) -> Vec<u32> {
+    match layout {
+        IndexLayout::Triangles => {
+            let row = zs as u32;
+            let mut inds = Vec::with_capacity((xs - 1) * (zs - 1) * 6);
+            for i in 0..xs as u32 - 1 {
+                for j in 0..row - 1 {
+                    let v00 = i * row + j;
+                    let v10 = v00 + row;
+                    let v01 = v00 + 1;
+                    let v11 = v10 + 1;
+                    inds.extend_from_slice(&[v00, v10, v01, v01, v10, v11]);
                 }
-            };
+            }
+            inds
+        },
+        IndexLayout::Strips => strip_indices(xs, zs),
+    }
+}
 
-            terrain_normal(get_h(CD::Right), get_h(CD::Left), get_h(CD::Up), get_h(CD::Down))
-        })
-        .collect::<Vec<[f32; 3]>>();
+/// Triangle strips over a grid of `xs` by `zs` vertices stored x-major, split like `grid_indices`
+pub fn strip_indices(xs: usize, zs: usize) -> Vec<u32> {
+    let mut inds = Vec::with_capacity((xs - 1) * (zs * 2 + 1));
+    for i in 0..xs - 1 {
+        if i > 0 {
+            inds.push(PRIMITIVE_RESTART);
+        }
+        for j in 0..zs {
+            let v0 = (i * zs + j) as u32;
+            inds.push(v0);
+            inds.push(v0 + zs as u32);
+        }
+    }
+    inds
+}
 
-    let verts = terrain.vec.iter().enumerate()
-        .map(|(i, &height)| (terrain.fixed_dim.to_coords(i), height))
-        .filter(|&(coords, _)| {
-            // heights on the edge don't produce faces
-            coords[0] != width_x - 1 && coords[1] != width_z - 1
-        })
-        // each coords creates the faces between itself and the right and up coords
-        .flat_map(|(coords, height)| {
-            let x = coords[0] as f32 * sample_size[0];
-            let z = coords[1] as f32 * sample_size[1];
-
-            let up_coords = [coords[0], coords[1] + 1];
-            let right_coords = [coords[0] + 1, coords[1]];
-            let oppo_coords = [coords[0] + 1, coords[1] + 1];
-
-            let up_x = up_coords[0] as f32 * sample_size[0];
-            let up_z = up_coords[1] as f32 * sample_size[1];
-            let right_x = right_coords[0] as f32 * sample_size[0];
-            let right_z = right_coords[1] as f32 * sample_size[1];
-            let oppo_x = oppo_coords[0] as f32 * sample_size[0];
-            let oppo_z = oppo_coords[1] as f32 * sample_size[1];
-
-            let to_index = |c| terrain.fixed_dim.to_index(c).unwrap();
-            let oppo_height = terrain.vec[to_index(oppo_coords)];
-            let up_height = terrain.vec[to_index(up_coords)];
-            let right_height = terrain.vec[to_index(right_coords)];
-            // let down_height = if coords[1] == 0 {
-            //     height + (height - up_height)
-            // } else {
-            //     terrain.vec[to_index([coords[0], coords[1] - 1])]
-            // };
-            // let left_height = if coords[0] == 0 {
-            //     height + (height - right_height)
-            // } else {
-            //     terrain.vec[to_index([coords[0] - 1, coords[1]])]
-            // };
-
-            let normal = normals[terrain.fixed_dim.to_index(coords).unwrap()];
-            let _right_normal = normals[terrain.fixed_dim.to_index(right_coords).unwrap()];
-            let _up_normal = normals[terrain.fixed_dim.to_index(up_coords).unwrap()];
-            let oppo_normal = normals[terrain.fixed_dim.to_index(oppo_coords).unwrap()];
-
-            let part_count = samples_per_tex;
-            let x_part = (coords[0] % part_count) as f32;
-            let y_part = (coords[1] % part_count) as f32;
-            let part_count = part_count as f32;
-            let tex_coords00 = [
-                x_part * (1.0 / part_count),
-                y_part * (1.0 / part_count),
-            ];
-            let tex_coords10 = [
-                tex_coords00[0] + (1.0 / part_count),
-                tex_coords00[1]
-            ];
-            let tex_coords01 = [
-                tex_coords00[0],
-                tex_coords00[1] + (1.0 / part_count)
-            ];
-            let tex_coords11 = [
-                tex_coords00[0] + (1.0 / part_count),
-                tex_coords00[1] + (1.0 / part_count)
-            ];
-
-
-
-            let vertex = FaceVertex {
-                v_pos: [x, height, z],
-                v_tex_pos: tex_coords00,
-                v_normal: normal,
-            };
-            let oppo_vertex = FaceVertex {
-                v_pos: [oppo_x, oppo_height, oppo_z],
-                v_tex_pos: tex_coords11,
-                v_normal: oppo_normal,
-            };
-            let right_vertex1 = FaceVertex {
-                v_pos: [right_x, right_height, right_z],
-                v_tex_pos: tex_coords10,
-                v_normal: normal,
-            };
-            let right_vertex2 = FaceVertex {
-                v_pos: [right_x, right_height, right_z],
-                v_tex_pos: tex_coords10,
-                v_normal: oppo_normal,
-            };
-            let up_vertex1 = FaceVertex {
-                v_pos: [up_x, up_height, up_z],
-                v_tex_pos: tex_coords01,
-                v_normal: normal,
-            };
-            let up_vertex2 = FaceVertex {
-                v_pos: [up_x, up_height, up_z],
-                v_tex_pos: tex_coords01,
-                v_normal: oppo_normal,
-            };
+/// Grid coordinates of the vertices along one axis of a chunk: every `stride`th sample from
+/// `origin` on, always ending with the last sample at `origin + cells`
+pub fn stride_samples(origin: usize, cells: usize, stride: usize) -> Vec<usize> {
+    let mut samples = (0..(cells + stride - 1) / stride)
+        .map(|i| origin + i * stride)
+        .collect::<Vec<_>>();
+    samples.push(origin + cells);
+    samples
+}
 
-            vec![
-                vertex, right_vertex1, up_vertex1,
-                oppo_vertex, right_vertex2, up_vertex2,
-            ]
-        })
-        .collect();
+/// How vertex normals are computed from the heights. All of them use the world scale of the
+/// cells and only the samples that exist at the border of the terrain.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NormalMethod {
+    /// Gradient from the neighbours on both sides along each axis, one-sided at the border
+    CentralDifference,
+    /// Central differences of the neighbouring rows and columns weighted 1, 2, 1, smoother on rough terrain
+    Sobel,
+    /// Sum of the normals of the triangles around the vertex, weighted by their area
+    AreaWeighted,
+}
+
+/// Unit normal at the sample, using the samples `step` samples away like a mesh with that stride
+pub fn vertex_normal(terrain: &Terrain, sample_size: [f32; 2], coords: [usize; 2], step: usize,
+        method: NormalMethod) -> [f32; 3] {
+
+    let v = match method {
+        NormalMethod::CentralDifference => {
+            let dx = gradient(terrain, sample_size, coords, step, 0);
+            let dz = gradient(terrain, sample_size, coords, step, 1);
+            Vector3::new(-dx, 1.0, -dz)
+        },
+        NormalMethod::Sobel => {
+            let dx = smoothed_gradient(terrain, sample_size, coords, step, 0);
+            let dz = smoothed_gradient(terrain, sample_size, coords, step, 1);
+            Vector3::new(-dx, 1.0, -dz)
+        },
+        NormalMethod::AreaWeighted => area_weighted_normal(terrain, sample_size, coords, step),
+    };
+
+    let v = v.normalize();
+    [v.x, v.y, v.z]
+}
+
+/// Last sample index along x and z
+fn last_coords(terrain: &Terrain) -> [usize; 2] {
+    let dims = terrain.dims();
+    [dims[0] - 1, dims[1] - 1]
+}
+
+/// Slope of the heights along `axis` in world units, one-sided where a neighbour is missing
+fn gradient(terrain: &Terrain, sample_size: [f32; 2], coords: [usize; 2], step: usize, axis: usize) -> f32 {
+    let last = last_coords(terrain)[axis];
+    let mut lo = coords;
+    let mut hi = coords;
+    lo[axis] = coords[axis].saturating_sub(step);
+    hi[axis] = cmp::min(coords[axis] + step, last);
+
+    if hi[axis] > lo[axis] {
+        let rise = *terrain.get(hi).unwrap() - *terrain.get(lo).unwrap();
+        rise / ((hi[axis] - lo[axis]) as f32 * sample_size[axis])
+    } else {
+        0.0
+    }
+}
+
+/// Sobel: the gradients of the neighbouring lines across `axis` weighted 1, 2, 1.
+/// Lines outside of the terrain are left out.
+fn smoothed_gradient(terrain: &Terrain, sample_size: [f32; 2], coords: [usize; 2], step: usize, axis: usize) -> f32 {
+    let across = 1 - axis;
+    let last = last_coords(terrain)[across];
+
+    let mut sum = 2.0 * gradient(terrain, sample_size, coords, step, axis);
+    let mut weight = 2.0;
+    if coords[across] >= step {
+        let mut c = coords;
+        c[across]-= step;
+        sum+= gradient(terrain, sample_size, c, step, axis);
+        weight+= 1.0;
+    }
+    if coords[across] + step <= last {
+        let mut c = coords;
+        c[across]+= step;
+        sum+= gradient(terrain, sample_size, c, step, axis);
+        weight+= 1.0;
+    }
+    sum / weight
+}
+
+/// Triangulated like `grid_indices`, with cells `step` samples wide that are cut at the border
+fn area_weighted_normal(terrain: &Terrain, sample_size: [f32; 2], coords: [usize; 2], step: usize) -> Vector3<f32> {
+    let last = last_coords(terrain);
+    let pos = |c: [usize; 2]| {
+        Vector3::new(c[0] as f32 * sample_size[0], *terrain.get(c).unwrap(), c[1] as f32 * sample_size[1])
+    };
+
+    // lower and upper corners of the cells around the sample along each axis
+    let mut spans = [Vec::new(), Vec::new()];
+    for axis in 0..2 {
+        if coords[axis] > 0 {
+            spans[axis].push((coords[axis].saturating_sub(step), coords[axis]));
+        }
+        if coords[axis] < last[axis] {
+            spans[axis].push((coords[axis], cmp::min(coords[axis] + step, last[axis])));
+        }
+    }
+
+    let mut sum = Vector3::new(0.0, 0.0, 0.0);
+    for &(x0, x1) in spans[0].iter() {
+        for &(z0, z1) in spans[1].iter() {
+            let (v00, v10, v01, v11) = ([x0, z0], [x1, z0], [x0, z1], [x1, z1]);
+            for tri in [[v00, v10, v01], [v11, v10, v01]].iter() {
+                if tri.contains(&coords) {
+                    // twice the area, pointing up
+                    let (a, b, c) = (pos(tri[0]), pos(tri[1]), pos(tri[2]));
+                    let n = (b - a).cross(c - a);
+                    sum = sum + if n.y < 0.0 { -n } else { n };
+                }
+            }
+        }
+    }
+
+    if sum.y > 0.0 {
+        sum
+    } else {
+        Vector3::new(0.0, 1.0, 0.0)
+    }
+}
+
+/// Vertices of a neighbouring chunk along the border it shares with another chunk
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EdgeSamples {
+    /// Grid coordinate of the neighbour's first vertex along the border
+    pub origin: usize,
+    pub cells: usize,
+    pub stride: usize,
+}
+
+/// How the cracks between chunks of different resolution are hidden
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Seams {
+    None,
+    /// Walls of the given depth hanging down from the chunk's border
+    Skirts(f32),
+    /// Border vertices are moved onto the edges of coarser neighbours, so the borders coincide.
+    /// Indexed by `CardinalDirection::index`, up is towards positive z and right towards positive x.
+    Stitch([Option<EdgeSamples>; 4]),
+}
+
+/// Indexed mesh of the part of the terrain starting at `origin` and spanning `cells`, using only
+/// every `stride`th sample. The sample `[x, z]` lies at `[x * sample_size[0], z * sample_size[1]]`
+/// for all chunks, so they line up with each other; the texture has to be sampled with repeat.
+pub fn chunk_mesh(terrain: &Terrain, sample_size: [f32; 2], samples_per_tex: usize,
+        origin: [usize; 2], cells: [usize; 2], stride: usize, seams: Seams, layout: IndexLayout,
+        normal_method: NormalMethod) -> Mesh<FaceVertex> {
+
+    let xs = stride_samples(origin[0], cells[0], stride);
+    let zs = stride_samples(origin[1], cells[1], stride);
+
+    let mut verts = Vec::with_capacity(xs.len() * zs.len());
+    for &x in xs.iter() {
+        for &z in zs.iter() {
+            verts.push(FaceVertex {
+                v_pos: [x as f32 * sample_size[0], *terrain.get([x, z]).unwrap(), z as f32 * sample_size[1]],
+                v_tex_pos: [x as f32 / samples_per_tex as f32, z as f32 / samples_per_tex as f32],
+                v_normal: vertex_normal(terrain, sample_size, [x, z], stride, normal_method),
+            });
+        }
+    }
+
+    let mut inds = grid_indices(xs.len(), zs.len(), layout);
+
+    match seams {
+        Seams::None => {},
+        Seams::Skirts(depth) => add_skirts(&mut verts, &mut inds, xs.len(), zs.len(), depth, layout),
+        Seams::Stitch(edges) => {
+            for (i, edge) in edges.iter().enumerate() {
+                if let Some(edge) = *edge {
+                    let dir = CardinalDirection::from_index(i).unwrap();
+                    stitch_edge(terrain, &mut verts, &xs, &zs, dir, edge);
+                }
+            }
+        },
+    }
 
     Mesh {
         verts: verts,
-        inds: None,
-        primitive_type: PrimitiveType::TrianglesList,
+        inds: Some(inds),
+        primitive_type: match layout {
+            IndexLayout::Triangles => PrimitiveType::TrianglesList,
+            IndexLayout::Strips => PrimitiveType::TriangleStrip,
+        },
+    }
+}
+
+/// Indices into the vertices of a `xs` by `zs` grid along the border in the given direction
+fn border_verts(dir: CardinalDirection, xs: usize, zs: usize) -> Vec<u32> {
+    match dir {
+        CardinalDirection::Left => (0..zs).map(|j| j as u32).collect(),
+        CardinalDirection::Right => (0..zs).map(|j| ((xs - 1) * zs + j) as u32).collect(),
+        CardinalDirection::Down => (0..xs).map(|i| (i * zs) as u32).collect(),
+        CardinalDirection::Up => (0..xs).map(|i| (i * zs + zs - 1) as u32).collect(),
+    }
+}
+
+fn add_skirts(verts: &mut Vec<FaceVertex>, inds: &mut Vec<u32>, xs: usize, zs: usize, depth: f32, layout: IndexLayout) {
+    for i in 0..4 {
+        let border = border_verts(CardinalDirection::from_index(i).unwrap(), xs, zs);
+        let first_skirt = verts.len() as u32;
+
+        for &v in border.iter() {
+            let mut skirt = verts[v as usize];
+            skirt.v_pos[1]-= depth;
+            verts.push(skirt);
+        }
+
+        match layout {
+            IndexLayout::Triangles => {
+                for k in 0..border.len() as u32 - 1 {
+                    let (a, b) = (border[k as usize], border[k as usize + 1]);
+                    let (sa, sb) = (first_skirt + k, first_skirt + k + 1);
+                    inds.extend_from_slice(&[a, sa, b, b, sa, sb]);
+                }
+            },
+            IndexLayout::Strips => {
+                inds.push(PRIMITIVE_RESTART);
+                for (k, &v) in border.iter().enumerate() {
+                    inds.push(v);
+                    inds.push(first_skirt + k as u32);
+                }
+            },
+        }
+    }
+}
+
+/// Moves the border vertices onto the straight edges between the neighbour's vertices
+fn stitch_edge(terrain: &Terrain, verts: &mut Vec<FaceVertex>, xs: &[usize], zs: &[usize],
+        dir: CardinalDirection, edge: EdgeSamples) {
+
+    // grid coordinates along the border, and the fixed coordinate across it
+    let (along, fixed) = match dir {
+        CardinalDirection::Left => (zs, xs[0]),
+        CardinalDirection::Right => (zs, xs[xs.len() - 1]),
+        CardinalDirection::Down => (xs, zs[0]),
+        CardinalDirection::Up => (xs, zs[zs.len() - 1]),
+    };
+    let h = |c: usize| match dir {
+        CardinalDirection::Left | CardinalDirection::Right => *terrain.get([fixed, c]).unwrap(),
+        CardinalDirection::Down | CardinalDirection::Up => *terrain.get([c, fixed]).unwrap(),
+    };
+
+    let coarse = stride_samples(edge.origin, edge.cells, edge.stride);
+    let border = border_verts(dir, xs.len(), zs.len());
+
+    for (&v, &c) in border.iter().zip(along.iter()) {
+        if let Some(w) = coarse.windows(2).find(|w| w[0] <= c && c <= w[1]) {
+            let t = (c - w[0]) as f32 / (w[1] - w[0]) as f32;
+            verts[v as usize].v_pos[1] = h(w[0]) + (h(w[1]) - h(w[0])) * t;
+        }
     }
 }
 
@@ -279,12 +439,6 @@ pub fn tri_normal(verts: [[f32; 3]; 3]) -> [f32; 3] {
     [r.x, r.y, r.z]
 }
 
-/// taking four heights from up, down, left, right and returns the normal vector
-pub fn terrain_normal(right: f32, left: f32, up: f32, down: f32) -> [f32; 3] {
-    let v = Vector3::new(left - right, 2.0, down - up).normalize();
-    [v.x, v.y, v.z]
-}
-
 impl Into<UploadedIndices> for NoIndices {
     fn into(self) -> UploadedIndices {
         UploadedIndices::NoIndices(self)
@@ -317,3 +471,115 @@ impl<'a> Into<IndicesSource<'a>> for &'a UploadedIndices {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use terrain::{Terrain};
+    use util::{Mat, FixedHeight};
+    use super::*;
+
+    /// Height `x * 10 + z`, so each sample can be recognized
+    fn numbered(size: usize) -> Terrain {
+        Mat {
+            vec: (0..size * size).map(|i| ((i / size) * 10 + i % size) as f32).collect(),
+            fixed_dim: FixedHeight::from_height(size).unwrap(),
+        }
+    }
+
+    #[test]
+    fn stride_samples_end_on_the_border() {
+        assert_eq!(stride_samples(0, 16, 4), vec![0, 4, 8, 12, 16]);
+        assert_eq!(stride_samples(8, 8, 1), (8..17).collect::<Vec<_>>());
+        assert_eq!(stride_samples(16, 16, 16), vec![16, 32]);
+        assert_eq!(stride_samples(5, 10, 4), vec![5, 9, 13, 15]);
+    }
+
+    #[test]
+    fn chunk_mesh_grid() {
+        let terrain = numbered(33);
+        let mesh = chunk_mesh(&terrain, [2.0, 3.0], 8, [16, 8], [16, 16], 4, Seams::None,
+            IndexLayout::Triangles, NormalMethod::CentralDifference);
+
+        assert_eq!(mesh.verts.len(), 25);
+        assert_eq!(mesh.inds.as_ref().unwrap().len(), 4 * 4 * 6);
+        assert_eq!(mesh.primitive_type, PrimitiveType::TrianglesList);
+
+        // x-major, corners at the chunk's border samples
+        assert_eq!(mesh.verts[0].v_pos, [32.0, 168.0, 24.0]);
+        assert_eq!(mesh.verts[4].v_pos, [32.0, 184.0, 72.0]);
+        assert_eq!(mesh.verts[20].v_pos, [64.0, 328.0, 24.0]);
+        assert_eq!(mesh.verts[24].v_pos, [64.0, 344.0, 72.0]);
+        assert_eq!(mesh.verts[7].v_pos, [40.0, 216.0, 48.0]);
+        assert_eq!(mesh.verts[0].v_tex_pos, [2.0, 1.0]);
+    }
+
+    #[test]
+    fn chunk_mesh_skirts() {
+        let terrain = numbered(9);
+        let mesh = chunk_mesh(&terrain, [1.0, 1.0], 8, [0, 0], [8, 8], 2, Seams::Skirts(1.5),
+            IndexLayout::Triangles, NormalMethod::CentralDifference);
+        // 5 by 5 grid and 5 skirt vertices per border
+        assert_eq!(mesh.verts.len(), 25 + 4 * 5);
+        assert_eq!(mesh.inds.as_ref().unwrap().len(), (4 * 4 + 4 * 4) * 6);
+        for skirt in mesh.verts[25..].iter() {
+            let p = skirt.v_pos;
+            assert!(mesh.verts[..25].iter().any(|v| v.v_pos == [p[0], p[1] + 1.5, p[2]]));
+        }
+    }
+
+    /// Triangles of an indexed mesh with their corners sorted, ignoring degenerate ones
+    fn triangles(inds: &[u32], layout: IndexLayout) -> Vec<[u32; 3]> {
+        let mut tris = Vec::new();
+        match layout {
+            IndexLayout::Triangles => {
+                for t in inds.chunks(3) {
+                    tris.push([t[0], t[1], t[2]]);
+                }
+            },
+            IndexLayout::Strips => {
+                for strip in inds.split(|&i| i == PRIMITIVE_RESTART) {
+                    for (k, t) in strip.windows(3).enumerate() {
+                        // every second triangle of a strip is flipped
+                        if k % 2 == 0 {
+                            tris.push([t[0], t[1], t[2]]);
+                        } else {
+                            tris.push([t[1], t[0], t[2]]);
+                        }
+                    }
+                }
+            },
+        }
+        // starting at the lowest index keeps the winding
+        for t in tris.iter_mut() {
+            while t[0] > t[1] || t[0] > t[2] {
+                *t = [t[1], t[2], t[0]];
+            }
+        }
+        tris.retain(|t| t[0] != t[1] && t[1] != t[2] && t[0] != t[2]);
+        tris.sort();
+        tris
+    }
+
+    #[test]
+    fn strips_cover_the_same_triangles() {
+        let terrain = numbered(9);
+        for &seams in [Seams::None, Seams::Skirts(1.0)].iter() {
+            let mesh = |layout| chunk_mesh(&terrain, [1.0, 1.0], 8, [0, 0], [8, 6], 2, seams, layout,
+                NormalMethod::CentralDifference);
+            let list = mesh(IndexLayout::Triangles);
+            let strips = mesh(IndexLayout::Strips);
+            assert_eq!(strips.primitive_type, PrimitiveType::TriangleStrip);
+            assert_eq!(list.verts.len(), strips.verts.len());
+            assert_eq!(triangles(list.inds.as_ref().unwrap(), IndexLayout::Triangles),
+                triangles(strips.inds.as_ref().unwrap(), IndexLayout::Strips));
+        }
+    }
+
+    #[test]
+    fn indexed_terrain_mesh_shares_vertices() {
+        let terrain = numbered(5);
+        let mesh = indexed_terrain_mesh(&terrain, [1.0, 1.0], 4, IndexLayout::Triangles, NormalMethod::Sobel);
+        assert_eq!(mesh.verts.len(), 25);
+        assert_eq!(mesh.inds.unwrap().len(), 4 * 4 * 6);
+    }
+}
@@ -0,0 +1,556 @@
+extern crate image;
+
+use std::io::{self, BufReader, BufWriter};
+use std::fs::File;
+use std::path::Path;
+
+use self::image::{ImageDecoder, ImageError, ColorType, DecodingResult};
+use self::image::png::{PNGDecoder, PNGEncoder};
+
+use cg::{Matrix4, Vector4};
+
+use mesh::{Mesh, FaceVertex};
+use export::{self, ExportError};
+
+#[derive(Debug)]
+pub enum RasterError {
+    IoError(io::Error),
+    ImageError(ImageError),
+    /// Only 8 bit RGB and RGBA images can be loaded
+    UnsupportedColorType(ColorType),
+    /// The mesh can't be split into triangles
+    Mesh(ExportError),
+}
+
+/// 8 bit RGBA pixels, rows from top to bottom like in image files
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// Difference between two images of the same size
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ImageDiff {
+    /// Pixels where a channel differs by more than the tolerance
+    pub mismatched: usize,
+    /// Largest difference of a channel
+    pub max_difference: u8,
+}
+
+/// Light of the face shader, `texture * (ambient + color * max(dot(normal, dir), 0))`
+#[derive(Copy, Clone, Debug)]
+pub struct Light {
+    /// Unit vector towards the light
+    pub dir: [f32; 3],
+    pub color: [f32; 3],
+    pub ambient: [f32; 3],
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32, color: [u8; 4]) -> Image {
+        let count = width as usize * height as usize;
+        Image {
+            width: width,
+            height: height,
+            pixels: color.iter().cloned().cycle().take(count * 4).collect(),
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = (y as usize * self.width as usize + x as usize) * 4;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]]
+    }
+
+    fn set(&mut self, x: u32, y: u32, color: [u8; 4]) {
+        let i = (y as usize * self.width as usize + x as usize) * 4;
+        self.pixels[i..i + 4].copy_from_slice(&color);
+    }
+
+    /// Bilinear lookup in `[0, 1]`, like a repeating GL texture. `v = 0` is the bottom row, as the
+    /// renderer uploads the images flipped.
+    pub fn sample(&self, uv: [f32; 2]) -> [f32; 4] {
+        let x = uv[0] * self.width as f32 - 0.5;
+        let y = (1.0 - uv[1]) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let texel = |dx: i64, dy: i64| {
+            let tx = wrap(x0 as i64 + dx, self.width);
+            let ty = wrap(y0 as i64 + dy, self.height);
+            self.get(tx, ty)
+        };
+        let (a, b, c, d) = (texel(0, 0), texel(1, 0), texel(0, 1), texel(1, 1));
+
+        let mut color = [0.0; 4];
+        for i in 0..4 {
+            let top = a[i] as f32 * (1.0 - fx) + b[i] as f32 * fx;
+            let bottom = c[i] as f32 * (1.0 - fx) + d[i] as f32 * fx;
+            color[i] = (top * (1.0 - fy) + bottom * fy) / 255.0;
+        }
+        color
+    }
+
+    /// Compares channel by channel, images of different sizes mismatch everywhere
+    pub fn compare(&self, other: &Image, tolerance: u8) -> ImageDiff {
+        if self.width != other.width || self.height != other.height {
+            return ImageDiff {
+                mismatched: ::std::cmp::max(self.pixels.len(), other.pixels.len()) / 4,
+                max_difference: 255,
+            };
+        }
+
+        let mut diff = ImageDiff {
+            mismatched: 0,
+            max_difference: 0,
+        };
+        for (a, b) in self.pixels.chunks(4).zip(other.pixels.chunks(4)) {
+            let d = a.iter().zip(b.iter())
+                .map(|(&x, &y)| if x > y { x - y } else { y - x })
+                .max()
+                .unwrap_or(0);
+            if d > tolerance {
+                diff.mismatched+= 1;
+            }
+            diff.max_difference = ::std::cmp::max(diff.max_difference, d);
+        }
+        diff
+    }
+
+    /// Loads an 8 bit RGB or RGBA PNG, RGB gets opaque
+    pub fn load_png<P: AsRef<Path>>(path: P) -> Result<Image, RasterError> {
+        let file = try!(File::open(path));
+        let mut decoder = PNGDecoder::new(BufReader::new(file));
+        let (w, h) = try!(decoder.dimensions());
+        let color_type = try!(decoder.colortype());
+        let data = try!(decoder.read_image());
+
+        let pixels = match (color_type, data) {
+            (ColorType::RGBA(8), DecodingResult::U8(data)) => data,
+            (ColorType::RGB(8), DecodingResult::U8(data)) => {
+                data.chunks(3).flat_map(|rgb| vec![rgb[0], rgb[1], rgb[2], 255]).collect()
+            },
+            (color_type, _) => return Err(RasterError::UnsupportedColorType(color_type)),
+        };
+        Ok(Image {
+            width: w,
+            height: h,
+            pixels: pixels,
+        })
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<(), RasterError> {
+        let file = try!(File::create(path));
+        try!(PNGEncoder::new(BufWriter::new(file)).encode(&self.pixels, self.width, self.height, ColorType::RGBA(8)));
+        Ok(())
+    }
+}
+
+/// CPU reference of the renderer's terrain pass: OpenGL clip space and depth test, perspective
+/// correct interpolation and the lighting of the face shader with a single texture layer.
+/// Doesn't cull back faces, like the renderer. Pixel centers are sampled, without multisampling.
+pub struct Rasterizer {
+    color: Image,
+    /// Window depth in `[0, 1]`, cleared to 1
+    depth: Vec<f32>,
+}
+
+/// Vertex after the vertex shader
+#[derive(Copy, Clone, Debug)]
+struct ClipVertex {
+    pos: [f32; 4],
+    tex_pos: [f32; 2],
+    normal: [f32; 3],
+}
+
+impl Rasterizer {
+    pub fn new(width: u32, height: u32, clear_color: [u8; 4]) -> Rasterizer {
+        Rasterizer {
+            color: Image::new(width, height, clear_color),
+            depth: vec![1.0; width as usize * height as usize],
+        }
+    }
+
+    pub fn image(&self) -> &Image {
+        &self.color
+    }
+
+    pub fn into_image(self) -> Image {
+        self.color
+    }
+
+    /// Draws the mesh's triangles, which can be lists or strips like for `export::triangles`
+    pub fn draw(&mut self, mesh: &Mesh<FaceVertex>, projview: &Matrix4<f32>, texture: &Image, light: &Light)
+            -> Result<(), RasterError> {
+        let tris = try!(export::triangles(mesh).map_err(RasterError::Mesh));
+        let verts = mesh.verts.iter()
+            .map(|v| {
+                let pos = *projview * Vector4::new(v.v_pos[0], v.v_pos[1], v.v_pos[2], 1.0);
+                ClipVertex {
+                    pos: [pos.x, pos.y, pos.z, pos.w],
+                    tex_pos: v.v_tex_pos,
+                    normal: v.v_normal,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for tri in tris.iter() {
+            let polygon = clip_near([verts[tri[0] as usize], verts[tri[1] as usize], verts[tri[2] as usize]]);
+            // a fan over the clipped polygon
+            for i in 1..polygon.len().saturating_sub(1) {
+                self.triangle([polygon[0], polygon[i], polygon[i + 1]], texture, light);
+            }
+        }
+        Ok(())
+    }
+
+    fn triangle(&mut self, tri: [ClipVertex; 3], texture: &Image, light: &Light) {
+        let (w, h) = (self.color.width as f32, self.color.height as f32);
+
+        // window coordinates with y downwards, depth and 1 / w
+        let mut screen = [[0.0f32; 4]; 3];
+        for (s, v) in screen.iter_mut().zip(tri.iter()) {
+            let inv_w = 1.0 / v.pos[3];
+            s[0] = (v.pos[0] * inv_w * 0.5 + 0.5) * w;
+            s[1] = (0.5 - v.pos[1] * inv_w * 0.5) * h;
+            s[2] = v.pos[2] * inv_w * 0.5 + 0.5;
+            s[3] = inv_w;
+        }
+
+        let area = edge(screen[0], screen[1], screen[2]);
+        if area == 0.0 {
+            return;
+        }
+
+        let min_x = screen.iter().fold(w, |acc, s| acc.min(s[0])).max(0.0).floor() as u32;
+        let max_x = screen.iter().fold(0.0f32, |acc, s| acc.max(s[0])).min(w - 1.0).ceil() as u32;
+        let min_y = screen.iter().fold(h, |acc, s| acc.min(s[1])).max(0.0).floor() as u32;
+        let max_y = screen.iter().fold(0.0f32, |acc, s| acc.max(s[1])).min(h - 1.0).ceil() as u32;
+
+        for y in min_y..max_y + 1 {
+            for x in min_x..max_x + 1 {
+                let p = [x as f32 + 0.5, y as f32 + 0.5, 0.0, 0.0];
+                // barycentric coordinates, both windings
+                let b = [
+                    edge(screen[1], screen[2], p) / area,
+                    edge(screen[2], screen[0], p) / area,
+                    edge(screen[0], screen[1], p) / area,
+                ];
+                if b[0] < 0.0 || b[1] < 0.0 || b[2] < 0.0 {
+                    continue;
+                }
+
+                // depth is linear in window space
+                let depth = b[0] * screen[0][2] + b[1] * screen[1][2] + b[2] * screen[2][2];
+                let i = y as usize * self.color.width as usize + x as usize;
+                if depth > 1.0 || depth >= self.depth[i] {
+                    continue;
+                }
+
+                // attributes are linear in clip space
+                let pw = [b[0] * screen[0][3], b[1] * screen[1][3], b[2] * screen[2][3]];
+                let sum = pw[0] + pw[1] + pw[2];
+                let mut tex_pos = [0.0; 2];
+                for k in 0..2 {
+                    tex_pos[k] = (tri[0].tex_pos[k] * pw[0] + tri[1].tex_pos[k] * pw[1] + tri[2].tex_pos[k] * pw[2]) / sum;
+                }
+                let mut normal = [0.0; 3];
+                for axis in 0..3 {
+                    normal[axis] = (tri[0].normal[axis] * pw[0] + tri[1].normal[axis] * pw[1] + tri[2].normal[axis] * pw[2]) / sum;
+                }
+
+                self.depth[i] = depth;
+                let color = shade(texture.sample(tex_pos), normal, light);
+                self.color.set(x, y, color);
+            }
+        }
+    }
+}
+
+/// The fragment shader
+fn shade(texel: [f32; 4], normal: [f32; 3], light: &Light) -> [u8; 4] {
+    let len = (normal[0] * normal[0] + normal[1] * normal[1] + normal[2] * normal[2]).sqrt();
+    let diffuse = if len > 0.0 {
+        ((normal[0] * light.dir[0] + normal[1] * light.dir[1] + normal[2] * light.dir[2]) / len).max(0.0)
+    } else {
+        0.0
+    };
+
+    let to_u8 = |x: f32| (x.max(0.0).min(1.0) * 255.0).round() as u8;
+    let mut color = [0; 4];
+    for i in 0..3 {
+        color[i] = to_u8(texel[i] * (light.ambient[i] + light.color[i] * diffuse));
+    }
+    color[3] = to_u8(texel[3]);
+    color
+}
+
+/// Cuts off what is in front of the near plane, `z >= -w`. The result has 0, 3 or 4 vertices.
+fn clip_near(tri: [ClipVertex; 3]) -> Vec<ClipVertex> {
+    let dist = |v: &ClipVertex| v.pos[2] + v.pos[3];
+    let mut out = Vec::with_capacity(4);
+    for i in 0..3 {
+        let (a, b) = (tri[i], tri[(i + 1) % 3]);
+        let (da, db) = (dist(&a), dist(&b));
+        if da >= 0.0 {
+            out.push(a);
+        }
+        if (da >= 0.0) != (db >= 0.0) {
+            let t = da / (da - db);
+            let mut v = a;
+            for k in 0..4 {
+                v.pos[k] = a.pos[k] + (b.pos[k] - a.pos[k]) * t;
+            }
+            for k in 0..2 {
+                v.tex_pos[k] = a.tex_pos[k] + (b.tex_pos[k] - a.tex_pos[k]) * t;
+            }
+            for k in 0..3 {
+                v.normal[k] = a.normal[k] + (b.normal[k] - a.normal[k]) * t;
+            }
+            out.push(v);
+        }
+    }
+    out
+}
+
+/// Twice the signed area of `a`, `b`, `p`
+fn edge(a: [f32; 4], b: [f32; 4], p: [f32; 4]) -> f32 {
+    (b[0] - a[0]) * (p[1] - a[1]) - (b[1] - a[1]) * (p[0] - a[0])
+}
+
+fn wrap(i: i64, size: u32) -> u32 {
+    let size = size as i64;
+    (((i % size) + size) % size) as u32
+}
+
+impl From<io::Error> for RasterError {
+    fn from(err: io::Error) -> RasterError {
+        RasterError::IoError(err)
+    }
+}
+
+impl From<ImageError> for RasterError {
+    fn from(err: ImageError) -> RasterError {
+        RasterError::ImageError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::{Path, PathBuf};
+
+    use terrain::{self, Area};
+    use height_source::{Fractal, Basis};
+    use mesh::{self, IndexLayout, NormalMethod};
+    use cg::{self, Matrix4, Point3, Vector3};
+
+    use util::{NonZero};
+    use super::*;
+
+    /// Per channel
+    const TOLERANCE: u8 = 2;
+    /// Pixels allowed to exceed the tolerance, for edges that fall on pixel centers
+    const MAX_MISMATCHED: usize = 8;
+
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+
+    /// 33 by 33 samples of value noise hills, 32 by 32 world units
+    fn hills() -> Mesh<FaceVertex> {
+        let source = Fractal::fbm(Basis::Value, 7).octaves(4).wavelength(16.0);
+        let samples = [NonZero::new(33).unwrap(), NonZero::new(33).unwrap()];
+        let area = Area { x: 0.0, y: 0.0, w: 64.0, h: 64.0 };
+        let terrain = terrain::gen_terrain_with(samples, &source, area, 12.0);
+        mesh::indexed_terrain_mesh(&terrain, [1.0, 1.0], 8, IndexLayout::Triangles, NormalMethod::CentralDifference)
+    }
+
+    /// Two greens, 2 by 2 texels each
+    fn checker() -> Image {
+        let mut image = Image::new(4, 4, [90, 140, 60, 255]);
+        for y in 0..4 {
+            for x in 0..4 {
+                if (x / 2 + y / 2) % 2 == 1 {
+                    image.set(x, y, [150, 190, 90, 255]);
+                }
+            }
+        }
+        image
+    }
+
+    fn sun() -> Light {
+        let dir = [0.4, 0.8, 0.3];
+        let len = (0.16f32 + 0.64 + 0.09).sqrt();
+        Light {
+            dir: [dir[0] / len, dir[1] / len, dir[2] / len],
+            color: [0.9, 0.85, 0.8],
+            ambient: [0.2, 0.2, 0.25],
+        }
+    }
+
+    fn render(eye: [f32; 3], center: [f32; 3], near: f32) -> Image {
+        let (w, h) = (96, 64);
+        let proj = cg::perspective(cg::deg(60.0), w as f32 / h as f32, near, 100.0);
+        let view = Matrix4::look_at(Point3::new(eye[0], eye[1], eye[2]), Point3::new(center[0], center[1], center[2]),
+            Vector3::new(0.0, 1.0, 0.0));
+        let projview = proj * view;
+
+        let mut rasterizer = Rasterizer::new(w, h, [40, 60, 90, 255]);
+        rasterizer.draw(&hills(), &projview, &checker(), &sun()).unwrap();
+        rasterizer.into_image()
+    }
+
+    fn golden_path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(name)
+    }
+
+    /// Compares with `tests/golden/<name>`, or overwrites it when `UPDATE_GOLDEN` is set
+    fn assert_golden(image: &Image, name: &str) {
+        let path = golden_path(name);
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            image.save_png(&path).unwrap();
+            return;
+        }
+
+        let golden = Image::load_png(&path).unwrap();
+        let diff = image.compare(&golden, TOLERANCE);
+        assert!(diff.mismatched <= MAX_MISMATCHED, "{}: {:?}", name, diff);
+    }
+
+    #[test]
+    fn hills_overview() {
+        assert_golden(&render([-6.0, 20.0, -6.0], [16.0, 3.0, 16.0], 0.5), "hills.png");
+    }
+
+    #[test]
+    fn hills_through_the_near_plane() {
+        // close to the ground, the triangles below and beside the eye cross the near plane
+        assert_golden(&render([16.0, 8.0, 2.0], [16.0, 4.0, 30.0], 2.0), "hills_near.png");
+    }
+
+    #[test]
+    fn compare_counts_pixels_beyond_the_tolerance() {
+        let a = Image::new(4, 2, [10, 20, 30, 255]);
+        let mut b = a.clone();
+        b.set(0, 0, [12, 20, 30, 255]);
+        b.set(3, 1, [10, 20, 35, 255]);
+
+        assert_eq!(a.compare(&b, 2), ImageDiff { mismatched: 1, max_difference: 5 });
+        assert_eq!(a.compare(&b, 5), ImageDiff { mismatched: 0, max_difference: 5 });
+        assert_eq!(a.compare(&Image::new(2, 4, BLACK), 255).mismatched, 8);
+    }
+
+    fn vertex(x: f32, y: f32, z: f32, w: f32, u: f32) -> ClipVertex {
+        ClipVertex {
+            pos: [x, y, z, w],
+            tex_pos: [u, 0.0],
+            normal: [0.0, 1.0, 0.0],
+        }
+    }
+
+    fn near_dist(v: &ClipVertex) -> f32 {
+        v.pos[2] + v.pos[3]
+    }
+
+    #[test]
+    fn clip_near_keeps_visible_triangles() {
+        let tri = [vertex(0.0, 0.0, 0.5, 1.0, 0.0), vertex(1.0, 0.0, -1.0, 1.0, 1.0), vertex(0.0, 1.0, 0.0, 2.0, 2.0)];
+        let clipped = clip_near(tri);
+        assert_eq!(clipped.len(), 3);
+        for (c, v) in clipped.iter().zip(tri.iter()) {
+            assert_eq!(c.pos, v.pos);
+            assert_eq!(c.tex_pos, v.tex_pos);
+        }
+    }
+
+    #[test]
+    fn clip_near_drops_hidden_triangles() {
+        let tri = [vertex(0.0, 0.0, -2.0, 1.0, 0.0), vertex(1.0, 0.0, -3.0, 2.0, 1.0), vertex(0.0, 1.0, -1.5, 1.0, 2.0)];
+        assert!(clip_near(tri).is_empty());
+    }
+
+    #[test]
+    fn clip_near_one_vertex_behind() {
+        // the first vertex is 1 behind the plane, the others 1 and 3 in front of it
+        let tri = [vertex(0.0, 0.0, -2.0, 1.0, 0.0), vertex(1.0, 0.0, 0.0, 1.0, 1.0), vertex(0.0, 1.0, 1.0, 2.0, 2.0)];
+        let clipped = clip_near(tri);
+        assert_eq!(clipped.len(), 4);
+        assert!(clipped.iter().all(|v| near_dist(v) >= -1e-6));
+
+        let on_plane = clipped.iter().filter(|v| near_dist(v).abs() < 1e-6).collect::<Vec<_>>();
+        assert_eq!(on_plane.len(), 2);
+        // a quarter of the way to the third vertex and halfway to the second, which both lands on u = 0.5
+        assert!(on_plane.iter().all(|v| (v.tex_pos[0] - 0.5).abs() < 1e-6));
+        assert!(on_plane.iter().any(|v| (v.pos[1] - 0.25).abs() < 1e-6));
+        assert!(on_plane.iter().any(|v| (v.pos[0] - 0.5).abs() < 1e-6 && v.pos[1] == 0.0));
+    }
+
+    #[test]
+    fn clip_near_two_vertices_behind() {
+        let tri = [vertex(0.0, 0.0, -2.0, 1.0, 0.0), vertex(1.0, 0.0, 1.0, 1.0, 1.0), vertex(0.0, 1.0, -4.0, 2.0, 2.0)];
+        let clipped = clip_near(tri);
+        assert_eq!(clipped.len(), 3);
+        assert!(clipped.iter().all(|v| near_dist(v) >= -1e-6));
+        assert_eq!(clipped.iter().filter(|v| near_dist(v).abs() < 1e-6).count(), 2);
+        assert!(clipped.iter().any(|v| v.pos == tri[1].pos));
+    }
+
+    /// Draws a triangle given in normalized device coordinates, in white
+    fn draw_ndc(rasterizer: &mut Rasterizer, corners: [[f32; 2]; 3]) {
+        let v = |c: [f32; 2]| vertex(c[0], c[1], 0.0, 1.0, 0.0);
+        let light = Light {
+            dir: [0.0, 1.0, 0.0],
+            color: [1.0, 1.0, 1.0],
+            ambient: [0.0, 0.0, 0.0],
+        };
+        rasterizer.triangle([v(corners[0]), v(corners[1]), v(corners[2])], &Image::new(1, 1, WHITE), &light);
+    }
+
+    fn untouched(rasterizer: &Rasterizer) -> bool {
+        rasterizer.color == Image::new(8, 8, BLACK) && rasterizer.depth.iter().all(|&d| d == 1.0)
+    }
+
+    #[test]
+    fn off_screen_triangles_draw_nothing() {
+        let mut rasterizer = Rasterizer::new(8, 8, BLACK);
+        draw_ndc(&mut rasterizer, [[-3.0, -0.5], [-1.5, 0.5], [-2.0, 0.8]]);
+        draw_ndc(&mut rasterizer, [[1.5, -0.5], [3.0, 0.5], [2.0, 0.8]]);
+        draw_ndc(&mut rasterizer, [[-0.5, 1.5], [0.5, 1.5], [0.0, 3.0]]);
+        draw_ndc(&mut rasterizer, [[-0.5, -1.5], [0.5, -1.5], [0.0, -3.0]]);
+        draw_ndc(&mut rasterizer, [[1e6, 1e6], [2e6, 1e6], [1e6, 2e6]]);
+        draw_ndc(&mut rasterizer, [[-2e6, -1e6], [-1e6, -1e6], [-1e6, -2e6]]);
+        // the bounding box overlaps the screen, the triangle doesn't
+        draw_ndc(&mut rasterizer, [[-3.0, 0.0], [0.0, 3.0], [-3.0, 3.0]]);
+        assert!(untouched(&rasterizer));
+    }
+
+    #[test]
+    fn degenerate_triangles_draw_nothing() {
+        let mut rasterizer = Rasterizer::new(8, 8, BLACK);
+        draw_ndc(&mut rasterizer, [[-0.9, -0.9], [0.0, 0.0], [0.9, 0.9]]);
+        draw_ndc(&mut rasterizer, [[-0.9, 0.1], [0.9, 0.1], [0.0, 0.1]]);
+        draw_ndc(&mut rasterizer, [[0.1, 0.1], [0.1, 0.1], [0.1, 0.1]]);
+        assert!(untouched(&rasterizer));
+    }
+
+    #[test]
+    fn triangles_are_cut_at_the_screen_edges() {
+        // covers the bottom left corner, the hypotenuse is x + y = -1.4
+        for &corners in [[[-2.0, -2.0], [0.6, -2.0], [-2.0, 0.6]], [[-2.0, -2.0], [-2.0, 0.6], [0.6, -2.0]]].iter() {
+            let mut rasterizer = Rasterizer::new(8, 8, BLACK);
+            draw_ndc(&mut rasterizer, corners);
+            for y in 0..8 {
+                for x in 0..8 {
+                    let ndc = [(x as f32 + 0.5) / 4.0 - 1.0, 1.0 - (y as f32 + 0.5) / 4.0];
+                    let expected = if ndc[0] + ndc[1] < -1.4 { WHITE } else { BLACK };
+                    assert_eq!(rasterizer.image().get(x, y), expected, "{} {}", x, y);
+                }
+            }
+        }
+
+        let mut rasterizer = Rasterizer::new(8, 8, BLACK);
+        draw_ndc(&mut rasterizer, [[-10.0, -10.0], [30.0, -10.0], [-10.0, 30.0]]);
+        assert_eq!(rasterizer.into_image(), Image::new(8, 8, WHITE));
+    }
+}
@@ -0,0 +1,336 @@
+use cg::{EuclideanVector, SquareMatrix, Matrix4, Point3, Vector3, Vector4};
+
+use terrain::{Terrain};
+
+#[derive(Copy, Clone, Debug)]
+pub struct Ray {
+    pub origin: Vector3<f32>,
+    /// Doesn't need to be normalized, hit distances are in multiples of it
+    pub dir: Vector3<f32>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RayHit {
+    pub point: Vector3<f32>,
+    /// Distance along the ray, `point = origin + dir * t`
+    pub t: f32,
+    /// Grid coordinates of the lower corner of the hit cell
+    pub cell: [usize; 2],
+    /// 0 for the triangle at the lower corner of the cell, 1 for the one at the opposite corner,
+    /// like in `mesh::chunk_mesh`
+    pub triangle: usize,
+    /// Face normal, pointing up
+    pub normal: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn at(&self, t: f32) -> Vector3<f32> {
+        self.origin + self.dir * t
+    }
+}
+
+/// Turns a cursor position in pixels, origin at the top left, into a world space ray through
+/// the near and far plane. `projview` is the matrix the scene was rendered with.
+pub fn screen_ray(cursor: [f32; 2], viewport: [f32; 2], projview: &Matrix4<f32>) -> Option<Ray> {
+    let inv = match projview.invert() {
+        Some(inv) => inv,
+        None => return None,
+    };
+
+    let ndc_x = cursor[0] / viewport[0] * 2.0 - 1.0;
+    let ndc_y = 1.0 - cursor[1] / viewport[1] * 2.0;
+    let near = Point3::from_homogeneous(inv * Vector4::new(ndc_x, ndc_y, -1.0, 1.0));
+    let far = Point3::from_homogeneous(inv * Vector4::new(ndc_x, ndc_y, 1.0, 1.0));
+
+    Some(Ray {
+        origin: Vector3::new(near.x, near.y, near.z),
+        dir: (far - near).normalize(),
+    })
+}
+
+/// Finds the first intersection of the ray with the triangulated terrain within `max_t`.
+/// Walks the cells under the ray front to back (DDA), so only cells the ray passes over are tested.
+pub fn raycast(terrain: &Terrain, sample_size: [f32; 2], ray: &Ray, max_t: f32) -> Option<RayHit> {
+    let dims = terrain.dims();
+    if dims[0] < 2 || dims[1] < 2 {
+        return None;
+    }
+
+    let (min_h, max_h) = terrain.vec.iter()
+        .fold((::std::f32::MAX, ::std::f32::MIN), |(min, max), &h| (min.min(h), max.max(h)));
+
+    // the ray in grid space, scaling x and z keeps the parametrisation
+    let origin = [ray.origin.x / sample_size[0], ray.origin.y, ray.origin.z / sample_size[1]];
+    let dir = [ray.dir.x / sample_size[0], ray.dir.y, ray.dir.z / sample_size[1]];
+
+    let bounds_min = [0.0, min_h, 0.0];
+    let bounds_max = [(dims[0] - 1) as f32, max_h, (dims[1] - 1) as f32];
+    let (t_start, t_end) = match clip_to_box(origin, dir, bounds_min, bounds_max, 0.0, max_t) {
+        Some(range) => range,
+        None => return None,
+    };
+
+    let start = [origin[0] + dir[0] * t_start, origin[2] + dir[2] * t_start];
+    let mut cell = [
+        (start[0].floor() as i64).max(0).min(dims[0] as i64 - 2),
+        (start[1].floor() as i64).max(0).min(dims[1] as i64 - 2),
+    ];
+
+    let grid_dir = [dir[0], dir[2]];
+    let grid_origin = [origin[0], origin[2]];
+    let mut step = [0i64; 2];
+    let mut t_next = [::std::f32::INFINITY; 2];
+    let mut t_delta = [::std::f32::INFINITY; 2];
+    for axis in 0..2 {
+        if grid_dir[axis] > 0.0 {
+            step[axis] = 1;
+            t_next[axis] = ((cell[axis] + 1) as f32 - grid_origin[axis]) / grid_dir[axis];
+            t_delta[axis] = 1.0 / grid_dir[axis];
+        } else if grid_dir[axis] < 0.0 {
+            step[axis] = -1;
+            t_next[axis] = (cell[axis] as f32 - grid_origin[axis]) / grid_dir[axis];
+            t_delta[axis] = -1.0 / grid_dir[axis];
+        }
+    }
+
+    let mut t_cell_start = t_start;
+    loop {
+        let t_cell_end = t_next[0].min(t_next[1]).min(t_end);
+        let cell_coords = [cell[0] as usize, cell[1] as usize];
+
+        if let Some(hit) = hit_cell(terrain, sample_size, ray, cell_coords, t_cell_start, t_cell_end) {
+            return Some(hit);
+        }
+
+        if t_cell_end >= t_end {
+            return None;
+        }
+
+        let axis = if t_next[0] < t_next[1] { 0 } else { 1 };
+        cell[axis]+= step[axis];
+        if cell[axis] < 0 || cell[axis] > dims[axis] as i64 - 2 {
+            return None;
+        }
+        t_cell_start = t_next[axis];
+        t_next[axis]+= t_delta[axis];
+    }
+}
+
+/// Tests the two triangles of the cell, if the ray's height range over the cell overlaps the cell's
+fn hit_cell(terrain: &Terrain, sample_size: [f32; 2], ray: &Ray, cell: [usize; 2], t0: f32, t1: f32)
+        -> Option<RayHit> {
+    let h = |dx: usize, dz: usize| *terrain.get([cell[0] + dx, cell[1] + dz]).unwrap();
+    let heights = [h(0, 0), h(1, 0), h(0, 1), h(1, 1)];
+    let cell_min = heights.iter().cloned().fold(::std::f32::MAX, f32::min);
+    let cell_max = heights.iter().cloned().fold(::std::f32::MIN, f32::max);
+
+    let y0 = ray.origin.y + ray.dir.y * t0;
+    let y1 = ray.origin.y + ray.dir.y * t1;
+    if y0.min(y1) > cell_max || y0.max(y1) < cell_min {
+        return None;
+    }
+
+    let corner = |dx: usize, dz: usize, height: f32| {
+        Vector3::new(
+            (cell[0] + dx) as f32 * sample_size[0],
+            height,
+            (cell[1] + dz) as f32 * sample_size[1],
+        )
+    };
+    let v00 = corner(0, 0, heights[0]);
+    let v10 = corner(1, 0, heights[1]);
+    let v01 = corner(0, 1, heights[2]);
+    let v11 = corner(1, 1, heights[3]);
+
+    let triangles = [[v00, v10, v01], [v11, v10, v01]];
+    let mut best: Option<RayHit> = None;
+
+    for (i, tri) in triangles.iter().enumerate() {
+        if let Some(t) = intersect_triangle(ray, tri) {
+            if best.map(|b| t < b.t).unwrap_or(true) {
+                let mut normal = (tri[1] - tri[0]).cross(tri[2] - tri[0]).normalize();
+                if normal.y < 0.0 {
+                    normal = -normal;
+                }
+                best = Some(RayHit {
+                    point: ray.at(t),
+                    t: t,
+                    cell: cell,
+                    triangle: i,
+                    normal: normal,
+                });
+            }
+        }
+    }
+
+    best
+}
+
+/// Möller-Trumbore, returns the ray parameter of the hit
+fn intersect_triangle(ray: &Ray, tri: &[Vector3<f32>; 3]) -> Option<f32> {
+    let edge1 = tri[1] - tri[0];
+    let edge2 = tri[2] - tri[0];
+    let p = ray.dir.cross(edge2);
+    let det = edge1.dot(p);
+    if det.abs() < 1e-9 {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let s = ray.origin - tri[0];
+    let u = s.dot(p) * inv_det;
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+
+    let q = s.cross(edge1);
+    let v = ray.dir.dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = edge2.dot(q) * inv_det;
+    if t >= 0.0 {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+/// Slab test, returns the part of `[t_min, t_max]` in which the ray is inside the box
+fn clip_to_box(origin: [f32; 3], dir: [f32; 3], min: [f32; 3], max: [f32; 3], t_min: f32, t_max: f32)
+        -> Option<(f32, f32)> {
+    let mut t0 = t_min;
+    let mut t1 = t_max;
+
+    for axis in 0..3 {
+        if dir[axis] == 0.0 {
+            if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                return None;
+            }
+        } else {
+            let a = (min[axis] - origin[axis]) / dir[axis];
+            let b = (max[axis] - origin[axis]) / dir[axis];
+            t0 = t0.max(a.min(b));
+            t1 = t1.min(a.max(b));
+        }
+    }
+
+    if t0 <= t1 {
+        Some((t0, t1))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cg::{self, Point3};
+
+    use util::{Mat, FixedHeight};
+    use super::*;
+
+    /// 16 by 16 samples of `f(x, z)` at the world positions of the samples
+    fn terrain<F>(sample_size: [f32; 2], f: F) -> Terrain where F: Fn(f32, f32) -> f32 {
+        Mat {
+            vec: (0..256).map(|i| f((i / 16) as f32 * sample_size[0], (i % 16) as f32 * sample_size[1])).collect(),
+            fixed_dim: FixedHeight::from_height(16).unwrap(),
+        }
+    }
+
+    fn ray(origin: [f32; 3], dir: [f32; 3]) -> Ray {
+        Ray {
+            origin: Vector3::new(origin[0], origin[1], origin[2]),
+            dir: Vector3::new(dir[0], dir[1], dir[2]),
+        }
+    }
+
+    fn assert_near(a: Vector3<f32>, b: [f32; 3]) {
+        assert!((a.x - b[0]).abs() < 1e-4 && (a.y - b[1]).abs() < 1e-4 && (a.z - b[2]).abs() < 1e-4,
+            "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn hits_a_plane() {
+        let t = terrain([1.0, 1.0], |_, _| 2.0);
+        let hit = raycast(&t, [1.0, 1.0], &ray([0.5, 10.0, 0.5], [1.0, -1.0, 0.5]), 100.0).unwrap();
+        assert!((hit.t - 8.0).abs() < 1e-4);
+        assert_near(hit.point, [8.5, 2.0, 4.5]);
+        assert_eq!(hit.cell, [8, 4]);
+        assert_near(hit.normal, [0.0, 1.0, 0.0]);
+
+        // too far
+        assert!(raycast(&t, [1.0, 1.0], &ray([0.5, 10.0, 0.5], [1.0, -1.0, 0.5]), 7.9).is_none());
+    }
+
+    #[test]
+    fn hits_the_front_of_a_bump() {
+        // a pyramid of height 4 on the sample `[5, 5]`
+        let t = terrain([1.0, 1.0], |x, z| if x == 5.0 && z == 5.0 { 4.0 } else { 0.0 });
+        let hit = raycast(&t, [1.0, 1.0], &ray([0.0, 1.0, 5.2], [1.0, 0.0, 0.0]), 100.0).unwrap();
+        // the face of the lower triangle of the cell `[4, 5]` rises by 4 per unit of x
+        assert_near(hit.point, [4.25, 1.0, 5.2]);
+        assert_eq!(hit.cell, [4, 5]);
+        assert_eq!(hit.triangle, 0);
+        assert!(hit.normal.y > 0.0 && hit.normal.x < 0.0);
+
+        // over the top
+        assert!(raycast(&t, [1.0, 1.0], &ray([0.0, 4.5, 5.2], [1.0, 0.0, 0.0]), 100.0).is_none());
+    }
+
+    #[test]
+    fn misses_above_the_terrain() {
+        let t = terrain([1.0, 1.0], |x, z| (x * 0.7).sin() + (z * 0.4).cos());
+        assert!(raycast(&t, [1.0, 1.0], &ray([-3.0, 10.0, 2.0], [1.0, 0.0, 0.3]), 100.0).is_none());
+        assert!(raycast(&t, [1.0, 1.0], &ray([5.0, 3.0, 5.0], [0.2, 1.0, 0.1]), 100.0).is_none());
+        // beside the grid
+        assert!(raycast(&t, [1.0, 1.0], &ray([20.0, 3.0, 5.0], [0.0, -1.0, 0.0]), 100.0).is_none());
+    }
+
+    #[test]
+    fn enters_the_grid_from_outside() {
+        let t = terrain([1.0, 1.0], |_, _| 2.0);
+        let hit = raycast(&t, [1.0, 1.0], &ray([-5.0, 6.0, 3.5], [1.0, -0.5, 0.0]), 100.0).unwrap();
+        assert_near(hit.point, [3.0, 2.0, 3.5]);
+        assert_eq!(hit.cell, [3, 3]);
+
+        let hit = raycast(&t, [1.0, 1.0], &ray([30.0, 6.0, 20.0], [-1.0, -0.25, -1.0]), 100.0).unwrap();
+        assert_near(hit.point, [14.0, 2.0, 4.0]);
+
+        // leaving it
+        assert!(raycast(&t, [1.0, 1.0], &ray([-5.0, 6.0, 3.5], [-1.0, -0.5, 0.0]), 100.0).is_none());
+    }
+
+    #[test]
+    fn vertical_rays() {
+        let plane = |x: f32, z: f32| 1.0 + 0.25 * x + 0.5 * z;
+        let t = terrain([2.0, 0.5], &plane);
+        for &(x, z) in [(3.3, 4.7), (0.0, 0.0), (30.0, 7.5), (12.0, 2.25)].iter() {
+            let hit = raycast(&t, [2.0, 0.5], &ray([x, 20.0, z], [0.0, -1.0, 0.0]), 100.0).unwrap();
+            assert_near(hit.point, [x, plane(x, z), z]);
+            assert!((hit.t - (20.0 - plane(x, z))).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn screen_center_looks_along_the_camera() {
+        let (eye, dir) = (Point3::new(1.0, 2.0, 3.0), Vector3::new(0.3, -0.4, -1.0).normalize());
+        let proj = cg::perspective(cg::deg(70.0), 1.6, 0.5, 100.0);
+        let projview = proj * Matrix4::look_at(eye, eye + dir, Vector3::new(0.0, 1.0, 0.0));
+
+        let r = screen_ray([800.0, 500.0], [1600.0, 1000.0], &projview).unwrap();
+        assert_near(r.dir, [dir.x, dir.y, dir.z]);
+        assert!((r.dir.length() - 1.0).abs() < 1e-5);
+        // starts on the near plane
+        assert_near(r.origin, [eye.x + dir.x * 0.5, eye.y + dir.y * 0.5, eye.z + dir.z * 0.5]);
+
+        // points along other rays land on their pixel
+        for &cursor in [[0.0, 0.0], [1600.0, 1000.0], [250.0, 730.0]].iter() {
+            let r = screen_ray(cursor, [1600.0, 1000.0], &projview).unwrap();
+            let p = r.at(20.0);
+            let clip = Point3::from_homogeneous(projview * Vector4::new(p.x, p.y, p.z, 1.0));
+            assert!(((clip.x + 1.0) / 2.0 * 1600.0 - cursor[0]).abs() < 0.1, "{:?}", cursor);
+            assert!(((1.0 - clip.y) / 2.0 * 1000.0 - cursor[1]).abs() < 0.1, "{:?}", cursor);
+        }
+    }
+}
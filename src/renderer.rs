@@ -1,161 +1,692 @@
 
 extern crate image;
 
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::cmp;
 
 use glium;
 use glium::Surface;
 use glium::backend::{Facade};
-use glium::texture::{Texture2d, RawImage2d};
+use glium::texture::{Texture2d, DepthTexture2d, RawImage2d};
+use glium::uniforms::{SamplerWrapFunction, Sampler, MagnifySamplerFilter, MinifySamplerFilter};
 use glium::program::{Program};
+use glium::framebuffer::{SimpleFrameBuffer};
 
-use util::{NonZero, EnsureNotZero, MappableArray};
-use terrain::{self, Area};
-use mesh::{self, UploadedMesh, FaceVertex, LineVertex};
+use cg::{SquareMatrix, Matrix4, Vector3};
+
+use util::{NonZero, EnsureNotZero, MappableArray, Ground};
+use terrain::{self, Terrain};
+use sampler::{TerrainSampler, Interpolation};
+use mesh::{self, UploadedMesh, FaceVertex, MeshUploadError, EdgeSamples, Seams, IndexLayout, NormalMethod};
+use lod::{LodTree, LodParams, SeamMode};
+use frustum::{Frustum};
+use streaming::{TileStreamer, StreamingParams};
+use splat::{self, SplatRule};
+use shadow::{self, Cascade};
+use water::{Water, WaterParams};
+use sky::{SkyParams, SkyState};
+use config::{Config, ShadowConfig};
+
+/// What was drawn in the last frame
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RenderStats {
+    /// Chunks chosen by the level of detail
+    pub chunks_selected: usize,
+    /// Selected chunks outside the view frustum, not drawn
+    pub chunks_culled: usize,
+    /// Streamed tiles waiting to be generated or uploaded
+    pub tiles_in_flight: usize,
+}
+
+/// Frames a stitched chunk is kept after it was last selected
+const STITCHED_FRAMES: u64 = 300;
+
+/// The terrain of `terrain.size`, drawn in chunks chosen by the level of detail
+struct FixedTerrain {
+    heights: Terrain,
+    sample_size: [f32; 2],
+    lod: LodTree,
+    /// Mesh of each lod node, indexed like `lod.nodes`
+    chunks: Vec<UploadedMesh<FaceVertex>>,
+    /// Chunks with stitched borders, built when a combination of neighbours first shows up, and the
+    /// frame each was last selected in
+    stitched: HashMap<(usize, [Option<EdgeSamples>; 4]), (UploadedMesh<FaceVertex>, u64)>,
+    /// Number of the current frame, counted by `select`
+    frame: u64,
+    samples_per_tex: usize,
+    normal_method: NormalMethod,
+    index_layout: IndexLayout,
+    /// Weights of the layers for each sample
+    splat_tex: Texture2d,
+    splat_transform: [f32; 4],
+}
+
+impl FixedTerrain {
+    fn new<F: Facade>(facade: &F, config: &Config, rules: &[SplatRule]) -> Result<FixedTerrain, MeshUploadError> {
+        let tc = &config.terrain;
+        let samples = [tc.samples[0].ensure_not_zero(), tc.samples[1].ensure_not_zero()];
+        let heights = terrain::gen_terrain(samples, tc.seed, tc.area, tc.max_height);
+        let samples = samples.map().with(|x| x.val());
+
+        let sample_size = [tc.size[0] / samples[0] as f32, tc.size[1] / samples[1] as f32];
+
+        let lod = LodTree::build(&heights, sample_size, config.lod.chunk_cells, config.lod.seams);
+        let chunks = try!(lod.nodes.iter()
+            .map(|node| {
+                let seams = match node.seams {
+                    SeamMode::Skirts => Seams::Skirts(config.lod.skirt_depth),
+                    SeamMode::None | SeamMode::Stitch => Seams::None,
+                };
+                mesh::chunk_mesh(&heights, sample_size, tc.samples_per_tex, node.origin, node.cells, node.stride, seams,
+                    tc.index_layout, tc.normals)
+                    .upload(facade)
+            })
+            .collect::<Result<Vec<_>, MeshUploadError>>());
+
+        let dims = heights.dims();
+        let splat_tex = {
+            let image = RawImage2d::from_raw_rgba(splat::splat_map(&heights, sample_size, rules), (dims[0] as u32, dims[1] as u32));
+            Texture2d::new(facade, image).expect("Error uploading splat map")
+        };
+
+        Ok(FixedTerrain {
+            lod: lod,
+            chunks: chunks,
+            stitched: HashMap::new(),
+            frame: 0,
+            samples_per_tex: tc.samples_per_tex,
+            normal_method: tc.normals,
+            index_layout: tc.index_layout,
+            splat_tex: splat_tex,
+            splat_transform: splat::splat_transform(dims, sample_size),
+            heights: heights,
+            sample_size: sample_size,
+        })
+    }
+
+    /// Chunks to draw from `eye` and the coarser neighbours of each, building the stitched
+    /// chunks that are missing and dropping those not selected for `STITCHED_FRAMES` frames
+    fn select<F: Facade>(&mut self, facade: &F, eye: [f32; 3], params: &LodParams)
+            -> (Vec<usize>, Vec<[Option<EdgeSamples>; 4]>) {
+        self.frame+= 1;
+        let selected = self.lod.select(eye, params);
+        let selected_set = selected.iter().cloned().collect::<HashSet<_>>();
+        let edges = selected.iter()
+            .map(|&id| {
+                if self.lod.nodes[id].seams == SeamMode::Stitch {
+                    self.lod.coarser_neighbours(&selected_set, id)
+                } else {
+                    [None; 4]
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // stitched chunks are built up front, so all chunks can be borrowed together afterwards
+        for (&id, &edges) in selected.iter().zip(edges.iter()) {
+            if edges.iter().any(|e| e.is_some()) {
+                let (heights, sample_size, samples_per_tex) = (&self.heights, self.sample_size, self.samples_per_tex);
+                let (normal_method, index_layout) = (self.normal_method, self.index_layout);
+                let node = &self.lod.nodes[id];
+                let entry = self.stitched.entry((id, edges)).or_insert_with(|| {
+                    let mesh = mesh::chunk_mesh(heights, sample_size, samples_per_tex, node.origin, node.cells, node.stride,
+                        Seams::Stitch(edges), index_layout, normal_method)
+                        .upload(facade).expect("Error uploading chunk");
+                    (mesh, 0)
+                });
+                entry.1 = self.frame;
+            }
+        }
+
+        let frame = self.frame;
+        let stale = self.stitched.iter()
+            .filter(|&(_, &(_, used))| frame - used > STITCHED_FRAMES)
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+        for key in stale {
+            self.stitched.remove(&key);
+        }
+
+        (selected, edges)
+    }
+}
+
+/// Where the drawn terrain comes from
+enum Landscape {
+    Fixed(FixedTerrain),
+    Streamed(TileStreamer),
+}
 
 pub struct Renderer {
-    /// Y-Major
-    terrain: UploadedMesh<FaceVertex>,
-    normals: UploadedMesh<LineVertex>,
-    terrain_tex: Texture2d,
-    line_shader: Program,
+    landscape: Landscape,
+    max_pixel_error: f32,
+    fovy: f32,
+    /// One texture per material layer
+    layer_tex: Vec<Texture2d>,
+    triplanar: bool,
+    triplanar_scale: f32,
+    triplanar_sharpness: f32,
+    /// One map per cascade, a single unused texel if shadows are disabled
+    shadow_maps: Vec<DepthTexture2d>,
+    shadows: ShadowConfig,
+    near: f32,
+    far: f32,
     face_shader: Program,
+    shadow_shader: Program,
+    water: Option<Water>,
+    sky: SkyParams,
+    stats: RenderStats,
 }
 
 impl Renderer {
-    pub fn new<F: Facade>(facade: &F) -> Result<Renderer, mesh::MeshUploadError> {
-        let area = Area {x: 0.0, y: 0.0, w: 1000.0, h: 1000.0};
-        let samples = [100.ensure_not_zero(); 2];
-        let terrain = terrain::gen_terrain(samples, 12, area, 30.0);
-        let samples = samples.map().with(|x| x.val());
+    pub fn new<F: Facade>(facade: &F, config: &Config) -> Result<Renderer, mesh::MeshUploadError> {
+        let tc = &config.terrain;
+        let layers = if config.splat.layers.is_empty() {
+            vec![(tc.texture.clone(), SplatRule::everywhere())]
+        } else {
+            config.splat.layers.iter().map(|layer| (layer.texture.clone(), layer.rule)).collect()
+        };
+        let rules = layers.iter().map(|&(_, rule)| rule).collect::<Vec<_>>();
 
-        let sample_size = [100.0 / samples[0] as f32, 100.0 / samples[1] as f32];
-        let terrain_mesh = mesh::terrain_mesh(&terrain, sample_size, 30);
-        let uploaded_terrain = terrain_mesh.upload(facade);
+        // the fixed terrain is only generated when it is drawn
+        let sc = &config.streaming;
+        let landscape = if sc.enabled {
+            Landscape::Streamed(TileStreamer::new(StreamingParams {
+                seed: tc.seed,
+                max_height: tc.max_height,
+                noise_scale: tc.area.w / tc.size[0],
+                noise_origin: [tc.area.x, tc.area.y],
+                tile_size: sc.tile_size,
+                cells: sc.cells,
+                samples_per_tex: tc.samples_per_tex,
+                normal_method: tc.normals,
+                index_layout: tc.index_layout,
+                load_radius: sc.load_radius,
+                unload_radius: sc.unload_radius,
+                max_tiles: sc.max_tiles,
+                max_in_flight: sc.max_in_flight,
+                uploads_per_frame: sc.uploads_per_frame,
+                workers: sc.workers,
+            }, rules.clone()))
+        } else {
+            Landscape::Fixed(try!(FixedTerrain::new(facade, config, &rules)))
+        };
 
-        let terrain_texture = {
-            let file = File::open("res/terrain.png").expect("Error opening file");
-            let image = image::load(file, image::PNG).expect("Error decoding image").to_rgba();
-            let dims = image.dimensions();
-            let image = RawImage2d::from_raw_rgba_reversed(image.into_raw(), dims);
-            Texture2d::new(facade, image).expect("Error uploading texture")
+        let layer_textures = layers.iter()
+            .map(|&(ref path, _)| {
+                let image = image::open(path).expect("Error loading texture").to_rgba();
+                let dims = image.dimensions();
+                let image = RawImage2d::from_raw_rgba_reversed(image.into_raw(), dims);
+                Texture2d::new(facade, image).expect("Error uploading texture")
+            })
+            .collect::<Vec<_>>();
+
+        let shadow_maps = if config.shadows.enabled {
+            (0..config.shadows.cascades)
+                .map(|_| DepthTexture2d::empty(facade, config.shadows.map_size, config.shadows.map_size)
+                    .expect("Error creating shadow map"))
+                .collect::<Vec<_>>()
+        } else {
+            vec![DepthTexture2d::empty(facade, 1, 1).expect("Error creating shadow map")]
         };
 
-        let normal_lines_colors = [[0.0, 0.0, 1.0], [0.0, 1.0, 0.0]];
-        let shown_normals = mesh::show_normals(&terrain_mesh, 0.2, normal_lines_colors).upload(facade);
+        let wc = &config.water;
+        let water = if wc.enabled {
+            Some(try!(Water::new(facade, WaterParams {
+                level: wc.level * tc.max_height,
+                deep_color: wc.deep_color,
+                absorption: wc.absorption,
+                foam_depth: wc.foam_depth,
+                wave_scale: wc.wave_scale,
+                wave_speed: wc.wave_speed,
+                distortion: wc.distortion,
+                clip_margin: wc.clip_margin,
+                resolution: wc.resolution,
+            })))
+        } else {
+            None
+        };
 
-        uploaded_terrain
-            .and_then(|uploaded_terrain| {
-                shown_normals.map(|sn| (sn, uploaded_terrain))
-            })
-            .map(|(shown_normals, terrain)| {
-                Renderer {
-                    terrain: terrain,
-                    normals: shown_normals,
-                    terrain_tex: terrain_texture,
-                    line_shader: program!(facade,
-                        330 => {
-                            vertex: r#"
-                                #version 330
-
-                                in vec3 v_pos;
-                                in vec3 v_color;
-                                out vec3 p_color;
-
-                                uniform mat4 projview;
-                                uniform mat4 model;
-
-                                void main()
-                                {
-                                    gl_Position = projview * model * vec4(v_pos, 1.0);
-                                    p_color = v_color;
-                                }
-                            "#,
-                            fragment: r#"
-                                #version 330
+        Ok(Renderer {
+            landscape: landscape,
+            max_pixel_error: config.lod.max_pixel_error,
+            fovy: config.camera.fov.to_radians(),
+            layer_tex: layer_textures,
+            triplanar: config.triplanar.enabled,
+            triplanar_scale: config.triplanar.scale,
+            triplanar_sharpness: config.triplanar.sharpness,
+            shadow_maps: shadow_maps,
+            shadows: config.shadows.clone(),
+            near: config.camera.near,
+            far: config.camera.far,
+            face_shader: program!(facade,
+                330 => {
+                    vertex: r#"
+                        #version 330
 
-                                in vec3 p_color;
-                                out vec4 f_color;
+                        in vec3 v_pos;
+                        in vec2 v_tex_pos;
+                        in vec3 v_normal;
 
-                                void main()
-                                {
-                                    f_color = vec4(p_color, 1.0);
-                                }
-                            "#,
-                        }).expect("Error creating program"),
-                    face_shader: program!(facade,
-                        330 => {
-                            vertex: r#"
-                                #version 330
-
-                                in vec3 v_pos;
-                                in vec2 v_tex_pos;
-                                in vec3 v_normal;
-
-                                out vec2 p_tex_pos;
-                                out vec3 p_normal;
-
-                                uniform mat4 projview;
-                                uniform mat4 model;
-
-                                void main()
-                                {
-                                    gl_Position = projview * model * vec4(v_pos, 1.0);
-                                    p_tex_pos = v_tex_pos;
-                                    p_normal = v_normal;
-                                }
-                            "#,
-                            fragment: r#"
-                                #version 330
+                        out vec2 p_tex_pos;
+                        out vec3 p_normal;
+                        out vec2 p_splat_pos;
+                        out vec3 p_world_pos;
+                        out float p_depth;
+
+                        uniform mat4 projview;
+                        uniform mat4 model;
+                        // mesh xz to splat map coordinates, scale and offset
+                        uniform vec4 splat_transform;
+
+                        void main()
+                        {
+                            vec4 world_pos = model * vec4(v_pos, 1.0);
+                            gl_Position = projview * world_pos;
+                            p_world_pos = world_pos.xyz;
+                            // view depth with a perspective projection
+                            p_depth = gl_Position.w;
+                            p_tex_pos = v_tex_pos;
+                            p_normal = v_normal;
+                            p_splat_pos = v_pos.xz * splat_transform.xy + splat_transform.zw;
+                        }
+                    "#,
+                    fragment: r#"
+                        #version 330
 
-                                in vec2 p_tex_pos;
-                                in vec3 p_normal;
+                        in vec2 p_tex_pos;
+                        in vec3 p_normal;
+                        in vec2 p_splat_pos;
+                        in vec3 p_world_pos;
+                        in float p_depth;
 
-                                out vec4 f_color;
+                        out vec4 f_color;
 
-                                uniform vec3 light_dir;
-                                uniform sampler2D tex;
+                        // towards the sun or moon
+                        uniform vec3 light_dir;
+                        uniform vec3 light_color;
+                        uniform vec3 ambient;
+                        uniform sampler2D tex0;
+                        uniform sampler2D tex1;
+                        uniform sampler2D tex2;
+                        uniform sampler2D tex3;
+                        // weight of each layer
+                        uniform sampler2D splat;
+                        uniform bool triplanar;
+                        uniform float triplanar_scale;
+                        uniform float triplanar_sharpness;
+                        uniform int cascade_count;
+                        // view depth where each cascade ends
+                        uniform vec4 cascade_far;
+                        uniform mat4 shadow_matrix0;
+                        uniform mat4 shadow_matrix1;
+                        uniform mat4 shadow_matrix2;
+                        uniform mat4 shadow_matrix3;
+                        uniform sampler2D shadow_map0;
+                        uniform sampler2D shadow_map1;
+                        uniform sampler2D shadow_map2;
+                        uniform sampler2D shadow_map3;
+                        uniform float shadow_bias;
+                        uniform float shadow_normal_bias;
+                        uniform int pcf_radius;
+                        // only what is on the positive side is drawn
+                        uniform vec4 clip_plane;
 
-                                void main()
-                                {
-                                    f_color = texture(tex, p_tex_pos) * dot(p_normal, light_dir);
+                        vec4 layer_color(sampler2D tex)
+                        {
+                            if (!triplanar) {
+                                return texture(tex, p_tex_pos);
+                            }
+
+                            // projections along the axes, weighted by how much the surface faces them
+                            vec3 w = pow(abs(normalize(p_normal)), vec3(triplanar_sharpness));
+                            w /= w.x + w.y + w.z;
+                            vec3 p = p_world_pos / triplanar_scale;
+                            return texture(tex, p.zy) * w.x
+                                + texture(tex, p.xz) * w.y
+                                + texture(tex, p.xy) * w.z;
+                        }
+
+                        // fraction of the shadow map texels around the position that see it
+                        float lit_fraction(sampler2D shadow_map, mat4 shadow_matrix, vec3 pos)
+                        {
+                            vec3 p = (shadow_matrix * vec4(pos, 1.0)).xyz * 0.5 + 0.5;
+                            if (p.x < 0.0 || p.x > 1.0 || p.y < 0.0 || p.y > 1.0 || p.z > 1.0) {
+                                return 1.0;
+                            }
+
+                            vec2 texel = 1.0 / vec2(textureSize(shadow_map, 0));
+                            float lit = 0.0;
+                            for (int x = -pcf_radius; x <= pcf_radius; x++) {
+                                for (int y = -pcf_radius; y <= pcf_radius; y++) {
+                                    lit += p.z <= texture(shadow_map, p.xy + vec2(x, y) * texel).r ? 1.0 : 0.0;
                                 }
-                            "#,
-                        }).expect("Error creating program"),
+                            }
+                            float taps = float(2 * pcf_radius + 1);
+                            return lit / (taps * taps);
+                        }
+
+                        float shadow(vec3 normal)
+                        {
+                            // moved off the surface, so it doesn't shadow itself
+                            vec3 pos = p_world_pos + light_dir * shadow_bias + normal * shadow_normal_bias;
+                            if (cascade_count > 0 && p_depth < cascade_far.x) {
+                                return lit_fraction(shadow_map0, shadow_matrix0, pos);
+                            } else if (cascade_count > 1 && p_depth < cascade_far.y) {
+                                return lit_fraction(shadow_map1, shadow_matrix1, pos);
+                            } else if (cascade_count > 2 && p_depth < cascade_far.z) {
+                                return lit_fraction(shadow_map2, shadow_matrix2, pos);
+                            } else if (cascade_count > 3 && p_depth < cascade_far.w) {
+                                return lit_fraction(shadow_map3, shadow_matrix3, pos);
+                            }
+                            return 1.0;
+                        }
+
+                        void main()
+                        {
+                            if (dot(vec4(p_world_pos, 1.0), clip_plane) < 0.0) {
+                                discard;
+                            }
+
+                            vec4 w = texture(splat, p_splat_pos);
+                            vec4 color = layer_color(tex0) * w.r
+                                + layer_color(tex1) * w.g
+                                + layer_color(tex2) * w.b
+                                + layer_color(tex3) * w.a;
+                            vec3 normal = normalize(p_normal);
+                            vec3 light = ambient + light_color * max(dot(normal, light_dir), 0.0) * shadow(normal);
+                            f_color = vec4(color.rgb * light, color.a);
+                        }
+                    "#,
+                }).expect("Error creating program"),
+            shadow_shader: program!(facade,
+                330 => {
+                    vertex: r#"
+                        #version 330
+
+                        in vec3 v_pos;
+
+                        uniform mat4 projview;
+                        uniform mat4 model;
+
+                        void main()
+                        {
+                            gl_Position = projview * model * vec4(v_pos, 1.0);
+                        }
+                    "#,
+                    fragment: r#"
+                        #version 330
+
+                        void main()
+                        {
+                        }
+                    "#,
+                }).expect("Error creating program"),
+            water: water,
+            sky: SkyParams {
+                day_length: config.sky.day_length,
+                start_hour: config.sky.start_hour,
+                latitude: config.sky.latitude.to_radians(),
+                declination: config.sky.declination.to_radians(),
+                fixed_direction: config.sky.fixed_direction,
+            },
+            stats: RenderStats::default(),
+        })
+    }
+
+    /// Streams tiles around the camera, does nothing for the fixed terrain
+    pub fn update<F: Facade>(&mut self, facade: &F, eye: [f32; 3]) -> Result<(), MeshUploadError> {
+        match self.landscape {
+            Landscape::Streamed(ref mut streamer) => streamer.update(facade, eye),
+            Landscape::Fixed(_) => Ok(()),
+        }
+    }
+
+    /// Switches between the mesh's texture coordinates and triplanar mapping
+    pub fn toggle_triplanar(&mut self) {
+        self.triplanar = !self.triplanar;
+    }
+
+    pub fn stats(&self) -> RenderStats {
+        self.stats
+    }
+
+    pub fn render<F: Facade, S: Surface>(&mut self, facade: &F, target: &mut S, projview: &Matrix4<f32>, eye: [f32; 3], time: f32) {
+        let lod_params = LodParams {
+            max_pixel_error: self.max_pixel_error,
+            viewport_height: target.get_dimensions().1 as f32,
+            fovy: self.fovy,
+        };
+
+        let (selected, edges) = match self.landscape {
+            Landscape::Fixed(ref mut fixed) => fixed.select(facade, eye, &lod_params),
+            Landscape::Streamed(_) => (Vec::new(), Vec::new()),
+        };
+
+        let sky = self.sky.at(time);
+        let clear = (sky.clear_color[0], sky.clear_color[1], sky.clear_color[2], 1.0);
+
+        if let Some(ref mut water) = self.water {
+            water.resize(facade, target.get_dimensions());
+        }
+
+        let stats = {
+            let parts = match self.landscape {
+                Landscape::Streamed(ref streamer) => {
+                    let size = streamer.tile_size();
+                    streamer.tiles().values()
+                        .map(|tile| Part {
+                            mesh: &tile.mesh,
+                            origin: tile.origin,
+                            min: [tile.origin[0], tile.min_height, tile.origin[1]],
+                            max: [tile.origin[0] + size, tile.max_height, tile.origin[1] + size],
+                            splat: &tile.splat,
+                            splat_transform: tile.splat_transform,
+                        })
+                        .collect::<Vec<_>>()
+                },
+                Landscape::Fixed(ref fixed) => {
+                    selected.iter().zip(edges.iter())
+                        .map(|(&id, &edges)| {
+                            let (min, max) = fixed.lod.bounds(id);
+                            Part {
+                                mesh: fixed.stitched.get(&(id, edges)).map(|s| &s.0).unwrap_or(&fixed.chunks[id]),
+                                origin: [0.0, 0.0],
+                                min: min,
+                                max: max,
+                                splat: &fixed.splat_tex,
+                                splat_transform: fixed.splat_transform,
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                },
+            };
+
+            let cascades = if self.shadows.enabled {
+                let mut casters_min = [::std::f32::MAX; 3];
+                let mut casters_max = [::std::f32::MIN; 3];
+                for part in parts.iter() {
+                    for axis in 0..3 {
+                        casters_min[axis] = casters_min[axis].min(part.min[axis]);
+                        casters_max[axis] = casters_max[axis].max(part.max[axis]);
+                    }
                 }
-            })
+                let sc = &self.shadows;
+                let splits = shadow::cascade_splits(self.near, sc.distance, sc.cascades, sc.split_lambda);
+                shadow::cascades(projview, self.near, self.far, &splits, sky.light_dir, casters_min, casters_max, sc.map_size)
+            } else {
+                Vec::new()
+            };
+            self.draw_shadows(facade, &cascades, &parts);
+
+            // the targets are only drawn when the surface is
+            let water = match self.water {
+                Some(ref water) if water.visible(projview, eye, self.far) => Some(water),
+                _ => None,
+            };
+
+            if let Some(water) = water {
+                let reflected = *projview * water.reflection();
+                let mut reflection = water.reflection_target(facade);
+                reflection.clear_color_and_depth(clear, 1.0);
+                self.draw_terrain(&mut reflection, &reflected, &parts, &cascades, &sky, water.clip_above());
+
+                let mut refraction = water.refraction_target(facade);
+                refraction.clear_color_and_depth(clear, 1.0);
+                self.draw_terrain(&mut refraction, projview, &parts, &cascades, &sky, water.clip_below());
+            }
+
+            target.clear_color_and_depth(clear, 1.0);
+            let culled = self.draw_terrain(target, projview, &parts, &cascades, &sky, [0.0, 0.0, 0.0, 1.0]);
+            if let Some(water) = water {
+                water.draw(target, projview, eye, sky.light_dir, sky.light_color, time, self.near, self.far);
+            }
+
+            RenderStats {
+                chunks_selected: parts.len(),
+                chunks_culled: culled,
+                tiles_in_flight: match self.landscape {
+                    Landscape::Streamed(ref streamer) => streamer.in_flight(),
+                    Landscape::Fixed(_) => 0,
+                },
+            }
+        };
+        self.stats = stats;
     }
 
-    pub fn render<S: Surface>(&mut self, target: &mut S, projview: &[[f32; 4]; 4], _time: f32) {
-        let uniforms = uniform! {
-            projview: *projview,
-            model: [
-                [1.0, 0.0, 0.0, 0.0],
-                [0.0, 1.0, 0.0, 0.0],
-                [0.0, 0.0, 1.0, 0.0],
-                [0.0, 0.0, 0.0, 1.0_f32],
-            ],
-            light_dir: [
-                0.3,
-                0.4,
-                0.1f32,
-            ],
-            tex: &self.terrain_tex,
+    /// Draws the parts that intersect the view frustum of `projview` and returns how many were culled.
+    /// Only what is on the positive side of `clip_plane` is drawn.
+    fn draw_terrain<S: Surface>(&self, target: &mut S, projview: &Matrix4<f32>, parts: &[Part], cascades: &[Cascade],
+            sky: &SkyState, clip_plane: [f32; 4]) -> usize {
+        let draw_params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLess,
+                write: true,
+                .. Default::default()
+            },
+            // separates the strips of `IndexLayout::Strips`
+            primitive_restart_index: true,
+            .. Default::default()
         };
 
+        let cascade = |i: usize| -> [[f32; 4]; 4] {
+            cascades.get(i).or(cascades.last()).map(|c| c.projview).unwrap_or(Matrix4::identity()).into()
+        };
+        let mut cascade_far = [0.0; 4];
+        for (far, c) in cascade_far.iter_mut().zip(cascades.iter()) {
+            *far = c.far;
+        }
+
+        let frustum = Frustum::from_projview(projview);
+        let mut culled = 0;
+        for part in parts.iter() {
+            if !frustum.intersects_aabb(part.min, part.max) {
+                culled+= 1;
+                continue;
+            }
+
+            let uniforms = uniform! {
+                projview: *projview.as_ref(),
+                model: translation(part.origin),
+                light_dir: sky.light_dir,
+                light_color: sky.light_color,
+                ambient: sky.ambient,
+                tex0: layer(&self.layer_tex, 0),
+                tex1: layer(&self.layer_tex, 1),
+                tex2: layer(&self.layer_tex, 2),
+                tex3: layer(&self.layer_tex, 3),
+                splat: part.splat.sampled().wrap_function(SamplerWrapFunction::Clamp),
+                splat_transform: part.splat_transform,
+                triplanar: self.triplanar,
+                triplanar_scale: self.triplanar_scale,
+                triplanar_sharpness: self.triplanar_sharpness,
+                cascade_count: cascades.len() as i32,
+                cascade_far: cascade_far,
+                shadow_matrix0: cascade(0),
+                shadow_matrix1: cascade(1),
+                shadow_matrix2: cascade(2),
+                shadow_matrix3: cascade(3),
+                shadow_map0: shadow_map(&self.shadow_maps, 0),
+                shadow_map1: shadow_map(&self.shadow_maps, 1),
+                shadow_map2: shadow_map(&self.shadow_maps, 2),
+                shadow_map3: shadow_map(&self.shadow_maps, 3),
+                shadow_bias: self.shadows.bias,
+                shadow_normal_bias: self.shadows.normal_bias,
+                pcf_radius: self.shadows.pcf_radius as i32,
+                clip_plane: clip_plane,
+            };
+            target.draw(&part.mesh.vbo, &part.mesh.ibo, &self.face_shader, &uniforms, &draw_params).expect("Error drawing");
+        }
+        culled
+    }
+
+    /// Draws the depth of the parts into the shadow map of each cascade, culled by the light's frustum
+    fn draw_shadows<F: Facade>(&self, facade: &F, cascades: &[Cascade], parts: &[Part]) {
         let draw_params = glium::DrawParameters {
             depth: glium::Depth {
                 test: glium::draw_parameters::DepthTest::IfLess,
                 write: true,
                 .. Default::default()
             },
+            primitive_restart_index: true,
             .. Default::default()
         };
 
-        target.clear_color_and_depth((0.0, 0.0, 0.0, 0.0), 1.0);
-        target.draw(&self.terrain.vbo, &self.terrain.ibo, &self.face_shader, &uniforms, &draw_params).expect("Error drawing");
-        if false {
-            target.draw(&self.normals.vbo, &self.normals.ibo, &self.line_shader, &uniforms, &draw_params).expect("Error drawing");
+        for (cascade, map) in cascades.iter().zip(self.shadow_maps.iter()) {
+            let mut target = SimpleFrameBuffer::depth_only(facade, map).expect("Error creating shadow framebuffer");
+            target.clear_depth(1.0);
+
+            let frustum = Frustum::from_projview(&cascade.projview);
+            for part in parts.iter() {
+                if !frustum.intersects_aabb(part.min, part.max) {
+                    continue;
+                }
+                let uniforms = uniform! {
+                    projview: *cascade.projview.as_ref(),
+                    model: translation(part.origin),
+                };
+                target.draw(&part.mesh.vbo, &part.mesh.ibo, &self.shadow_shader, &uniforms, &draw_params).expect("Error drawing");
+            }
         }
     }
 }
+
+/// Mesh drawn in a frame, a chunk of the fixed terrain or a streamed tile
+struct Part<'a> {
+    mesh: &'a UploadedMesh<FaceVertex>,
+    /// Translation of the mesh in the xz plane
+    origin: [f32; 2],
+    /// World bounds
+    min: [f32; 3],
+    max: [f32; 3],
+    splat: &'a Texture2d,
+    splat_transform: [f32; 4],
+}
+
+impl Ground for Renderer {
+    /// The rendered terrain surface, for walking on it
+    fn ground_at(&self, x: f32, z: f32) -> Option<(f32, [f32; 3])> {
+        match self.landscape {
+            Landscape::Streamed(ref streamer) => streamer.ground_at(x, z),
+            Landscape::Fixed(ref fixed) =>
+                TerrainSampler::new(&fixed.heights, fixed.sample_size, Interpolation::Triangles).ground_at(x, z),
+        }
+    }
+}
+
+/// Texture of the material layer, layers past the configured ones have zero weight.
+/// Meshes use continuous texture coordinates, so the textures repeat.
+fn layer(textures: &[Texture2d], i: usize) -> Sampler<Texture2d> {
+    textures[cmp::min(i, textures.len() - 1)].sampled().wrap_function(SamplerWrapFunction::Repeat)
+}
+
+/// Shadow map of the cascade, cascades past the used ones are never sampled
+fn shadow_map(maps: &[DepthTexture2d], i: usize) -> Sampler<DepthTexture2d> {
+    maps[cmp::min(i, maps.len() - 1)].sampled()
+        .wrap_function(SamplerWrapFunction::Clamp)
+        .magnify_filter(MagnifySamplerFilter::Nearest)
+        .minify_filter(MinifySamplerFilter::Nearest)
+}
+
+fn translation(xz: [f32; 2]) -> [[f32; 4]; 4] {
+    Matrix4::from_translation(Vector3::new(xz[0], 0.0, xz[1])).into()
+}
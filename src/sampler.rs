@@ -0,0 +1,308 @@
+use cg::{EuclideanVector, Vector3};
+
+use util::{Ground};
+use terrain::{Terrain};
+
+/// How heights between the samples are reconstructed
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interpolation {
+    /// Planar inside the two triangles of each cell, exactly the surface `mesh::chunk_mesh` draws
+    Triangles,
+    Bilinear,
+    /// Catmull-Rom, smooth across cell borders
+    Bicubic,
+}
+
+/// Height, normal and slope of the surface at one point
+#[derive(Copy, Clone, Debug)]
+pub struct SurfacePoint {
+    pub height: f32,
+    pub normal: [f32; 3],
+    /// Angle between the surface and the horizontal plane, in radians
+    pub slope: f32,
+}
+
+/// Queries the terrain at arbitrary world positions.
+/// Uses the same world scale as `mesh::chunk_mesh`: the sample `[i, j]` lies at
+/// world x = `i * sample_size[0]` and z = `j * sample_size[1]`.
+#[derive(Copy, Clone)]
+pub struct TerrainSampler<'a> {
+    pub terrain: &'a Terrain,
+    pub sample_size: [f32; 2],
+    pub interpolation: Interpolation,
+}
+
+impl<'a> TerrainSampler<'a> {
+    pub fn new(terrain: &'a Terrain, sample_size: [f32; 2], interpolation: Interpolation) -> TerrainSampler<'a> {
+        TerrainSampler {
+            terrain: terrain,
+            sample_size: sample_size,
+            interpolation: interpolation,
+        }
+    }
+
+    /// World size of the sampled area along x and z
+    pub fn extent(&self) -> [f32; 2] {
+        let dims = self.terrain.dims();
+        [(dims[0] - 1) as f32 * self.sample_size[0], (dims[1] - 1) as f32 * self.sample_size[1]]
+    }
+
+    /// Returns `None` outside of the terrain
+    pub fn height(&self, x: f32, z: f32) -> Option<f32> {
+        self.height_and_gradient(x, z).map(|(h, _)| h)
+    }
+
+    /// Unit normal pointing away from the ground
+    pub fn normal(&self, x: f32, z: f32) -> Option<[f32; 3]> {
+        self.sample(x, z).map(|p| p.normal)
+    }
+
+    pub fn slope(&self, x: f32, z: f32) -> Option<f32> {
+        self.sample(x, z).map(|p| p.slope)
+    }
+
+    pub fn sample(&self, x: f32, z: f32) -> Option<SurfacePoint> {
+        self.height_and_gradient(x, z).map(|(height, gradient)| {
+            let n = Vector3::new(-gradient[0], 1.0, -gradient[1]).normalize();
+            let steepness = (gradient[0] * gradient[0] + gradient[1] * gradient[1]).sqrt();
+            SurfacePoint {
+                height: height,
+                normal: [n.x, n.y, n.z],
+                slope: steepness.atan(),
+            }
+        })
+    }
+
+    /// Height and its derivatives along world x and z
+    pub fn height_and_gradient(&self, x: f32, z: f32) -> Option<(f32, [f32; 2])> {
+        let dims = self.terrain.dims();
+        if dims[0] < 2 || dims[1] < 2 {
+            return None;
+        }
+
+        let gx = x / self.sample_size[0];
+        let gz = z / self.sample_size[1];
+        if !(gx >= 0.0 && gz >= 0.0 && gx <= (dims[0] - 1) as f32 && gz <= (dims[1] - 1) as f32) {
+            return None;
+        }
+
+        // the far border belongs to the last cell
+        let cell = [
+            (gx as usize).min(dims[0] - 2),
+            (gz as usize).min(dims[1] - 2),
+        ];
+        let u = gx - cell[0] as f32;
+        let v = gz - cell[1] as f32;
+
+        let (h, du, dv) = match self.interpolation {
+            Interpolation::Triangles => self.triangles(cell, u, v),
+            Interpolation::Bilinear => self.bilinear(cell, u, v),
+            Interpolation::Bicubic => self.bicubic(cell, u, v),
+        };
+
+        Some((h, [du / self.sample_size[0], dv / self.sample_size[1]]))
+    }
+
+    /// Height at integer grid coordinates, clamped to the terrain
+    fn at(&self, x: i64, z: i64) -> f32 {
+        let dims = self.terrain.dims();
+        let x = x.max(0).min(dims[0] as i64 - 1) as usize;
+        let z = z.max(0).min(dims[1] as i64 - 1) as usize;
+        *self.terrain.get([x, z]).unwrap()
+    }
+
+    fn corners(&self, cell: [usize; 2]) -> [f32; 4] {
+        let (x, z) = (cell[0] as i64, cell[1] as i64);
+        [self.at(x, z), self.at(x + 1, z), self.at(x, z + 1), self.at(x + 1, z + 1)]
+    }
+
+    /// `mesh::grid_indices` splits each cell along the diagonal from `[1, 0]` to `[0, 1]`
+    fn triangles(&self, cell: [usize; 2], u: f32, v: f32) -> (f32, f32, f32) {
+        let c = self.corners(cell);
+        let (h00, h10, h01, h11) = (c[0], c[1], c[2], c[3]);
+        if u + v <= 1.0 {
+            let (du, dv) = (h10 - h00, h01 - h00);
+            (h00 + du * u + dv * v, du, dv)
+        } else {
+            let (du, dv) = (h11 - h01, h11 - h10);
+            (h11 - du * (1.0 - u) - dv * (1.0 - v), du, dv)
+        }
+    }
+
+    fn bilinear(&self, cell: [usize; 2], u: f32, v: f32) -> (f32, f32, f32) {
+        let c = self.corners(cell);
+        let (h00, h10, h01, h11) = (c[0], c[1], c[2], c[3]);
+        let h =
+            h00 * (1.0 - u) * (1.0 - v) +
+            h10 * u * (1.0 - v) +
+            h01 * (1.0 - u) * v +
+            h11 * u * v;
+        let du = (h10 - h00) * (1.0 - v) + (h11 - h01) * v;
+        let dv = (h01 - h00) * (1.0 - u) + (h11 - h10) * u;
+        (h, du, dv)
+    }
+
+    fn bicubic(&self, cell: [usize; 2], u: f32, v: f32) -> (f32, f32, f32) {
+        let (x, z) = (cell[0] as i64, cell[1] as i64);
+
+        let mut rows = [0.0; 4];
+        let mut row_derivs = [0.0; 4];
+        for j in 0..4 {
+            let zj = z + j as i64 - 1;
+            let p = [self.at(x - 1, zj), self.at(x, zj), self.at(x + 1, zj), self.at(x + 2, zj)];
+            rows[j] = catmull_rom(p, u);
+            row_derivs[j] = catmull_rom_deriv(p, u);
+        }
+
+        (catmull_rom(rows, v), catmull_rom(row_derivs, v), catmull_rom_deriv(rows, v))
+    }
+}
+
+impl<'a> Ground for TerrainSampler<'a> {
+    fn ground_at(&self, x: f32, z: f32) -> Option<(f32, [f32; 3])> {
+        self.sample(x, z).map(|p| (p.height, p.normal))
+    }
+}
+
+/// Interpolates between `p[1]` and `p[2]`
+fn catmull_rom(p: [f32; 4], t: f32) -> f32 {
+    0.5 * (
+        2.0 * p[1] +
+        (p[2] - p[0]) * t +
+        (2.0 * p[0] - 5.0 * p[1] + 4.0 * p[2] - p[3]) * t * t +
+        (3.0 * p[1] - p[0] - 3.0 * p[2] + p[3]) * t * t * t
+    )
+}
+
+fn catmull_rom_deriv(p: [f32; 4], t: f32) -> f32 {
+    0.5 * (
+        (p[2] - p[0]) +
+        2.0 * (2.0 * p[0] - 5.0 * p[1] + 4.0 * p[2] - p[3]) * t +
+        3.0 * (3.0 * p[1] - p[0] - 3.0 * p[2] + p[3]) * t * t
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use util::{Mat, FixedHeight};
+    use mesh::{self, IndexLayout};
+    use super::*;
+
+    const ALL: [Interpolation; 3] = [Interpolation::Triangles, Interpolation::Bilinear, Interpolation::Bicubic];
+
+    /// `w` by `h` samples of `f(x, z)` at the grid coordinates
+    fn terrain<F>(w: usize, h: usize, f: F) -> Terrain where F: Fn(f32, f32) -> f32 {
+        Mat {
+            vec: (0..w * h).map(|i| f((i / h) as f32, (i % h) as f32)).collect(),
+            fixed_dim: FixedHeight::from_height(h).unwrap(),
+        }
+    }
+
+    /// Irregular heights
+    fn bumps(x: f32, z: f32) -> f32 {
+        (x * 1.3).sin() * 2.0 + (z * 0.7 + x * 0.4).cos() * 3.0
+    }
+
+    fn assert_near(a: f32, b: f32, eps: f32) {
+        assert!((a - b).abs() < eps, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn grid_points_return_the_samples() {
+        let t = terrain(6, 5, bumps);
+        for &interpolation in ALL.iter() {
+            let sampler = TerrainSampler::new(&t, [2.0, 0.5], interpolation);
+            for x in 0..6 {
+                for z in 0..5 {
+                    let h = sampler.height(x as f32 * 2.0, z as f32 * 0.5).unwrap();
+                    assert_near(h, bumps(x as f32, z as f32), 1e-5);
+                }
+            }
+            assert_eq!(sampler.extent(), [10.0, 2.0]);
+            assert!(sampler.height(-0.01, 1.0).is_none());
+            assert!(sampler.height(5.0, 2.01).is_none());
+        }
+    }
+
+    #[test]
+    fn planes_are_exact() {
+        // world units, the samples are 2 by 0.5 apart
+        let plane = |x: f32, z: f32| 2.0 + 0.5 * x - 0.25 * z;
+        let t = terrain(8, 8, |x, z| plane(x * 2.0, z * 0.5));
+        let gradient = [0.5, -0.25];
+        let len = (gradient[0] * gradient[0] + gradient[1] * gradient[1] + 1.0f32).sqrt();
+        let normal = [-gradient[0] / len, 1.0 / len, -gradient[1] / len];
+        let slope = (gradient[0] * gradient[0] + gradient[1] * gradient[1]).sqrt().atan();
+
+        for &interpolation in ALL.iter() {
+            let sampler = TerrainSampler::new(&t, [2.0, 0.5], interpolation);
+            // bicubic clamps at the border, which bends the plane in the outer cells
+            for &(x, z) in [(2.3, 0.7), (5.9, 1.9), (8.0, 2.5), (11.1, 2.95), (4.0, 1.5)].iter() {
+                let p = sampler.sample(x, z).unwrap();
+                assert_near(p.height, plane(x, z), 1e-4);
+                for axis in 0..3 {
+                    assert_near(p.normal[axis], normal[axis], 1e-5);
+                }
+                assert_near(p.slope, slope, 1e-5);
+                assert_near(sampler.slope(x, z).unwrap(), slope, 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn bicubic_reproduces_quadratics() {
+        let quadratic = |x: f32, z: f32| 0.1 * x * x - 0.05 * z * z + 0.02 * x * z + x;
+        let t = terrain(8, 8, &quadratic);
+        let sampler = TerrainSampler::new(&t, [1.0, 1.0], Interpolation::Bicubic);
+        for &(x, z) in [(1.5, 1.5), (2.25, 4.8), (5.9, 3.1), (3.0, 5.5)].iter() {
+            let (h, gradient) = sampler.height_and_gradient(x, z).unwrap();
+            assert_near(h, quadratic(x, z), 1e-4);
+            assert_near(gradient[0], 0.2 * x + 0.02 * z + 1.0, 1e-4);
+            assert_near(gradient[1], -0.1 * z + 0.02 * x, 1e-4);
+        }
+    }
+
+    #[test]
+    fn bicubic_is_smooth_across_cells() {
+        let t = terrain(8, 8, bumps);
+        let sampler = TerrainSampler::new(&t, [1.0, 1.0], Interpolation::Bicubic);
+        let eps = 1e-3;
+        for &(x, z) in [(3.0, 2.4), (4.0, 5.7), (1.6, 2.0), (5.0, 3.0)].iter() {
+            let (h_before, g_before) = sampler.height_and_gradient(x - eps, z - eps).unwrap();
+            let (h_after, g_after) = sampler.height_and_gradient(x + eps, z + eps).unwrap();
+            assert_near(h_before, h_after, 0.05);
+            assert_near(g_before[0], g_after[0], 0.05);
+            assert_near(g_before[1], g_after[1], 0.05);
+        }
+
+        // bilinear has a kink at the cell border
+        let sampler = TerrainSampler::new(&t, [1.0, 1.0], Interpolation::Bilinear);
+        let (_, g_before) = sampler.height_and_gradient(3.0 - eps, 2.4).unwrap();
+        let (_, g_after) = sampler.height_and_gradient(3.0 + eps, 2.4).unwrap();
+        assert!((g_before[0] - g_after[0]).abs() > 0.1);
+    }
+
+    #[test]
+    fn triangles_follow_the_mesh_diagonal() {
+        // only the `[1, 1]` corner is raised, so the triangle with the other three is flat
+        let t = terrain(2, 2, |x, z| if x == 1.0 && z == 1.0 { 1.0 } else { 0.0 });
+        let sampler = TerrainSampler::new(&t, [1.0, 1.0], Interpolation::Triangles);
+        assert_near(sampler.height(0.4, 0.4).unwrap(), 0.0, 1e-6);
+        assert_near(sampler.height(0.6, 0.6).unwrap(), 0.2, 1e-6);
+
+        // the centroids of the mesh's triangles lie on them
+        let t = terrain(3, 4, bumps);
+        let sampler = TerrainSampler::new(&t, [1.0, 1.0], Interpolation::Triangles);
+        let inds = mesh::grid_indices(3, 4, IndexLayout::Triangles);
+        for tri in inds.chunks(3) {
+            // vertices are stored x-major like the terrain
+            let corners = tri.iter()
+                .map(|&i| ((i / 4) as f32, (i % 4) as f32))
+                .collect::<Vec<_>>();
+            let x = corners.iter().fold(0.0, |acc, c| acc + c.0) / 3.0;
+            let z = corners.iter().fold(0.0, |acc, c| acc + c.1) / 3.0;
+            let h = corners.iter().fold(0.0, |acc, c| acc + bumps(c.0, c.1)) / 3.0;
+            assert_near(sampler.height(x, z).unwrap(), h, 1e-5);
+        }
+    }
+}
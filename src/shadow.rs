@@ -0,0 +1,261 @@
+use cg::{SquareMatrix, Matrix4, Point3, Vector4};
+
+/// The face shader samples at most this many shadow maps
+pub const MAX_CASCADES: usize = 4;
+
+/// Shadow map of one part of the view frustum
+#[derive(Copy, Clone, Debug)]
+pub struct Cascade {
+    /// World to light clip space, an orthographic projection along the light
+    pub projview: Matrix4<f32>,
+    /// The cascade is used for fragments up to this view depth
+    pub far: f32,
+}
+
+/// View depths where the cascades end, the last one is `far`. `lambda` blends between uniform
+/// (0) and logarithmic (1) splits, the practical split scheme of Zhang et al.
+pub fn cascade_splits(near: f32, far: f32, count: usize, lambda: f32) -> Vec<f32> {
+    (1..count + 1)
+        .map(|i| {
+            let f = i as f32 / count as f32;
+            let log = near * (far / near).powf(f);
+            let uniform = near + (far - near) * f;
+            lambda * log + (1.0 - lambda) * uniform
+        })
+        .collect()
+}
+
+/// World space corners of the part of the view frustum between the view depths `from` and `to`,
+/// the four at `from` first. `near` and `far` are the planes `projview` was built with.
+/// Returns `None` if `projview` can't be inverted.
+pub fn slice_corners(projview: &Matrix4<f32>, near: f32, far: f32, from: f32, to: f32) -> Option<[[f32; 3]; 8]> {
+    let inv = match projview.invert() {
+        Some(inv) => inv,
+        None => return None,
+    };
+
+    // points along the edges of the frustum are linear in view depth
+    let t = [(from - near) / (far - near), (to - near) / (far - near)];
+    let mut corners = [[0.0; 3]; 8];
+    for (i, &(x, y)) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].iter().enumerate() {
+        let a = project(&inv, [x, y, -1.0]);
+        let b = project(&inv, [x, y, 1.0]);
+        for k in 0..2 {
+            for axis in 0..3 {
+                corners[k * 4 + i][axis] = a[axis] + (b[axis] - a[axis]) * t[k];
+            }
+        }
+    }
+    Some(corners)
+}
+
+/// Orthographic projection along `light_dir` (pointing towards the light) covering the points.
+/// The covered square fits the bounding sphere of the points and moves in whole texels of a
+/// `map_size` map, so the shadow edges don't shimmer when the camera moves or turns. Depth
+/// reaches towards the light up to the box `casters_min`, `casters_max`, so everything that can
+/// cast a shadow onto the points is drawn into the map.
+pub fn light_projview(light_dir: [f32; 3], points: &[[f32; 3]], casters_min: [f32; 3], casters_max: [f32; 3],
+        map_size: u32) -> Matrix4<f32> {
+    let (right, up, back) = light_basis(light_dir);
+    let to_light = |p: [f32; 3]| [dot(p, right), dot(p, up), dot(p, back)];
+
+    let mut center = [0.0; 3];
+    for p in points.iter() {
+        for axis in 0..3 {
+            center[axis]+= p[axis] / points.len() as f32;
+        }
+    }
+    let radius = points.iter()
+        .map(|&p| {
+            let d = [p[0] - center[0], p[1] - center[1], p[2] - center[2]];
+            dot(d, d).sqrt()
+        })
+        .fold(0.0f32, |acc, r| acc.max(r))
+        .max(1e-3);
+    // rounded, so the size doesn't jitter with the precision of the corners, and a texel larger
+    // than the sphere, which the snapping may move by up to a texel
+    let radius = (radius * 16.0).ceil() / 16.0;
+    let half = radius * map_size as f32 / (map_size as f32 - 2.0).max(1.0);
+
+    let texel = 2.0 * half / map_size as f32;
+    let c = to_light(center);
+    let x = (c[0] / texel).floor() * texel;
+    let y = (c[1] / texel).floor() * texel;
+
+    let mut nearest = c[2] + radius;
+    for i in 0..8 {
+        let corner = [
+            if i & 1 == 0 { casters_min[0] } else { casters_max[0] },
+            if i & 2 == 0 { casters_min[1] } else { casters_max[1] },
+            if i & 4 == 0 { casters_min[2] } else { casters_max[2] },
+        ];
+        nearest = nearest.max(dot(corner, back));
+    }
+    let furthest = c[2] - radius;
+
+    // light space x and y to [-1, 1], distance towards the light from [furthest, nearest] to [1, -1]
+    let sx = 1.0 / half;
+    let sz = -2.0 / (nearest - furthest);
+    let oz = 1.0 - sz * furthest;
+    Matrix4::new(
+        right[0] * sx, up[0] * sx, back[0] * sz, 0.0,
+        right[1] * sx, up[1] * sx, back[1] * sz, 0.0,
+        right[2] * sx, up[2] * sx, back[2] * sz, 0.0,
+        -x * sx, -y * sx, oz, 1.0,
+    )
+}
+
+/// One cascade per split, `splits` as returned by `cascade_splits`
+pub fn cascades(projview: &Matrix4<f32>, near: f32, far: f32, splits: &[f32], light_dir: [f32; 3],
+        casters_min: [f32; 3], casters_max: [f32; 3], map_size: u32) -> Vec<Cascade> {
+    let mut from = near;
+    let mut cascades = Vec::with_capacity(splits.len());
+    for &to in splits.iter() {
+        if let Some(corners) = slice_corners(projview, near, far, from, to) {
+            cascades.push(Cascade {
+                projview: light_projview(light_dir, &corners, casters_min, casters_max, map_size),
+                far: to,
+            });
+        }
+        from = to;
+    }
+    cascades
+}
+
+/// Unit vectors of light space, the last one points towards the light
+fn light_basis(light_dir: [f32; 3]) -> ([f32; 3], [f32; 3], [f32; 3]) {
+    let back = normalize(light_dir);
+    let up = if back[1].abs() < 0.99 { [0.0, 1.0, 0.0] } else { [1.0, 0.0, 0.0] };
+    let right = normalize(cross(up, back));
+    (right, cross(back, right), back)
+}
+
+/// Transforms a point and divides by w
+fn project(m: &Matrix4<f32>, p: [f32; 3]) -> [f32; 3] {
+    let p = Point3::from_homogeneous(*m * Vector4::new(p[0], p[1], p[2], 1.0));
+    [p.x, p.y, p.z]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let len = dot(v, v).sqrt();
+    [v[0] / len, v[1] / len, v[2] / len]
+}
+
+#[cfg(test)]
+mod tests {
+    use cg::{self, Matrix4, Point3, Vector3};
+
+    use super::*;
+
+    const NEAR: f32 = 0.5;
+    const FAR: f32 = 200.0;
+
+    fn camera(eye: [f32; 3]) -> Matrix4<f32> {
+        let proj = cg::perspective(cg::deg(60.0), 1.5, NEAR, FAR);
+        let view = Matrix4::look_at(Point3::new(eye[0], eye[1], eye[2]),
+            Point3::new(eye[0] + 3.0, eye[1] - 1.0, eye[2] - 4.0), Vector3::new(0.0, 1.0, 0.0));
+        proj * view
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() <= 1e-4 * b.abs().max(1.0), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn splits_end_at_far() {
+        for &lambda in [0.0, 0.3, 0.75, 1.0].iter() {
+            for count in 1..MAX_CASCADES + 1 {
+                let splits = cascade_splits(NEAR, FAR, count, lambda);
+                assert_eq!(splits.len(), count);
+                assert_close(*splits.last().unwrap(), FAR);
+                assert!(splits[0] > NEAR);
+                for w in splits.windows(2) {
+                    assert!(w[0] < w[1], "{:?}", splits);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lambda_picks_the_scheme() {
+        let uniform = cascade_splits(NEAR, FAR, 4, 0.0);
+        let log = cascade_splits(NEAR, FAR, 4, 1.0);
+        for i in 0..4 {
+            let f = (i + 1) as f32 / 4.0;
+            assert_close(uniform[i], NEAR + (FAR - NEAR) * f);
+            assert_close(log[i], NEAR * (FAR / NEAR).powf(f));
+        }
+    }
+
+    #[test]
+    fn first_slice_starts_at_near() {
+        let eye = [10.0, 20.0, 30.0];
+        let projview = camera(eye);
+        let splits = cascade_splits(NEAR, FAR, 3, 0.75);
+        let corners = slice_corners(&projview, NEAR, FAR, NEAR, splits[0]).unwrap();
+        for c in corners[..4].iter() {
+            assert_close(project(&projview, *c)[2], -1.0);
+        }
+        let last = slice_corners(&projview, NEAR, FAR, splits[1], splits[2]).unwrap();
+        for c in last[4..].iter() {
+            assert_close(project(&projview, *c)[2], 1.0);
+        }
+    }
+
+    #[test]
+    fn slices_fit_their_cascades() {
+        let projview = camera([10.0, 20.0, 30.0]);
+        let splits = cascade_splits(NEAR, FAR, 4, 0.75);
+        let (casters_min, casters_max) = ([-500.0, -50.0, -500.0], [500.0, 100.0, 500.0]);
+
+        for &light in [[0.3, 0.8, -0.2], [0.0, 1.0, 0.0], [-0.9, 0.1, 0.4]].iter() {
+            let cascades = cascades(&projview, NEAR, FAR, &splits, light, casters_min, casters_max, 1024);
+            assert_eq!(cascades.len(), 4);
+
+            let mut from = NEAR;
+            for (cascade, &to) in cascades.iter().zip(splits.iter()) {
+                assert_eq!(cascade.far, to);
+                for c in slice_corners(&projview, NEAR, FAR, from, to).unwrap().iter() {
+                    let p = project(&cascade.projview, *c);
+                    assert!(p.iter().all(|x| x.abs() <= 1.0 + 1e-4), "{:?} {:?}", light, p);
+                }
+                from = to;
+            }
+        }
+    }
+
+    #[test]
+    fn snapped_to_texels() {
+        let map_size = 1024;
+        let light = [0.3, 0.8, -0.2];
+        let (casters_min, casters_max) = ([-500.0, -50.0, -500.0], [500.0, 100.0, 500.0]);
+        let at = |eye: [f32; 3]| {
+            let projview = camera(eye);
+            let corners = slice_corners(&projview, NEAR, FAR, NEAR, 20.0).unwrap();
+            light_projview(light, &corners, casters_min, casters_max, map_size)
+        };
+
+        let before = at([10.0, 20.0, 30.0]);
+        let texel = 2.0 / map_size as f32;
+        for &delta in [0.001, 0.004, 0.013].iter() {
+            let after = at([10.0 + delta, 20.0, 30.0 - delta]);
+            assert_eq!(before.x.x, after.x.x);
+
+            // a fixed point moves on the map by whole texels, if at all
+            let p = [12.0, 5.0, 17.0];
+            let (a, b) = (project(&before, p), project(&after, p));
+            for axis in 0..2 {
+                let texels = (b[axis] - a[axis]) / texel;
+                assert!((texels - texels.round()).abs() < 0.01, "moved by {} texels", texels);
+            }
+        }
+    }
+}
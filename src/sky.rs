@@ -0,0 +1,203 @@
+use std::f32::consts::PI;
+
+/// Day/night cycle. World axes are x east, y up and z south.
+#[derive(Copy, Clone, Debug)]
+pub struct SkyParams {
+    /// Seconds of a full day, 0 stops the time at `start_hour`
+    pub day_length: f32,
+    /// Hour of the day when the program starts, 12 is noon
+    pub start_hour: f32,
+    /// Of the observer, in radians
+    pub latitude: f32,
+    /// Of the sun, the season, in radians. Positive in the northern summer.
+    pub declination: f32,
+    /// Direction towards the sun that is used instead of the cycle
+    pub fixed_direction: Option<[f32; 3]>,
+}
+
+/// Lighting at one moment
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SkyState {
+    /// Unit vector towards the sun
+    pub sun_dir: [f32; 3],
+    /// Unit vector towards the light that casts the shadows, the moon opposite of the sun at night
+    pub light_dir: [f32; 3],
+    pub light_color: [f32; 3],
+    /// Light from the whole sky, reaching shadowed surfaces too
+    pub ambient: [f32; 3],
+    /// Color of the sky behind the terrain
+    pub clear_color: [f32; 3],
+}
+
+impl SkyParams {
+    /// Hour of the day `time` seconds after the start, in `[0, 24)`
+    pub fn hour_at(&self, time: f32) -> f32 {
+        let elapsed = if self.day_length > 0.0 { time / self.day_length * 24.0 } else { 0.0 };
+        let hour = (self.start_hour + elapsed) % 24.0;
+        if hour < 0.0 { hour + 24.0 } else { hour }
+    }
+
+    pub fn at(&self, time: f32) -> SkyState {
+        match self.fixed_direction {
+            Some(dir) => {
+                let len = (dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2]).sqrt();
+                sky_state([dir[0] / len, dir[1] / len, dir[2] / len])
+            },
+            None => sky_state(sun_direction(self.hour_at(time), self.latitude, self.declination)),
+        }
+    }
+}
+
+/// Unit vector towards the sun at the hour of the (solar) day, from the hour angle
+pub fn sun_direction(hour: f32, latitude: f32, declination: f32) -> [f32; 3] {
+    let hour_angle = (hour - 12.0) / 24.0 * 2.0 * PI;
+    let (sin_lat, cos_lat) = (latitude.sin(), latitude.cos());
+    let (sin_dec, cos_dec) = (declination.sin(), declination.cos());
+
+    let east = -cos_dec * hour_angle.sin();
+    let north = cos_lat * sin_dec - sin_lat * cos_dec * hour_angle.cos();
+    let up = sin_lat * sin_dec + cos_lat * cos_dec * hour_angle.cos();
+    [east, up, -north]
+}
+
+/// Colors for the sun at `sun_dir`. The sunlight is reddened by the air it passes through and
+/// fades out around sunset, the moon takes over at night.
+pub fn sky_state(sun_dir: [f32; 3]) -> SkyState {
+    let elevation = sun_dir[1].max(-1.0).min(1.0).asin();
+
+    // relative air mass of Kasten and Young, clamped at the horizon
+    let degrees = elevation.to_degrees().max(0.0);
+    let air_mass = 1.0 / (elevation.max(0.0).sin() + 0.50572 * (degrees + 6.07995).powf(-1.6364));
+    let extinction = [0.08, 0.18, 0.38];
+    let day = smoothstep(0.0, 0.1, sun_dir[1]);
+    let sun = [
+        (-extinction[0] * air_mass).exp() * day,
+        (-extinction[1] * air_mass).exp() * day,
+        (-extinction[2] * air_mass).exp() * day,
+    ];
+
+    let night = 1.0 - smoothstep(-0.2, 0.0, sun_dir[1]);
+    let moon = [0.06 * night, 0.07 * night, 0.11 * night];
+
+    // both lights are off when the sun is at the horizon, so switching between them doesn't jump
+    let (light_dir, light_color) = if sun_dir[1] >= 0.0 {
+        (sun_dir, sun)
+    } else {
+        ([-sun_dir[0], -sun_dir[1], -sun_dir[2]], moon)
+    };
+
+    // blue at day, red around sunrise and sunset, dark blue at night
+    let daylight = smoothstep(-0.15, 0.3, sun_dir[1]);
+    let dusk = (1.0 - (sun_dir[1].abs() / 0.25).min(1.0)) * smoothstep(-0.2, 0.0, sun_dir[1]);
+    let mix = |night: [f32; 3], day: [f32; 3], dusk_color: [f32; 3]| {
+        let mut c = [0.0; 3];
+        for i in 0..3 {
+            c[i] = (night[i] + (day[i] - night[i]) * daylight) * (1.0 - dusk * 0.6) + dusk_color[i] * dusk * 0.6;
+        }
+        c
+    };
+
+    SkyState {
+        sun_dir: sun_dir,
+        light_dir: light_dir,
+        light_color: light_color,
+        ambient: mix([0.02, 0.025, 0.05], [0.25, 0.3, 0.38], [0.3, 0.2, 0.18]),
+        clear_color: mix([0.01, 0.012, 0.03], [0.45, 0.65, 0.9], [0.9, 0.45, 0.25]),
+    }
+}
+
+fn smoothstep(from: f32, to: f32, x: f32) -> f32 {
+    let t = ((x - from) / (to - from)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_dir(dir: [f32; 3], expected: [f32; 3]) {
+        for (a, b) in dir.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-5, "{:?} != {:?}", dir, expected);
+        }
+    }
+
+    fn length(v: [f32; 3]) -> f32 {
+        (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+    }
+
+    #[test]
+    fn sun_at_the_equator() {
+        assert_dir(sun_direction(12.0, 0.0, 0.0), [0.0, 1.0, 0.0]);
+        // rises in the east, sets in the west, below the feet at midnight
+        assert_dir(sun_direction(6.0, 0.0, 0.0), [1.0, 0.0, 0.0]);
+        assert_dir(sun_direction(18.0, 0.0, 0.0), [-1.0, 0.0, 0.0]);
+        assert_dir(sun_direction(0.0, 0.0, 0.0), [0.0, -1.0, 0.0]);
+    }
+
+    #[test]
+    fn noon_sun_is_south_in_the_north() {
+        let (lat, dec) = (45.0f32.to_radians(), 10.0f32.to_radians());
+        let noon = sun_direction(12.0, lat, dec);
+        // elevation is 90 degrees minus the latitude plus the declination, towards positive z
+        let elevation = 55.0f32.to_radians();
+        assert_dir(noon, [0.0, elevation.sin(), elevation.cos()]);
+    }
+
+    #[test]
+    fn sun_direction_is_unit() {
+        for &lat in [-80.0f32, -30.0, 0.0, 45.0, 90.0].iter() {
+            for &dec in [-23.5f32, 0.0, 10.0, 23.5].iter() {
+                for i in 0..48 {
+                    let dir = sun_direction(i as f32 * 0.5, lat.to_radians(), dec.to_radians());
+                    assert!((length(dir) - 1.0).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hour_wraps() {
+        let sky = SkyParams { day_length: 240.0, start_hour: 10.0, latitude: 0.0, declination: 0.0, fixed_direction: None };
+        assert_eq!(sky.hour_at(0.0), 10.0);
+        assert_eq!(sky.hour_at(140.0), 0.0);
+        assert_eq!(sky.hour_at(-60.0), 4.0);
+        assert_eq!(sky.hour_at(-240.0 * 3.0 - 120.0), 22.0);
+        assert!((sky.hour_at(240.0 * 1000.0 + 60.0) - 16.0).abs() < 1e-2);
+        for i in -50..50 {
+            let hour = sky.hour_at(i as f32 * 37.3);
+            assert!(hour >= 0.0 && hour < 24.0);
+        }
+
+        let stopped = SkyParams { day_length: 0.0, start_hour: 30.0, .. sky };
+        assert_eq!(stopped.hour_at(0.0), 6.0);
+        assert_eq!(stopped.hour_at(1e6), 6.0);
+        assert_eq!(stopped.hour_at(-5.0), 6.0);
+    }
+
+    #[test]
+    fn fixed_direction_stops_the_cycle() {
+        let sky = SkyParams { day_length: 240.0, start_hour: 10.0, latitude: 0.0, declination: 0.0,
+            fixed_direction: Some([0.0, 3.0, 4.0]) };
+        assert_eq!(sky.at(0.0), sky.at(130.0));
+        assert_dir(sky.at(0.0).sun_dir, [0.0, 0.6, 0.8]);
+    }
+
+    #[test]
+    fn light_is_continuous_at_the_horizon() {
+        let state = |y: f32| sky_state([(1.0 - y * y).sqrt(), y, 0.0]);
+        let max_diff = |a: [f32; 3], b: [f32; 3]| (0..3).fold(0.0f32, |acc, i| acc.max((a[i] - b[i]).abs()));
+
+        let (above, below) = (state(1e-4), state(-1e-4));
+        assert!(max_diff(above.light_color, below.light_color) < 1e-3);
+        assert!(above.light_color.iter().chain(below.light_color.iter()).all(|&c| c < 1e-3));
+
+        let step = 1e-3;
+        for i in -300..300 {
+            let (a, b) = (state(i as f32 * step), state((i + 1) as f32 * step));
+            assert!(max_diff(a.light_color, b.light_color) < 0.02, "light jumps at {}", i as f32 * step);
+            assert!(max_diff(a.ambient, b.ambient) < 0.02);
+            assert!(max_diff(a.clear_color, b.clear_color) < 0.02);
+            assert!((length(a.light_dir) - 1.0).abs() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use terrain::{Terrain};
+use mesh::{NormalMethod, vertex_normal};
+
+/// The face shader blends at most this many layers
+pub const MAX_LAYERS: usize = 4;
+
+/// Where a material layer covers the terrain. The layer has full weight inside both ranges and
+/// fades out over the blend widths outside of them.
+#[derive(Copy, Clone, Debug)]
+pub struct SplatRule {
+    /// Lowest and highest height
+    pub height: [f32; 2],
+    /// Flattest and steepest slope in radians
+    pub slope: [f32; 2],
+    pub height_blend: f32,
+    pub slope_blend: f32,
+}
+
+impl SplatRule {
+    /// Covers everything
+    pub fn everywhere() -> SplatRule {
+        SplatRule {
+            height: [::std::f32::MIN, ::std::f32::MAX],
+            slope: [0.0, ::std::f32::consts::PI],
+            height_blend: 0.0,
+            slope_blend: 0.0,
+        }
+    }
+
+    /// Weight before normalization, in `[0, 1]`
+    pub fn weight(&self, height: f32, slope: f32) -> f32 {
+        range_weight(height, self.height, self.height_blend) * range_weight(slope, self.slope, self.slope_blend)
+    }
+}
+
+/// Weights of the layers at a point, they sum up to 1. Where no layer covers the point the
+/// first one is used. Layers past `MAX_LAYERS` are ignored.
+pub fn splat_weights(rules: &[SplatRule], height: f32, slope: f32) -> [f32; MAX_LAYERS] {
+    let mut weights = [0.0; MAX_LAYERS];
+    for (w, rule) in weights.iter_mut().zip(rules.iter()) {
+        *w = rule.weight(height, slope);
+    }
+
+    let sum = weights.iter().fold(0.0, |acc, &w| acc + w);
+    if sum > 0.0 {
+        for w in weights.iter_mut() {
+            *w/= sum;
+        }
+    } else {
+        weights[0] = 1.0;
+    }
+    weights
+}
+
+/// Weights for every sample of the terrain, as RGBA bytes of an image that is `width` (x) by
+/// `height` (z) texels. The slope is taken from the central difference normals.
+pub fn splat_map(terrain: &Terrain, sample_size: [f32; 2], rules: &[SplatRule]) -> Vec<u8> {
+    let dims = terrain.dims();
+    let mut data = Vec::with_capacity(dims[0] * dims[1] * 4);
+
+    for z in 0..dims[1] {
+        for x in 0..dims[0] {
+            let normal = vertex_normal(terrain, sample_size, [x, z], 1, NormalMethod::CentralDifference);
+            let slope = normal[1].max(-1.0).min(1.0).acos();
+            let weights = splat_weights(rules, *terrain.get([x, z]).unwrap(), slope);
+            for &w in weights.iter() {
+                data.push((w * 255.0).round() as u8);
+            }
+        }
+    }
+    data
+}
+
+/// Maps positions in the space of the terrain's meshes to the texel centers of its splat map,
+/// `uv = pos.xz * [0, 1] + [2, 3]`
+pub fn splat_transform(dims: [usize; 2], sample_size: [f32; 2]) -> [f32; 4] {
+    [
+        1.0 / (dims[0] as f32 * sample_size[0]),
+        1.0 / (dims[1] as f32 * sample_size[1]),
+        0.5 / dims[0] as f32,
+        0.5 / dims[1] as f32,
+    ]
+}
+
+/// 1 inside the range, falling off smoothly to 0 within `blend` outside of it
+fn range_weight(x: f32, range: [f32; 2], blend: f32) -> f32 {
+    if x >= range[0] && x <= range[1] {
+        return 1.0;
+    }
+    if blend <= 0.0 {
+        return 0.0;
+    }
+
+    let dist = if x < range[0] { range[0] - x } else { x - range[1] };
+    let t = (1.0 - dist / blend).max(0.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use terrain::{Terrain};
+    use util::{Mat, FixedHeight};
+    use super::*;
+
+    fn rule(height: [f32; 2], slope: [f32; 2]) -> SplatRule {
+        SplatRule {
+            height: height,
+            slope: [slope[0].to_radians(), slope[1].to_radians()],
+            height_blend: 3.0,
+            slope_blend: 8.0f32.to_radians(),
+        }
+    }
+
+    /// Grass low and flat, stone high and flat, rock on steep slopes, like scene.toml
+    fn rules() -> Vec<SplatRule> {
+        vec![
+            rule([0.0, 16.0], [0.0, 30.0]),
+            rule([16.0, 1000.0], [0.0, 30.0]),
+            SplatRule { height: [::std::f32::MIN, ::std::f32::MAX], .. rule([0.0, 0.0], [30.0, 90.0]) },
+        ]
+    }
+
+    fn assert_weights(weights: [f32; MAX_LAYERS], expected: [f32; MAX_LAYERS]) {
+        for (w, e) in weights.iter().zip(expected.iter()) {
+            assert!((w - e).abs() < 1e-5, "{:?} != {:?}", weights, expected);
+        }
+    }
+
+    #[test]
+    fn rules_pick_layers() {
+        let rules = rules();
+        let deg = |d: f32| d.to_radians();
+        assert_weights(splat_weights(&rules, 10.0, 0.0), [1.0, 0.0, 0.0, 0.0]);
+        assert_weights(splat_weights(&rules, 300.0, deg(10.0)), [0.0, 1.0, 0.0, 0.0]);
+        assert_weights(splat_weights(&rules, 10.0, deg(60.0)), [0.0, 0.0, 1.0, 0.0]);
+
+        // on the boundaries both layers have full weight, past the blend width only one is left
+        assert_weights(splat_weights(&rules, 16.0, 0.0), [0.5, 0.5, 0.0, 0.0]);
+        assert_weights(splat_weights(&rules, 19.0, 0.0), [0.0, 1.0, 0.0, 0.0]);
+        assert_weights(splat_weights(&rules, 13.0, 0.0), [1.0, 0.0, 0.0, 0.0]);
+        assert_weights(splat_weights(&rules, 10.0, deg(30.0)), [0.5, 0.0, 0.5, 0.0]);
+        assert_weights(splat_weights(&rules, 10.0, deg(38.0)), [0.0, 0.0, 1.0, 0.0]);
+        assert_weights(splat_weights(&rules, 10.0, deg(22.0)), [1.0, 0.0, 0.0, 0.0]);
+
+        // halfway through the blend width the smoothstep is at one half
+        assert_weights(splat_weights(&rules, 17.5, deg(60.0)), [0.0, 0.0, 1.0, 0.0]);
+        assert_weights(splat_weights(&rules, 17.5, 0.0), [1.0 / 3.0, 2.0 / 3.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn uncovered_uses_first_layer() {
+        let rules = vec![rule([0.0, 1.0], [0.0, 10.0]), rule([5.0, 6.0], [0.0, 10.0])];
+        assert_weights(splat_weights(&rules, 100.0, 0.0), [1.0, 0.0, 0.0, 0.0]);
+        assert_weights(splat_weights(&[], 100.0, 0.0), [1.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn at_most_four_layers() {
+        let mut rules = (0..4).map(|i| rule([i as f32 * 10.0, i as f32 * 10.0 + 5.0], [0.0, 90.0])).collect::<Vec<_>>();
+        rules.push(rule([100.0, 200.0], [0.0, 90.0]));
+        // only the fifth layer covers this height
+        assert_weights(splat_weights(&rules, 150.0, 0.0), [1.0, 0.0, 0.0, 0.0]);
+        assert_weights(splat_weights(&rules, 32.0, 0.0), [0.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn map_weights_sum_to_one() {
+        let (w, h) = (12, 7);
+        let terrain: Terrain = Mat {
+            // low plain, cliff, high plain
+            vec: (0..w * h)
+                .map(|i| {
+                    let (x, z) = (i / h, (i % h) as f32 * 0.4);
+                    if x < 6 { z } else if x < 9 { (x - 5) as f32 * 5.0 + z } else { 22.0 + z }
+                })
+                .collect(),
+            fixed_dim: FixedHeight::from_height(h).unwrap(),
+        };
+        let map = splat_map(&terrain, [1.0, 1.0], &rules());
+        assert_eq!(map.len(), w * h * 4);
+
+        let mut used = [false; 3];
+        for (i, texel) in map.chunks(4).enumerate() {
+            let sum = texel.iter().fold(0, |acc, &b| acc + b as u32);
+            // each weight is rounded on its own
+            assert!(sum >= 253 && sum <= 257, "texel {} sums to {}", i, sum);
+            for k in 0..3 {
+                used[k] = used[k] || texel[k] > 0;
+            }
+
+            // rows along x
+            let (x, z) = (i % w, i / w);
+            let normal = vertex_normal(&terrain, [1.0, 1.0], [x, z], 1, NormalMethod::CentralDifference);
+            let weights = splat_weights(&rules(), *terrain.get([x, z]).unwrap(), normal[1].acos());
+            assert_eq!(texel[0], (weights[0] * 255.0).round() as u8);
+        }
+        assert_eq!(used, [true; 3]);
+    }
+}
@@ -0,0 +1,457 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+
+use glium::backend::{Facade};
+use glium::texture::{Texture2d, RawImage2d};
+
+use util::{NonZero, Ground, Mat, FixedHeight, FixedDimension};
+use terrain::{self, Terrain, Tiling, TileKey, DefaultSource};
+use height_source::{HeightSource};
+use sampler::{TerrainSampler, Interpolation};
+use mesh::{self, Mesh, UploadedMesh, FaceVertex, MeshUploadError, Seams, IndexLayout, NormalMethod};
+use splat::{self, SplatRule};
+
+#[derive(Copy, Clone, Debug)]
+pub struct StreamingParams {
+    pub seed: u32,
+    pub max_height: f32,
+    /// Noise units per world unit, like `terrain.area` over `terrain.size`
+    pub noise_scale: f32,
+    /// Noise position at the world origin
+    pub noise_origin: [f32; 2],
+    /// World edge length of a tile
+    pub tile_size: f32,
+    /// Cells per tile edge
+    pub cells: u32,
+    pub samples_per_tex: usize,
+    pub normal_method: NormalMethod,
+    pub index_layout: IndexLayout,
+    /// Tiles up to this many tiles away from the camera's tile are loaded
+    pub load_radius: u32,
+    /// Tiles further away than this are evicted, larger than `load_radius` to avoid thrashing
+    pub unload_radius: u32,
+    /// Upper bound of tiles kept in memory, the furthest are evicted first
+    pub max_tiles: usize,
+    /// Upper bound of tiles queued for or being generated at a time
+    pub max_in_flight: usize,
+    /// Tiles uploaded to the GPU per frame
+    pub uploads_per_frame: usize,
+    pub workers: usize,
+}
+
+/// A generated tile, positions of the mesh are relative to `origin`
+pub struct Tile {
+    pub origin: [f32; 2],
+    pub heights: Terrain,
+    pub mesh: UploadedMesh<FaceVertex>,
+    /// Material layer weights, see `splat::splat_map`
+    pub splat: Texture2d,
+    pub splat_transform: [f32; 4],
+    pub min_height: f32,
+    pub max_height: f32,
+}
+
+/// Result of a worker, everything but the upload
+struct BuiltTile {
+    key: TileKey,
+    heights: Terrain,
+    mesh: Mesh<FaceVertex>,
+    splat: Vec<u8>,
+}
+
+/// Keeps the tiles around the camera loaded. Tiles are generated and meshed on worker threads,
+/// the main thread only uploads them. A tile only depends on the seed and its key, so tiles
+/// that are evicted and loaded again come back identical.
+pub struct TileStreamer {
+    params: StreamingParams,
+    jobs: Option<Sender<TileKey>>,
+    results: Receiver<BuiltTile>,
+    workers: Vec<JoinHandle<()>>,
+    /// Requested and not yet resident
+    in_flight: HashSet<TileKey>,
+    /// Generated, waiting for the upload
+    ready: Vec<BuiltTile>,
+    tiles: HashMap<TileKey, Tile>,
+}
+
+impl TileStreamer {
+    pub fn new(params: StreamingParams, splat_rules: Vec<SplatRule>) -> TileStreamer {
+        let (job_tx, job_rx) = mpsc::channel::<TileKey>();
+        let (result_tx, result_rx) = mpsc::channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        let workers = (0..cmp::max(params.workers, 1))
+            .map(|_| {
+                let jobs = job_rx.clone();
+                let results = result_tx.clone();
+                let rules = splat_rules.clone();
+                thread::spawn(move || {
+                    let source = DefaultSource::new(params.seed);
+                    loop {
+                        let key = match jobs.lock().unwrap().recv() {
+                            Ok(key) => key,
+                            // the streamer was dropped
+                            Err(_) => break,
+                        };
+
+                        if results.send(build_tile(&source, &params, &rules, key)).is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        TileStreamer {
+            params: params,
+            jobs: Some(job_tx),
+            results: result_rx,
+            workers: workers,
+            in_flight: HashSet::new(),
+            ready: Vec::new(),
+            tiles: HashMap::new(),
+        }
+    }
+
+    pub fn tiles(&self) -> &HashMap<TileKey, Tile> {
+        &self.tiles
+    }
+
+    pub fn tile_size(&self) -> f32 {
+        self.params.tile_size
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// World position of the tile's lower corner
+    pub fn tile_origin(&self, key: TileKey) -> [f32; 2] {
+        [key.x as f32 * self.params.tile_size, key.y as f32 * self.params.tile_size]
+    }
+
+    /// Key of the tile containing the world position
+    pub fn tile_at(&self, x: f32, z: f32) -> TileKey {
+        TileKey {
+            x: (x / self.params.tile_size).floor() as i32,
+            y: (z / self.params.tile_size).floor() as i32,
+        }
+    }
+
+    /// Collects finished tiles, uploads some of them, evicts far tiles and requests missing ones,
+    /// nearest first. Called once per frame with the camera position.
+    pub fn update<F: Facade>(&mut self, facade: &F, eye: [f32; 3]) -> Result<(), MeshUploadError> {
+        let center = self.tile_at(eye[0], eye[2]);
+        let p = self.params;
+
+        while let Ok(built) = self.results.try_recv() {
+            if distance(built.key, center) <= p.unload_radius {
+                self.ready.push(built);
+            } else {
+                self.in_flight.remove(&built.key);
+            }
+        }
+
+        // nearest last, to pop them
+        self.ready.sort_by(|a, b| distance(b.key, center).cmp(&distance(a.key, center)));
+        for _ in 0..p.uploads_per_frame {
+            let built = match self.ready.pop() {
+                Some(built) => built,
+                None => break,
+            };
+            self.in_flight.remove(&built.key);
+
+            let mesh = try!(built.mesh.upload(facade));
+            let samples = self.params.cells + 1;
+            let splat = Texture2d::new(facade, RawImage2d::from_raw_rgba(built.splat, (samples, samples)))
+                .expect("Error uploading splat map");
+            let sample_size = self.params.tile_size / self.params.cells as f32;
+            let (min_height, max_height) = built.heights.vec.iter()
+                .fold((::std::f32::MAX, ::std::f32::MIN), |(min, max), &h| (min.min(h), max.max(h)));
+            let tile = Tile {
+                origin: self.tile_origin(built.key),
+                heights: built.heights,
+                mesh: mesh,
+                splat: splat,
+                splat_transform: splat::splat_transform([samples as usize; 2], [sample_size; 2]),
+                min_height: min_height,
+                max_height: max_height,
+            };
+            self.tiles.insert(built.key, tile);
+        }
+
+        self.evict(center);
+        self.request(center);
+        Ok(())
+    }
+
+    fn evict(&mut self, center: TileKey) {
+        let p = self.params;
+        let resident = self.tiles.keys().cloned().collect::<Vec<_>>();
+        for key in evicted_tiles(&p, center, &resident) {
+            self.tiles.remove(&key);
+        }
+
+        let (keep, stale): (Vec<_>, Vec<_>) = self.ready.drain(..)
+            .partition(|built| distance(built.key, center) <= p.unload_radius);
+        for built in stale {
+            self.in_flight.remove(&built.key);
+        }
+        self.ready = keep;
+    }
+
+    fn request(&mut self, center: TileKey) {
+        let jobs = match self.jobs {
+            Some(ref jobs) => jobs,
+            None => return,
+        };
+        let missing = requested_tiles(&self.params, center, &self.tiles.keys().cloned().collect(), &self.in_flight);
+        for key in missing {
+            if jobs.send(key).is_ok() {
+                self.in_flight.insert(key);
+            }
+        }
+    }
+}
+
+/// Generates, meshes and splats a tile. The normals and splat weights at its border see one
+/// sample of the neighbouring tiles, so they match theirs.
+fn build_tile<H: HeightSource>(source: &H, params: &StreamingParams, rules: &[SplatRule], key: TileKey) -> BuiltTile {
+    let with_apron = terrain::gen_tile(source, &tiling(params), key, params.max_height, 1);
+    let sample_size = params.tile_size / params.cells as f32;
+    let cells = params.cells as usize;
+
+    let mut mesh = mesh::chunk_mesh(&with_apron, [sample_size, sample_size], params.samples_per_tex,
+        [1, 1], [cells, cells], 1, Seams::None, params.index_layout, params.normal_method);
+    // the apron moved the tile by one sample
+    let tex_step = 1.0 / params.samples_per_tex as f32;
+    for v in mesh.verts.iter_mut() {
+        v.v_pos[0]-= sample_size;
+        v.v_pos[2]-= sample_size;
+        v.v_tex_pos[0]-= tex_step;
+        v.v_tex_pos[1]-= tex_step;
+    }
+
+    let samples = cells + 1;
+    let fixed_dim = FixedHeight::from_height(samples).unwrap();
+    let heights = Mat {
+        vec: fixed_dim.coords_iter()
+            .take(samples * samples)
+            .map(|coords| *with_apron.get([coords[0] + 1, coords[1] + 1]).unwrap())
+            .collect(),
+        fixed_dim: fixed_dim,
+    };
+    let apron_splat = splat::splat_map(&with_apron, [sample_size, sample_size], rules);
+    let mut splat = Vec::with_capacity(samples * samples * 4);
+    for z in 1..samples + 1 {
+        let start = (z * (samples + 2) + 1) * 4;
+        splat.extend_from_slice(&apron_splat[start..start + samples * 4]);
+    }
+
+    BuiltTile {
+        key: key,
+        heights: heights,
+        mesh: mesh,
+        splat: splat,
+    }
+}
+
+/// Resident tiles to drop with the camera in the tile `center`: those further than `unload_radius`
+/// and the furthest beyond `max_tiles`
+pub fn evicted_tiles(params: &StreamingParams, center: TileKey, resident: &[TileKey]) -> Vec<TileKey> {
+    let mut keys = resident.to_vec();
+    keys.sort_by(|&a, &b| (distance(a, center), a).cmp(&(distance(b, center), b)));
+
+    keys.into_iter()
+        .enumerate()
+        .filter(|&(i, key)| i >= params.max_tiles || distance(key, center) > params.unload_radius)
+        .map(|(_, key)| key)
+        .collect()
+}
+
+/// Tiles within `load_radius` of `center` that are neither resident nor in flight, nearest first
+/// and no more than fit into `max_in_flight`
+pub fn requested_tiles(params: &StreamingParams, center: TileKey, resident: &HashSet<TileKey>,
+        in_flight: &HashSet<TileKey>) -> Vec<TileKey> {
+    let r = params.load_radius as i32;
+    let mut missing = Vec::new();
+    for dx in -r..r + 1 {
+        for dy in -r..r + 1 {
+            let key = TileKey { x: center.x + dx, y: center.y + dy };
+            if !resident.contains(&key) && !in_flight.contains(&key) {
+                missing.push(key);
+            }
+        }
+    }
+    missing.sort_by(|&a, &b| (distance(a, center), a).cmp(&(distance(b, center), b)));
+    missing.truncate(params.max_in_flight.saturating_sub(in_flight.len()));
+    missing
+}
+
+impl Drop for TileStreamer {
+    fn drop(&mut self) {
+        // closing the channel ends the workers after their current tile
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Ground for TileStreamer {
+    /// Only resident tiles have ground
+    fn ground_at(&self, x: f32, z: f32) -> Option<(f32, [f32; 3])> {
+        self.tiles.get(&self.tile_at(x, z)).and_then(|tile| {
+            let sample_size = self.params.tile_size / self.params.cells as f32;
+            TerrainSampler::new(&tile.heights, [sample_size, sample_size], Interpolation::Triangles)
+                .ground_at(x - tile.origin[0], z - tile.origin[1])
+        })
+    }
+}
+
+/// The tiling in noise space, the tile with key `(0, 0)` starts at the world origin
+fn tiling(params: &StreamingParams) -> Tiling {
+    Tiling {
+        origin: params.noise_origin,
+        tile_size: params.tile_size * params.noise_scale,
+        cells: NonZero::new(params.cells).expect("Tiles need at least one cell"),
+    }
+}
+
+/// Chebyshev distance in tiles
+fn distance(a: TileKey, b: TileKey) -> u32 {
+    cmp::max((a.x - b.x).abs(), (a.y - b.y).abs()) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashSet};
+
+    use terrain::{TileKey, DefaultSource};
+    use mesh::{IndexLayout, NormalMethod};
+    use splat::{SplatRule};
+    use super::*;
+
+    fn params() -> StreamingParams {
+        StreamingParams {
+            seed: 3,
+            max_height: 40.0,
+            noise_scale: 4.0,
+            noise_origin: [12.0, -7.0],
+            tile_size: 32.0,
+            cells: 8,
+            samples_per_tex: 4,
+            normal_method: NormalMethod::CentralDifference,
+            index_layout: IndexLayout::Triangles,
+            load_radius: 1,
+            unload_radius: 2,
+            max_tiles: 16,
+            max_in_flight: 4,
+            uploads_per_frame: 2,
+            workers: 1,
+        }
+    }
+
+    fn key(x: i32, y: i32) -> TileKey {
+        TileKey { x: x, y: y }
+    }
+
+    #[test]
+    fn requests_nearest_first() {
+        let p = params();
+        let requested = requested_tiles(&p, key(5, -3), &HashSet::new(), &HashSet::new());
+        assert_eq!(requested, vec![key(5, -3), key(4, -4), key(4, -3), key(4, -2)]);
+    }
+
+    #[test]
+    fn requests_only_missing_tiles() {
+        let p = StreamingParams { max_in_flight: 20, .. params() };
+        let resident = vec![key(0, 0), key(1, 1)].into_iter().collect();
+        let in_flight = vec![key(-1, 0), key(7, 7)].into_iter().collect::<HashSet<_>>();
+        let requested = requested_tiles(&p, key(0, 0), &resident, &in_flight);
+        assert_eq!(requested.len(), 6);
+        assert!(requested.iter().all(|k| !resident.contains(k) && !in_flight.contains(k)));
+
+        // the tiles in flight use up the budget
+        let p = StreamingParams { max_in_flight: 3, .. p };
+        assert_eq!(requested_tiles(&p, key(0, 0), &resident, &in_flight), vec![key(-1, -1)]);
+        let p = StreamingParams { max_in_flight: 1, .. p };
+        assert!(requested_tiles(&p, key(0, 0), &resident, &in_flight).is_empty());
+    }
+
+    #[test]
+    fn evicts_beyond_unload_radius() {
+        let p = params();
+        let resident = vec![key(0, 0), key(2, -2), key(3, 0), key(-1, 5)];
+        // between the load and the unload radius tiles are kept
+        assert_eq!(evicted_tiles(&p, key(0, 0), &resident), vec![key(3, 0), key(-1, 5)]);
+        assert_eq!(evicted_tiles(&p, key(1, 2), &resident), vec![key(-1, 5), key(2, -2)]);
+        assert!(evicted_tiles(&p, key(2, 2), &resident[..1]).is_empty());
+    }
+
+    #[test]
+    fn evicts_furthest_beyond_max_tiles() {
+        let p = StreamingParams { max_tiles: 2, .. params() };
+        let resident = vec![key(1, 1), key(0, 0), key(0, 2), key(1, 0)];
+        assert_eq!(evicted_tiles(&p, key(0, 0), &resident), vec![key(1, 1), key(0, 2)]);
+    }
+
+    #[test]
+    fn tile_border_normals_match() {
+        let p = params();
+        let source = DefaultSource::new(p.seed);
+        let rules = vec![SplatRule::everywhere()];
+        let samples = p.cells as usize + 1;
+
+        for &method in [NormalMethod::CentralDifference, NormalMethod::Sobel, NormalMethod::AreaWeighted].iter() {
+            let p = StreamingParams { normal_method: method, .. p };
+            let tile = build_tile(&source, &p, &rules, key(-1, 0));
+            let right = build_tile(&source, &p, &rules, key(0, 0));
+            let above = build_tile(&source, &p, &rules, key(-1, 1));
+            assert_eq!(tile.mesh.verts.len(), samples * samples);
+            assert_eq!(tile.heights.dims(), [samples, samples]);
+
+            for i in 0..samples {
+                let last = samples - 1;
+                assert_eq!(tile.mesh.verts[last * samples + i].v_normal, right.mesh.verts[i].v_normal, "{:?} {}", method, i);
+                assert_eq!(tile.mesh.verts[i * samples + last].v_normal, above.mesh.verts[i * samples].v_normal, "{:?} {}", method, i);
+                assert_eq!(tile.mesh.verts[last * samples + i].v_pos[1], right.mesh.verts[i].v_pos[1]);
+            }
+
+            // the mesh starts at the tile's origin
+            assert_eq!(tile.mesh.verts[0].v_pos, [0.0, *tile.heights.get([0, 0]).unwrap(), 0.0]);
+            assert_eq!(tile.mesh.verts[0].v_tex_pos, [0.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn tile_border_splat_matches() {
+        let p = params();
+        let source = DefaultSource::new(p.seed);
+        let deg = |d: f32| d.to_radians();
+        // wide blends, so that the weights follow small differences of the slope
+        let rules = vec![
+            SplatRule { slope: [0.0, deg(15.0)], slope_blend: deg(30.0), .. SplatRule::everywhere() },
+            SplatRule { slope: [deg(45.0), deg(90.0)], slope_blend: deg(30.0), .. SplatRule::everywhere() },
+        ];
+        let samples = p.cells as usize + 1;
+        let last = samples - 1;
+
+        let tile = build_tile(&source, &p, &rules, key(-1, 0));
+        let right = build_tile(&source, &p, &rules, key(0, 0));
+        let above = build_tile(&source, &p, &rules, key(-1, 1));
+        assert_eq!(tile.splat.len(), samples * samples * 4);
+
+        let texel = |t: &BuiltTile, x: usize, z: usize| {
+            let i = (z * samples + x) * 4;
+            t.splat[i..i + 4].to_vec()
+        };
+        for i in 0..samples {
+            assert_eq!(texel(&tile, last, i), texel(&right, 0, i), "{}", i);
+            assert_eq!(texel(&tile, i, last), texel(&above, i, 0), "{}", i);
+        }
+    }
+}
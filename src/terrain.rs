@@ -1,13 +1,10 @@
 
-extern crate noise;
-
-use self::noise::{Brownian2, Seed};
-
 use util::{Mat, FixedHeight, MapRange, FixedDimension, NonZero, MappableArray};
+use height_source::{HeightSource, Fractal, Basis};
 
 pub type Terrain = Mat<f32, FixedHeight>;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct Area {
     pub x: f32,
     pub y: f32,
@@ -15,17 +12,40 @@ pub struct Area {
     pub h: f32,
 }
 
+/// Generates the terrain with the default height source, 8 octaves of OpenSimplex fBm
 pub fn gen_terrain(samples: [NonZero<u32>; 2], seed: u32, area: Area, max_height: f32) -> Terrain {
+    gen_terrain_with(samples, &DefaultSource::new(seed), area, max_height)
+}
+
+/// The fBm `gen_terrain` has always used. Its heights are scaled by the amplitude of all octaves
+/// but the last, as they were before the height sources existed, so a seed keeps its terrain.
+/// Streamed tiles use it too, so the seed gives the same terrain either way.
+pub struct DefaultSource(Fractal);
+
+impl DefaultSource {
+    pub fn new(seed: u32) -> DefaultSource {
+        DefaultSource(Fractal::fbm(Basis::OpenSimplex, seed))
+    }
+}
+
+impl HeightSource for DefaultSource {
+    fn height(&self, pos: [f32; 2]) -> f32 {
+        self.0.height(pos)
+    }
+
+    fn amplitude(&self) -> f32 {
+        let params = &self.0.params;
+        (0..params.octaves - 1).fold(0.0, |acc, i| acc + params.persistence.powi(i as i32))
+    }
+}
+
+pub fn gen_terrain_with<H: HeightSource>(samples: [NonZero<u32>; 2], source: &H, area: Area, max_height: f32) -> Terrain {
     let samples = samples.map().with(|nz| nz.map(|u| u as usize));
 
     let fixed_dim = FixedHeight::from_non_zero(samples[1]);
     let samples = [samples[0].val(), samples[1].val()];
 
-    let seed = Seed::new(seed);
-    let octaves = 8;
-    let persistence = 0.5 as f32;
-    let noise = Brownian2::new(noise::open_simplex2, octaves).wavelength(240.0).persistence(persistence);
-    let ampl = (0..octaves-1).fold(0.0, |acc, i| acc + persistence.powi(i as i32));
+    let ampl = source.amplitude();
 
     let vec =
         fixed_dim.coords_iter()
@@ -33,7 +53,7 @@ pub fn gen_terrain(samples: [NonZero<u32>; 2], seed: u32, area: Area, max_height
         .map(|coords| {
             let x = (coords[0] as f32 / samples[0] as f32) * area.w + area.x;
             let y = (coords[1] as f32 / samples[1] as f32) * area.h + area.y;
-            noise.apply(&seed, &[x, y]).map_range([-ampl, ampl], [0.0, max_height])
+            source.height([x, y]).map_range([-ampl, ampl], [0.0, max_height])
         })
         .collect();
 
@@ -42,3 +62,200 @@ pub fn gen_terrain(samples: [NonZero<u32>; 2], seed: u32, area: Area, max_height
         fixed_dim: fixed_dim,
     }
 }
+
+/// Position of a tile in a `Tiling`, the tile with key `(0, 0)` starts at the tiling's origin
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TileKey {
+    pub x: i32,
+    pub y: i32,
+}
+
+/// Splits the plane into square tiles that are generated independently.
+/// Each tile has `cells + 1` samples per edge, so neighbouring tiles share their border samples.
+#[derive(Copy, Clone, Debug)]
+pub struct Tiling {
+    pub origin: [f32; 2],
+    /// Edge length of a tile in world units
+    pub tile_size: f32,
+    /// Cells per tile edge
+    pub cells: NonZero<u32>,
+}
+
+impl Tiling {
+    /// Distance between two samples
+    pub fn spacing(&self) -> f32 {
+        self.tile_size / self.cells.val() as f32
+    }
+
+    /// Area covered by the tile, including its far border
+    pub fn tile_area(&self, key: TileKey) -> Area {
+        Area {
+            x: self.sample_pos(key.x as i64 * self.cells.val() as i64, 0),
+            y: self.sample_pos(key.y as i64 * self.cells.val() as i64, 1),
+            w: self.tile_size,
+            h: self.tile_size,
+        }
+    }
+
+    /// Key of the tile containing the world position
+    pub fn tile_at(&self, pos: [f32; 2]) -> TileKey {
+        TileKey {
+            x: ((pos[0] - self.origin[0]) / self.tile_size).floor() as i32,
+            y: ((pos[1] - self.origin[1]) / self.tile_size).floor() as i32,
+        }
+    }
+
+    /// World position of the sample with the global index `i` along `axis`.
+    /// Computed from the global index only, so all tiles containing a sample agree on it bit for bit.
+    fn sample_pos(&self, i: i64, axis: usize) -> f32 {
+        i as f32 * self.spacing() + self.origin[axis]
+    }
+}
+
+/// Generates one tile of the tiling, its border rows and columns are identical to those of the
+/// neighbouring tiles. `apron` extra samples of the neighbours are added on each side.
+pub fn gen_tile<H: HeightSource>(source: &H, tiling: &Tiling, key: TileKey, max_height: f32, apron: usize) -> Terrain {
+    let cells = tiling.cells.val() as usize;
+    let samples = cells + 1 + 2 * apron;
+    let first = [
+        key.x as i64 * cells as i64 - apron as i64,
+        key.y as i64 * cells as i64 - apron as i64,
+    ];
+
+    let fixed_dim = FixedHeight::from_height(samples).unwrap();
+    let ampl = source.amplitude();
+
+    let vec =
+        fixed_dim.coords_iter()
+        .take(samples * samples)
+        .map(|coords| {
+            let x = tiling.sample_pos(first[0] + coords[0] as i64, 0);
+            let y = tiling.sample_pos(first[1] + coords[1] as i64, 1);
+            source.height([x, y]).map_range([-ampl, ampl], [0.0, max_height])
+        })
+        .collect();
+
+    Mat {
+        vec: vec,
+        fixed_dim: fixed_dim,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate noise;
+
+    use self::noise::{Brownian2, Seed};
+
+    use util::{NonZero, MapRange};
+    use height_source::{Fractal, Basis};
+    use super::*;
+
+    /// `gen_terrain` as it was before the height sources were split out of it
+    fn pre_refactor(samples: [usize; 2], seed: u32, area: Area, max_height: f32) -> Vec<f32> {
+        let seed = Seed::new(seed);
+        let octaves = 8;
+        let persistence = 0.5 as f32;
+        let noise = Brownian2::new(noise::open_simplex2, octaves).wavelength(240.0).persistence(persistence);
+        let ampl = (0..octaves-1).fold(0.0, |acc, i| acc + persistence.powi(i as i32));
+
+        let mut vec = Vec::new();
+        for x in 0..samples[0] {
+            for z in 0..samples[1] {
+                let x = (x as f32 / samples[0] as f32) * area.w + area.x;
+                let y = (z as f32 / samples[1] as f32) * area.h + area.y;
+                vec.push(noise.apply(&seed, &[x, y]).map_range([-ampl, ampl], [0.0, max_height]));
+            }
+        }
+        vec
+    }
+
+    #[test]
+    fn default_terrain_is_unchanged() {
+        let area = Area { x: -130.0, y: 40.0, w: 512.0, h: 384.0 };
+        let samples = [NonZero::new(33).unwrap(), NonZero::new(25).unwrap()];
+        for &seed in [0, 1, 1234].iter() {
+            let terrain = gen_terrain(samples, seed, area, 80.0);
+            assert_eq!(terrain.vec, pre_refactor([33, 25], seed, area, 80.0));
+        }
+    }
+
+    #[test]
+    fn tile_borders_match() {
+        let source = Fractal::fbm(Basis::OpenSimplex, 42);
+        let tiling = Tiling {
+            origin: [-37.3, 112.9],
+            tile_size: 96.0,
+            cells: NonZero::new(16).unwrap(),
+        };
+        let last = 16;
+
+        for &(x, y) in [(-2, -1), (-1, -1), (-1, 0), (0, 0), (0, -1), (3, -4)].iter() {
+            let tile = gen_tile(&source, &tiling, TileKey { x: x, y: y }, 50.0, 0);
+            let right = gen_tile(&source, &tiling, TileKey { x: x + 1, y: y }, 50.0, 0);
+            let above = gen_tile(&source, &tiling, TileKey { x: x, y: y + 1 }, 50.0, 0);
+
+            for i in 0..last + 1 {
+                assert_eq!(tile.get([last, i]), right.get([0, i]), "tile {:?} row {}", (x, y), i);
+                assert_eq!(tile.get([i, last]), above.get([i, 0]), "tile {:?} column {}", (x, y), i);
+            }
+        }
+    }
+
+    #[test]
+    fn tile_apron() {
+        let source = Fractal::fbm(Basis::OpenSimplex, 42);
+        let tiling = Tiling {
+            origin: [-37.3, 112.9],
+            tile_size: 96.0,
+            cells: NonZero::new(16).unwrap(),
+        };
+        let key = TileKey { x: -1, y: 2 };
+        let tile = gen_tile(&source, &tiling, key, 50.0, 0);
+        let with_apron = gen_tile(&source, &tiling, key, 50.0, 1);
+        let left = gen_tile(&source, &tiling, TileKey { x: -2, y: 2 }, 50.0, 0);
+        assert_eq!(with_apron.dims(), [19, 19]);
+
+        for x in 0..17 {
+            for z in 0..17 {
+                assert_eq!(with_apron.get([x + 1, z + 1]), tile.get([x, z]));
+            }
+            assert_eq!(with_apron.get([0, x + 1]), left.get([15, x]));
+        }
+    }
+
+    #[test]
+    fn tiles_match_gen_terrain() {
+        // 2 noise units between the samples either way
+        let area = Area { x: 5.0, y: -3.0, w: 64.0, h: 64.0 };
+        let samples = [NonZero::new(32).unwrap(), NonZero::new(32).unwrap()];
+        let terrain = gen_terrain(samples, 9, area, 30.0);
+        let tiling = Tiling {
+            origin: [5.0, -3.0],
+            tile_size: 16.0,
+            cells: NonZero::new(8).unwrap(),
+        };
+
+        let tile = gen_tile(&DefaultSource::new(9), &tiling, TileKey { x: 1, y: 2 }, 30.0, 0);
+        for x in 0..9 {
+            for z in 0..9 {
+                let expected = *terrain.get([8 + x, 16 + z]).unwrap();
+                assert!((tile.get([x, z]).unwrap() - expected).abs() < 1e-3, "{} {}", x, z);
+            }
+        }
+    }
+
+    #[test]
+    fn tile_at_negative_positions() {
+        let tiling = Tiling {
+            origin: [10.0, -10.0],
+            tile_size: 64.0,
+            cells: NonZero::new(8).unwrap(),
+        };
+        assert_eq!(tiling.tile_at([10.0, -10.0]), TileKey { x: 0, y: 0 });
+        assert_eq!(tiling.tile_at([9.9, -10.1]), TileKey { x: -1, y: -1 });
+        assert_eq!(tiling.tile_at([-118.1, 118.0]), TileKey { x: -3, y: 2 });
+        let area = tiling.tile_area(TileKey { x: -2, y: 1 });
+        assert_eq!([area.x, area.y], [-118.0, 54.0]);
+    }
+}
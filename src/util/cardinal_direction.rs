@@ -43,6 +43,16 @@ impl CardinalDirection {
         }
     }
 
+    /// Grid offset of the neighbour in this direction, up is towards positive y
+    pub fn offset(self) -> [i32; 2] {
+        match self {
+            Up => [0, 1],
+            Down => [0, -1],
+            Left => [-1, 0],
+            Right => [1, 0],
+        }
+    }
+
     pub fn index(self) -> usize {
         match self {
             Up => 0,
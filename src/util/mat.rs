@@ -12,6 +12,21 @@ impl<T, F> Mat<T, F> where F: FixedDimension {
         self.fixed_dim.to_index(coords)
             .and_then(|ind| self.vec.get(ind))
     }
+
+    pub fn get_mut(&mut self, coords: [usize; 2]) -> Option<&mut T> {
+        match self.fixed_dim.to_index(coords) {
+            Some(ind) => self.vec.get_mut(ind),
+            None => None,
+        }
+    }
+}
+
+impl<T> Mat<T, FixedHeight> {
+    /// Returns `[width, height]`
+    pub fn dims(&self) -> [usize; 2] {
+        let h = self.fixed_dim.height();
+        [self.vec.len() / h, h]
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -88,7 +103,7 @@ impl FixedDimension for FixedHeight {
 
     fn to_index(&self, coord: [usize; 2]) -> Option<usize> {
         let h = self.height();
-        if coord[0] >= h {
+        if coord[1] >= h {
             None
         } else {
             Some(coord[0] * h + coord[1])
@@ -146,3 +161,38 @@ impl Iterator for CoordsIter<FixedHeight> {
         Some(ret)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 3 wide and 2 high, the values are the indices
+    fn mat() -> Mat<usize, FixedHeight> {
+        Mat {
+            vec: (0..6).collect(),
+            fixed_dim: FixedHeight::from_height(2).unwrap(),
+        }
+    }
+
+    #[test]
+    fn fixed_height_index() {
+        let fixed_dim = FixedHeight::from_height(2).unwrap();
+        assert_eq!(fixed_dim.to_index([0, 0]), Some(0));
+        assert_eq!(fixed_dim.to_index([0, 1]), Some(1));
+        assert_eq!(fixed_dim.to_index([2, 1]), Some(5));
+        for i in 0..6 {
+            assert_eq!(fixed_dim.to_index(fixed_dim.to_coords(i)), Some(i));
+        }
+    }
+
+    #[test]
+    fn fixed_height_out_of_range() {
+        // the bound used to be checked against x, so columns past the height were missing and
+        // z past the height wrapped into the next column
+        let mat = mat();
+        assert_eq!(mat.get([2, 0]), Some(&4));
+        assert_eq!(mat.get([0, 2]), None);
+        assert_eq!(mat.get([1, 3]), None);
+        assert_eq!(mat.get([3, 0]), None);
+    }
+}
@@ -12,6 +12,8 @@ pub mod cardinal_direction;
 pub use self::cardinal_direction::*;
 pub mod array_map;
 pub use self::array_map::*;
+pub mod rng;
+pub use self::rng::*;
 
 pub trait MapRange: Sized {
     fn map_range(&self, from: [Self; 2], to: [Self; 2]) -> Self;
@@ -47,12 +49,68 @@ impl Clock {
     }
 }
 
+/// Something the camera can walk on
+pub trait Ground {
+    /// Height and unit normal of the ground at `x`, `z`, or `None` where there is no ground
+    fn ground_at(&self, x: f32, z: f32) -> Option<(f32, [f32; 3])>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CamMode {
+    /// Free movement, up and down with the vertical movement keys
+    Fly,
+    /// Bound to the ground, the vertical movement keys jump and crouch
+    Walk,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct WalkParams {
+    pub eye_height: f32,
+    pub crouch_eye_height: f32,
+    pub speed: f32,
+    pub crouch_speed: f32,
+    pub gravity: f32,
+    /// Initial upwards speed of a jump
+    pub jump_speed: f32,
+    /// Steepest slope in radians that can be walked up
+    pub max_slope: f32,
+    /// Acceleration down slopes steeper than `max_slope`
+    pub slide_accel: f32,
+    /// Height difference that is followed without falling when walking downhill
+    pub step_down: f32,
+}
+
+impl Default for WalkParams {
+    fn default() -> WalkParams {
+        WalkParams {
+            eye_height: 1.7,
+            crouch_eye_height: 1.0,
+            speed: 4.0,
+            crouch_speed: 1.5,
+            gravity: 9.81,
+            jump_speed: 4.5,
+            max_slope: 45.0f32.to_radians(),
+            slide_accel: 6.0,
+            step_down: 0.5,
+        }
+    }
+}
+
 pub struct FirstPersonCam {
     pub pos: cg::Point3<f32>,
     pub dir: cg::Vector3<f32>,
     pub move_x: f32,
     pub move_y: f32,
     pub move_z: f32,
+    pub mode: CamMode,
+    pub walk: WalkParams,
+    /// Only used in walk mode, the camera stops when it lands
+    pub velocity: cg::Vector3<f32>,
+    pub on_ground: bool,
+    /// Whether the eye was at `walk.crouch_eye_height` in the last update
+    pub crouched: bool,
+    /// Ground height of the last position that had ground, stood on where there is none
+    pub last_ground: Option<f32>,
 }
 
 impl FirstPersonCam {
@@ -63,6 +121,29 @@ impl FirstPersonCam {
             move_x: 0.0,
             move_y: 0.0,
             move_z: 0.0,
+            mode: CamMode::Fly,
+            walk: WalkParams::default(),
+            velocity: cg::Vector3::new(0.0, 0.0, 0.0),
+            on_ground: false,
+            crouched: false,
+            last_ground: None,
+        }
+    }
+
+    pub fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            CamMode::Fly => CamMode::Walk,
+            CamMode::Walk => CamMode::Fly,
+        };
+        self.velocity = cg::Vector3::new(0.0, 0.0, 0.0);
+        self.on_ground = false;
+    }
+
+    /// Moves the camera according to its mode
+    pub fn update<G: Ground>(&mut self, delta: f32, ground: &G) {
+        match self.mode {
+            CamMode::Fly => self.update_pos(delta),
+            CamMode::Walk => self.update_walk(delta, ground),
         }
     }
 
@@ -89,4 +170,298 @@ impl FirstPersonCam {
                 + up * self.move_y * delta * 8.0;
         }
     }
+
+    /// Walks on the ground with gravity. Jumps while `move_y` is positive and crouches while it is
+    /// negative. Slopes steeper than `walk.max_slope` can't be walked up and make the camera slide.
+    /// Where there is no ground the camera stands at the last ground height it saw, and stays in
+    /// the air if it hasn't seen any yet.
+    pub fn update_walk<G: Ground>(&mut self, delta: f32, ground: &G) {
+        let p = self.walk;
+        let up = cg::Vector3::new(0.0, 1.0, 0.0);
+        let cam_right = self.dir.cross(up).normalize();
+        let cam_forw = -cam_right.cross(up);
+        let crouching = self.move_y < 0.0;
+
+        let mut walk = cg::Vector3::new(0.0, 0.0, 0.0);
+        if !cam_right.length().is_nan() && !cam_forw.length().is_nan() {
+            walk = cam_forw * self.move_z + cam_right * self.move_x;
+            if walk.length() > 1.0 {
+                walk = walk.normalize();
+            }
+            walk = walk * if crouching { p.crouch_speed } else { p.speed };
+        }
+
+        let eye_height = if crouching { p.crouch_eye_height } else { p.eye_height };
+        if self.on_ground && crouching != self.crouched {
+            // moves the eye directly, crouching would otherwise be a fall deeper than `step_down`
+            self.pos.y+= if crouching { p.crouch_eye_height - p.eye_height } else { p.eye_height - p.crouch_eye_height };
+        }
+        self.crouched = crouching;
+        let here = ground.ground_at(self.pos.x, self.pos.z);
+
+        let horizontal = if self.on_ground {
+            let steep = here.map(|(_, normal)| slope(normal) > p.max_slope).unwrap_or(false);
+
+            // on the ground the horizontal velocity is the sliding speed, lost on walkable ground
+            let slide = match here {
+                Some((_, normal)) if steep => {
+                    // the horizontal part of the normal points downhill
+                    let downhill = cg::Vector3::new(normal[0], 0.0, normal[2]).normalize();
+                    cg::Vector3::new(self.velocity.x, 0.0, self.velocity.z) + downhill * p.slide_accel * delta
+                },
+                _ => cg::Vector3::new(0.0, 0.0, 0.0),
+            };
+            self.velocity.x = slide.x;
+            self.velocity.z = slide.z;
+
+            if self.move_y > 0.0 && !steep {
+                self.velocity = walk + cg::Vector3::new(0.0, p.jump_speed, 0.0);
+                self.on_ground = false;
+            }
+
+            walk + slide
+        } else {
+            // no control in the air
+            cg::Vector3::new(self.velocity.x, 0.0, self.velocity.z)
+        };
+
+        // refused where there is no ground, or when walking up a slope that is too steep
+        let target_x = self.pos.x + horizontal.x * delta;
+        let target_z = self.pos.z + horizontal.z * delta;
+        if let Some((target_height, target_normal)) = ground.ground_at(target_x, target_z) {
+            let climbing = here.map(|(h, _)| target_height > h).unwrap_or(false);
+            if !(self.on_ground && climbing && slope(target_normal) > p.max_slope) {
+                self.pos.x = target_x;
+                self.pos.z = target_z;
+            }
+        }
+
+        if !self.on_ground {
+            self.velocity.y-= p.gravity * delta;
+        }
+        self.pos.y+= self.velocity.y * delta;
+
+        let height = ground.ground_at(self.pos.x, self.pos.z).map(|(height, _)| height);
+        if height.is_some() {
+            self.last_ground = height;
+        }
+
+        match height.or(self.last_ground) {
+            Some(height) => {
+                let eye = height + eye_height;
+                let snap = self.on_ground && self.pos.y - eye < p.step_down;
+                if self.pos.y <= eye || snap {
+                    self.pos.y = eye;
+                    self.velocity.y = 0.0;
+                    self.on_ground = true;
+                } else {
+                    if self.on_ground {
+                        // walked off an edge, keep going while falling
+                        self.velocity.x = horizontal.x;
+                        self.velocity.z = horizontal.z;
+                    }
+                    self.on_ground = false;
+                }
+            },
+            None => {
+                self.pos.y-= self.velocity.y * delta;
+                self.velocity.y = 0.0;
+                self.on_ground = false;
+            },
+        }
+    }
+}
+
+/// Angle between a surface with this normal and the horizontal plane, in radians
+fn slope(normal: [f32; 3]) -> f32 {
+    normal[1].max(-1.0).min(1.0).acos()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell};
+
+    use cg;
+    use super::*;
+
+    /// Flat ground at height 2 for x in `[0, 10]`, nothing elsewhere
+    struct Island;
+
+    impl Ground for Island {
+        fn ground_at(&self, x: f32, _z: f32) -> Option<(f32, [f32; 3])> {
+            if x >= 0.0 && x <= 10.0 { Some((2.0, [0.0, 1.0, 0.0])) } else { None }
+        }
+    }
+
+    fn walker(x: f32, y: f32) -> FirstPersonCam {
+        let mut cam = FirstPersonCam::new(cg::Point3::new(x, y, 0.0), cg::Vector3::new(1.0, 0.0, 0.0));
+        cam.mode = CamMode::Walk;
+        cam
+    }
+
+    #[test]
+    fn walk_lands_on_ground() {
+        let mut cam = walker(5.0, 20.0);
+        for _ in 0..200 {
+            cam.update(0.02, &Island);
+        }
+        assert!(cam.on_ground);
+        assert_eq!(cam.pos.y, 2.0 + cam.walk.eye_height);
+    }
+
+    #[test]
+    fn walk_stops_at_the_edge() {
+        let mut cam = walker(9.0, 20.0);
+        cam.set_movement(cg::Vector3::new(0.0, 0.0, 1.0));
+        for _ in 0..500 {
+            cam.update(0.02, &Island);
+        }
+        assert!(cam.pos.x > 9.9 && cam.pos.x <= 10.0);
+        assert_eq!(cam.pos.y, 2.0 + cam.walk.eye_height);
+    }
+
+    /// Ground that is gone after it was seen, like an evicted tile
+    struct Vanishing(Cell<bool>);
+
+    impl Ground for Vanishing {
+        fn ground_at(&self, _x: f32, _z: f32) -> Option<(f32, [f32; 3])> {
+            if self.0.get() { Some((-3.0, [0.0, 1.0, 0.0])) } else { None }
+        }
+    }
+
+    #[test]
+    fn walk_keeps_last_ground() {
+        let ground = Vanishing(Cell::new(true));
+        let mut cam = walker(0.0, 5.0);
+        cam.update(0.02, &ground);
+        ground.0.set(false);
+        for _ in 0..500 {
+            cam.update(0.02, &ground);
+        }
+        assert!(cam.on_ground);
+        assert_eq!(cam.pos.y, -3.0 + cam.walk.eye_height);
+    }
+
+    #[test]
+    fn walk_without_ground_hovers() {
+        let mut cam = walker(-5.0, 20.0);
+        for _ in 0..200 {
+            cam.update(0.02, &Island);
+        }
+        assert_eq!(cam.pos.y, 20.0);
+        assert!(!cam.on_ground);
+    }
+
+    fn landed<G: Ground>(x: f32, ground: &G) -> FirstPersonCam {
+        let mut cam = walker(x, 0.0);
+        cam.update(0.001, ground);
+        assert!(cam.on_ground);
+        cam
+    }
+
+    #[test]
+    fn walk_falls_with_gravity() {
+        let mut cam = walker(5.0, 20.0);
+        for _ in 0..1000 {
+            cam.update(0.001, &Island);
+        }
+        assert!((cam.pos.y - (20.0 - cam.walk.gravity / 2.0)).abs() < 0.02, "{}", cam.pos.y);
+        assert!((cam.velocity.y + cam.walk.gravity).abs() < 0.01);
+        assert!(!cam.on_ground);
+    }
+
+    #[test]
+    fn walk_jumps_in_an_arc() {
+        let mut cam = landed(5.0, &Island);
+        let stand = cam.pos.y;
+        cam.set_movement(cg::Vector3::new(0.0, 1.0, 1.0));
+        cam.update(0.001, &Island);
+        assert!(!cam.on_ground);
+        cam.set_movement(cg::Vector3::new(0.0, 0.0, 0.0));
+
+        let (mut top, mut steps) = (stand, 1);
+        while !cam.on_ground {
+            cam.update(0.001, &Island);
+            top = top.max(cam.pos.y);
+            steps+= 1;
+        }
+        let (v, g) = (cam.walk.jump_speed, cam.walk.gravity);
+        assert!((top - stand - v * v / (2.0 * g)).abs() < 0.01, "{}", top - stand);
+        assert!((steps as f32 * 0.001 - 2.0 * v / g).abs() < 0.01);
+        // kept the speed it jumped with
+        assert!((cam.pos.x - 5.0 - cam.walk.speed * 2.0 * v / g).abs() < 0.02);
+        assert_eq!(cam.pos.y, stand);
+    }
+
+    /// Flat at height 0 for x below 0, rising along x with the given slope above
+    struct Ramp(f32);
+
+    impl Ground for Ramp {
+        fn ground_at(&self, x: f32, _z: f32) -> Option<(f32, [f32; 3])> {
+            if x < 0.0 {
+                Some((0.0, [0.0, 1.0, 0.0]))
+            } else {
+                Some((x * self.0.tan(), [-self.0.sin(), self.0.cos(), 0.0]))
+            }
+        }
+    }
+
+    #[test]
+    fn walk_climbs_gentle_slopes_only() {
+        let gentle = Ramp(30.0f32.to_radians());
+        let mut cam = landed(-1.0, &gentle);
+        cam.set_movement(cg::Vector3::new(0.0, 0.0, 1.0));
+        for _ in 0..1000 {
+            cam.update(0.001, &gentle);
+        }
+        assert!(cam.pos.x > 2.5 && cam.on_ground);
+
+        let steep = Ramp(60.0f32.to_radians());
+        let mut cam = landed(-1.0, &steep);
+        cam.set_movement(cg::Vector3::new(0.0, 0.0, 1.0));
+        for _ in 0..1000 {
+            cam.update(0.001, &steep);
+        }
+        assert!(cam.pos.x < 0.01, "{}", cam.pos.x);
+        assert_eq!(cam.pos.y, cam.walk.eye_height);
+    }
+
+    #[test]
+    fn walk_slides_down_steep_slopes() {
+        let steep = Ramp(60.0f32.to_radians());
+        let mut cam = landed(10.0, &steep);
+        for _ in 0..1000 {
+            cam.update(0.001, &steep);
+        }
+        let slid = 10.0 - cam.pos.x;
+        assert!((slid - cam.walk.slide_accel / 2.0).abs() < 0.05, "{}", slid);
+        assert!(cam.on_ground);
+
+        // jumping is refused there
+        cam.set_movement(cg::Vector3::new(0.0, 1.0, 0.0));
+        cam.update(0.001, &steep);
+        assert!(cam.on_ground);
+
+        // stops on the flat ground below
+        cam.set_movement(cg::Vector3::new(0.0, 0.0, 0.0));
+        for _ in 0..5000 {
+            cam.update(0.001, &steep);
+        }
+        assert_eq!(cam.velocity.x, 0.0);
+        assert_eq!(cam.pos.y, cam.walk.eye_height);
+    }
+
+    #[test]
+    fn crouching_stays_on_the_ground() {
+        let mut cam = landed(5.0, &Island);
+        cam.set_movement(cg::Vector3::new(0.0, -1.0, 0.0));
+        cam.update(0.02, &Island);
+        assert!(cam.on_ground);
+        assert_eq!(cam.pos.y, 2.0 + cam.walk.crouch_eye_height);
+
+        cam.set_movement(cg::Vector3::new(0.0, 0.0, 0.0));
+        cam.update(0.02, &Island);
+        assert!(cam.on_ground);
+        assert_eq!(cam.pos.y, 2.0 + cam.walk.eye_height);
+    }
 }
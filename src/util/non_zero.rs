@@ -2,6 +2,7 @@
 use super::num::Integer;
 
 /// Inner value is guaranteed not to be zero.
+#[derive(Debug)]
 pub struct NonZero<T: Integer>(T);
 
 impl<T: Integer> NonZero<T> {
@@ -0,0 +1,29 @@
+/// Small xorshift generator, so simulations are reproducible for a given seed
+#[derive(Copy, Clone)]
+pub struct XorShift {
+    state: u32,
+}
+
+impl XorShift {
+    pub fn new(seed: u32) -> XorShift {
+        // scramble the seed, small seeds would give a slow start otherwise
+        let state = seed.wrapping_mul(0x9e3779b9) ^ 0x6d2b79f5;
+        XorShift {
+            state: if state == 0 { 0x6d2b79f5 } else { state },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x^= x << 13;
+        x^= x >> 17;
+        x^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Returns a value in `[0, 1)`
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+}
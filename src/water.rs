@@ -0,0 +1,298 @@
+use glium;
+use glium::Surface;
+use glium::backend::{Facade};
+use glium::index::{PrimitiveType};
+use glium::texture::{Texture2d, DepthTexture2d};
+use glium::uniforms::{SamplerWrapFunction, MagnifySamplerFilter, MinifySamplerFilter};
+use glium::program::{Program};
+use glium::framebuffer::{SimpleFrameBuffer};
+
+use cg::{Matrix4};
+
+use mesh::{Mesh, UploadedMesh, FaceVertex, MeshUploadError};
+use frustum::{Frustum};
+
+#[derive(Copy, Clone, Debug)]
+pub struct WaterParams {
+    /// World height of the water surface
+    pub level: f32,
+    /// Color of deep water
+    pub deep_color: [f32; 3],
+    /// Absorption coefficients of red, green and blue light, per world unit of water
+    pub absorption: [f32; 3],
+    /// Water shallower than this foams
+    pub foam_depth: f32,
+    /// World size of the waves
+    pub wave_scale: f32,
+    pub wave_speed: f32,
+    /// Offset of the reflection and refraction lookups by the waves, in texture coordinates
+    pub distortion: f32,
+    /// World height above the surface up to which the refraction keeps the terrain, so the
+    /// distorted lookups at the shore don't find holes
+    pub clip_margin: f32,
+    /// Size of the reflection and refraction targets relative to the window
+    pub resolution: f32,
+}
+
+/// The scene reflected at the water surface and the scene below it, rendered from the camera
+pub struct WaterTargets {
+    pub dims: (u32, u32),
+    pub reflection: Texture2d,
+    pub reflection_depth: DepthTexture2d,
+    pub refraction: Texture2d,
+    pub refraction_depth: DepthTexture2d,
+}
+
+/// A horizontal plane around the camera. The terrain is drawn twice into the targets before the
+/// water, see `reflection` and `clip_above` / `clip_below`.
+pub struct Water {
+    pub params: WaterParams,
+    pub targets: WaterTargets,
+    /// Unit square, scaled to the view distance around the camera
+    quad: UploadedMesh<FaceVertex>,
+    shader: Program,
+}
+
+impl Water {
+    /// The targets get their size with the first `resize`
+    pub fn new<F: Facade>(facade: &F, params: WaterParams) -> Result<Water, MeshUploadError> {
+        let vert = |x: f32, z: f32| FaceVertex {
+            v_pos: [x, 0.0, z],
+            v_tex_pos: [x, z],
+            v_normal: [0.0, 1.0, 0.0],
+        };
+        let quad = try!(Mesh {
+            verts: vec![vert(-1.0, -1.0), vert(1.0, -1.0), vert(-1.0, 1.0), vert(1.0, 1.0)],
+            inds: None,
+            primitive_type: PrimitiveType::TriangleStrip,
+        }.upload(facade));
+
+        Ok(Water {
+            params: params,
+            targets: targets(facade, (1, 1)),
+            quad: quad,
+            shader: program!(facade,
+                330 => {
+                    vertex: r#"
+                        #version 330
+
+                        in vec3 v_pos;
+
+                        out vec3 p_world_pos;
+                        out vec4 p_clip_pos;
+
+                        uniform mat4 projview;
+                        uniform mat4 model;
+
+                        void main()
+                        {
+                            vec4 world_pos = model * vec4(v_pos, 1.0);
+                            p_world_pos = world_pos.xyz;
+                            p_clip_pos = projview * world_pos;
+                            gl_Position = p_clip_pos;
+                        }
+                    "#,
+                    fragment: r#"
+                        #version 330
+
+                        in vec3 p_world_pos;
+                        in vec4 p_clip_pos;
+
+                        out vec4 f_color;
+
+                        uniform sampler2D reflection;
+                        uniform sampler2D refraction;
+                        uniform sampler2D refraction_depth;
+                        uniform vec3 eye;
+                        uniform vec3 light_dir;
+                        uniform vec3 light_color;
+                        uniform float time;
+                        uniform float near;
+                        uniform float far;
+                        uniform vec3 deep_color;
+                        uniform vec3 absorption;
+                        uniform float foam_depth;
+                        uniform float wave_scale;
+                        uniform float wave_speed;
+                        uniform float distortion;
+
+                        // slope of a few travelling waves, their sum is the height of the surface
+                        vec2 wave_slope(vec2 pos)
+                        {
+                            vec2 slope = vec2(0.0);
+                            vec2 dirs[4] = vec2[](vec2(1.0, 0.2), vec2(-0.4, 1.0), vec2(0.7, -0.7), vec2(-1.0, -0.3));
+                            for (int i = 0; i < 4; i++) {
+                                float k = (1.0 + float(i) * 0.7) / wave_scale;
+                                vec2 dir = normalize(dirs[i]);
+                                float phase = dot(pos, dir) * k + time * wave_speed * sqrt(k * 9.81);
+                                slope += dir * cos(phase) * 0.15 / (1.0 + float(i));
+                            }
+                            return slope;
+                        }
+
+                        // view depth of a depth buffer value
+                        float linear_depth(float depth)
+                        {
+                            float z = depth * 2.0 - 1.0;
+                            return 2.0 * near * far / (far + near - z * (far - near));
+                        }
+
+                        void main()
+                        {
+                            vec2 slope = wave_slope(p_world_pos.xz);
+                            vec3 normal = normalize(vec3(-slope.x, 1.0, -slope.y));
+                            vec3 to_eye = normalize(eye - p_world_pos);
+
+                            vec2 screen = p_clip_pos.xy / p_clip_pos.w * 0.5 + 0.5;
+                            vec2 offset = normal.xz * distortion;
+
+                            // the water between the surface and the ground below along the view ray
+                            float ground = linear_depth(texture(refraction_depth, screen).r);
+                            float thickness = max(ground - p_clip_pos.w, 0.0);
+                            // distorted, unless that would look up terrain in front of the water
+                            vec2 refracted = screen + offset * clamp(thickness, 0.0, 1.0);
+                            if (linear_depth(texture(refraction_depth, refracted).r) < p_clip_pos.w) {
+                                refracted = screen;
+                            }
+                            ground = linear_depth(texture(refraction_depth, refracted).r);
+                            thickness = max(ground - p_clip_pos.w, 0.0);
+
+                            vec3 transmitted = exp(-absorption * thickness);
+                            vec3 below = texture(refraction, refracted).rgb * transmitted + deep_color * (1.0 - transmitted);
+                            vec3 above = texture(reflection, screen + offset).rgb;
+
+                            // schlick's approximation, water reflects 2% straight down
+                            float fresnel = 0.02 + 0.98 * pow(1.0 - max(dot(to_eye, normal), 0.0), 5.0);
+                            vec3 color = mix(below, above, fresnel);
+
+                            color += light_color * pow(max(dot(reflect(-light_dir, normal), to_eye), 0.0), 128.0) * 0.6;
+
+                            // broken up by the waves, thickest where the water is shallowest
+                            float shore = 1.0 - clamp(thickness / foam_depth, 0.0, 1.0);
+                            float pattern = 0.5 + 0.5 * sin(dot(slope, vec2(40.0, 30.0)) + time * 2.0);
+                            float foam = smoothstep(0.3, 1.0, shore * (0.6 + 0.4 * pattern));
+                            color = mix(color, vec3(0.9, 0.95, 1.0), foam);
+
+                            f_color = vec4(color, 1.0);
+                        }
+                    "#,
+                }).expect("Error creating program"),
+        })
+    }
+
+    /// Recreates the targets when the window size changed
+    pub fn resize<F: Facade>(&mut self, facade: &F, window: (u32, u32)) {
+        let dims = target_dims(window, self.params.resolution);
+        if dims != self.targets.dims {
+            self.targets = targets(facade, dims);
+        }
+    }
+
+    pub fn reflection_target<F: Facade>(&self, facade: &F) -> SimpleFrameBuffer {
+        SimpleFrameBuffer::with_depth_buffer(facade, &self.targets.reflection, &self.targets.reflection_depth)
+            .expect("Error creating reflection framebuffer")
+    }
+
+    pub fn refraction_target<F: Facade>(&self, facade: &F) -> SimpleFrameBuffer {
+        SimpleFrameBuffer::with_depth_buffer(facade, &self.targets.refraction, &self.targets.refraction_depth)
+            .expect("Error creating refraction framebuffer")
+    }
+
+    /// Mirrors the world at the water surface, the reflection is rendered with `projview * reflection()`
+    pub fn reflection(&self) -> Matrix4<f32> {
+        reflection_matrix(self.params.level)
+    }
+
+    /// Clip plane keeping what is above the surface, for the reflection
+    pub fn clip_above(&self) -> [f32; 4] {
+        [0.0, 1.0, 0.0, -self.params.level]
+    }
+
+    /// Clip plane keeping what is below the surface, for the refraction. Reaches `clip_margin`
+    /// above it.
+    pub fn clip_below(&self) -> [f32; 4] {
+        [0.0, -1.0, 0.0, self.params.level + self.params.clip_margin]
+    }
+
+    /// Whether the surface drawn by `draw` is seen from above and in the view frustum. Otherwise
+    /// neither the targets nor the surface have to be drawn.
+    pub fn visible(&self, projview: &Matrix4<f32>, eye: [f32; 3], far: f32) -> bool {
+        let level = self.params.level;
+        eye[1] > level && Frustum::from_projview(projview)
+            .intersects_aabb([eye[0] - far, level, eye[2] - far], [eye[0] + far, level, eye[2] + far])
+    }
+
+    /// Draws the surface up to `far` around the camera, after the terrain
+    /// `light_dir` is a unit vector towards the light
+    pub fn draw<S: Surface>(&self, target: &mut S, projview: &Matrix4<f32>, eye: [f32; 3], light_dir: [f32; 3],
+            light_color: [f32; 3], time: f32, near: f32, far: f32) {
+        let p = &self.params;
+        let sampled = |texture: &Texture2d| texture.sampled()
+            .wrap_function(SamplerWrapFunction::Clamp)
+            .minify_filter(MinifySamplerFilter::Linear);
+        let uniforms = uniform! {
+            projview: *projview.as_ref(),
+            model: [
+                [far, 0.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0, 0.0],
+                [0.0, 0.0, far, 0.0],
+                [eye[0], p.level, eye[2], 1.0],
+            ],
+            reflection: sampled(&self.targets.reflection),
+            refraction: sampled(&self.targets.refraction),
+            refraction_depth: self.targets.refraction_depth.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .magnify_filter(MagnifySamplerFilter::Nearest)
+                .minify_filter(MinifySamplerFilter::Nearest),
+            eye: eye,
+            light_dir: light_dir,
+            light_color: light_color,
+            time: time,
+            near: near,
+            far: far,
+            deep_color: p.deep_color,
+            absorption: p.absorption,
+            foam_depth: p.foam_depth,
+            wave_scale: p.wave_scale,
+            wave_speed: p.wave_speed,
+            distortion: p.distortion,
+        };
+
+        let draw_params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLess,
+                write: true,
+                .. Default::default()
+            },
+            .. Default::default()
+        };
+        target.draw(&self.quad.vbo, &self.quad.ibo, &self.shader, &uniforms, &draw_params).expect("Error drawing");
+    }
+}
+
+/// Mirrors points at the plane `y = level`
+pub fn reflection_matrix(level: f32) -> Matrix4<f32> {
+    Matrix4::new(
+        1.0, 0.0, 0.0, 0.0,
+        0.0, -1.0, 0.0, 0.0,
+        0.0, 0.0, 1.0, 0.0,
+        0.0, 2.0 * level, 0.0, 1.0,
+    )
+}
+
+fn target_dims(window: (u32, u32), resolution: f32) -> (u32, u32) {
+    let scale = |x: u32| ((x as f32 * resolution) as u32).max(1);
+    (scale(window.0), scale(window.1))
+}
+
+fn targets<F: Facade>(facade: &F, dims: (u32, u32)) -> WaterTargets {
+    let color = || Texture2d::empty(facade, dims.0, dims.1).expect("Error creating water target");
+    let depth = || DepthTexture2d::empty(facade, dims.0, dims.1).expect("Error creating water target");
+    WaterTargets {
+        dims: dims,
+        reflection: color(),
+        reflection_depth: depth(),
+        refraction: color(),
+        refraction_depth: depth(),
+    }
+}